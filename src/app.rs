@@ -1,5 +1,10 @@
 pub use crate::errors::AppError;
-use crate::{VERSION, config, errors::TargetContextedResult, qb, server, task};
+use crate::{
+    VERSION, config,
+    errors::TargetContextedResult,
+    qb, server,
+    task::{self, store::StoreKind},
+};
 use futures_util::{FutureExt, select, try_join};
 use log::{error, info};
 use std::{convert::Infallible, net::IpAddr, path::PathBuf};
@@ -62,7 +67,7 @@ async fn wait_for_signal(shutdown_tx: broadcast::Sender<()>) {
 
 /// Task before shutdown the application
 async fn cleanup() -> Result<(), Infallible> {
-    if qb::is_logined() {
+    if qb::any_logined() {
         info!("Removing waited torrents");
         let _ = task::clean_waited().await;
     }
@@ -77,6 +82,7 @@ pub fn init() -> Result<(Option<IpAddr>, u16), AppError> {
     let args: Vec<String> = std::env::args().collect();
     let mut config_path = None;
     let mut task_path = None;
+    let mut task_store = StoreKind::default();
     let mut port = PORT;
     let mut log_level = None;
     let mut addr = None;
@@ -89,6 +95,13 @@ pub fn init() -> Result<(Option<IpAddr>, u16), AppError> {
             "--task-path" => {
                 task_path = Some(PathBuf::from(args.get(i + 1).expect("invalid arguments")));
             }
+            "--task-store" => {
+                task_store = args
+                    .get(i + 1)
+                    .expect("invalid arguments")
+                    .parse()
+                    .expect("invalid task store");
+            }
             "--log-level" => {
                 log_level = args.get(i + 1);
             }
@@ -122,6 +135,6 @@ pub fn init() -> Result<(Option<IpAddr>, u16), AppError> {
 
     info!("qb-downloader v{VERSION} starting...");
     config::init(config_path).convert_then_add_context("Failed to init config")?;
-    task::init(task_path)?;
+    task::init(task_path, task_store)?;
     Ok((addr, port))
 }
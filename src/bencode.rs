@@ -2,7 +2,7 @@ use std::{
     borrow::Cow,
     cmp::Ordering,
     collections::{BTreeMap, HashMap},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
 use bendy::{
@@ -11,6 +11,7 @@ use bendy::{
 };
 use serde::Serialize;
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
 use tokio::{fs, task::spawn_blocking};
 
 use crate::{
@@ -55,11 +56,20 @@ pub async fn get_torrent_name(hash: &str) -> Result<String, BencodeError> {
     get_root_dir(info)
 }
 
-/// check if the torrent is multi-file, else return [`BencodeError::SingleFile`] error
+/// check if the torrent is multi-file, else return [`BencodeError::SingleFile`] error.
+/// A v2 single-file torrent has the file itself as the only entry of `file tree`.
 fn check(info: &BTreeMap<BytesList, Value>) -> Result<(), BencodeError> {
     if info.contains_key("length".as_bytes()) {
         return Err(BencodeError::SingleFile);
     }
+    if !info.contains_key("files".as_bytes())
+        && let Some(Value::Dict(tree)) = info.get("file tree".as_bytes())
+        && tree.len() == 1
+        && let Some(Value::Dict(node)) = tree.values().next()
+        && node.contains_key("".as_bytes())
+    {
+        return Err(BencodeError::SingleFile);
+    }
     Ok(())
 }
 
@@ -92,77 +102,193 @@ fn get_root_dir(info: &BTreeMap<BytesList, Value>) -> Result<String, BencodeErro
     Err(BencodeError::Decode)
 }
 
-fn get_files<'a>(info: &'a BTreeMap<BytesList, Value>) -> Result<&'a Vec<Value<'a>>, BencodeError> {
+/// a file in the torrent, listed in the order of the torrent
+struct TorrentFile<'a> {
+    path: Vec<String>,
+    length: &'a i64,
+    /// padding file of BEP 47, which is never downloaded,
+    /// and is left out of the file indices by qBittorrent 4.4 and later
+    pad: bool,
+}
+
+/// Get the files from the v1 `files` list, or the v2 `file tree` if there is no v1 list.
+/// Hybrid torrents carry both, and qBittorrent indexes files by the v1 list.
+fn get_files<'a>(
+    info: &'a BTreeMap<BytesList, Value>,
+) -> Result<Vec<TorrentFile<'a>>, BencodeError> {
     if let Some(Value::List(files)) = info.get("files".as_bytes()) {
+        return files.iter().map(get_v1_file).collect();
+    }
+    if let Some(Value::Dict(tree)) = info.get("file tree".as_bytes()) {
+        let mut files = Vec::new();
+        walk_file_tree(tree, &mut Vec::new(), &mut files)?;
         return Ok(files);
     }
     Err(BencodeError::SingleFile)
 }
 
-fn get_file_length_list<'a>(files: &'a Vec<Value>) -> Result<Vec<&'a i64>, BencodeError> {
-    let mut lengths = Vec::new();
-    for file in files {
-        if let Value::Dict(f) = file
-            && let Some(Value::Integer(length)) = f.get("length".as_bytes())
-        {
-            lengths.push(length);
-            continue;
-        }
-        return Err(BencodeError::Decode);
+fn get_v1_file<'a>(file: &'a Value) -> Result<TorrentFile<'a>, BencodeError> {
+    if let Value::Dict(f) = file
+        && let Some(Value::Integer(length)) = f.get("length".as_bytes())
+        && let Some(Value::List(path)) = f.get("path".as_bytes())
+    {
+        let path = path
+            .iter()
+            .map(|node| match node {
+                Value::Bytes(n) => Ok(String::from_utf8_lossy(n).to_string()),
+                _ => Err(BencodeError::Decode),
+            })
+            .collect::<Result<_, _>>()?;
+        return Ok(TorrentFile {
+            path,
+            length,
+            pad: is_pad(f),
+        });
     }
-    Ok(lengths)
+    Err(BencodeError::Decode)
 }
 
-fn get_file_name_list(files: &Vec<Value>) -> Result<Vec<Vec<String>>, BencodeError> {
-    let mut paths = Vec::new();
-    for file in files {
-        if let Value::Dict(f) = file
-            && let Some(Value::List(path)) = f.get("path".as_bytes())
-        {
-            let mut path_vec = Vec::new();
-            for node in path {
-                if let Value::Bytes(n) = node {
-                    let n = String::from_utf8_lossy(n).to_string();
-                    path_vec.push(n);
-                } else {
+/// whether the file `attr` of BEP 47 marks a padding file
+fn is_pad(file: &BTreeMap<BytesList, Value>) -> bool {
+    matches!(file.get("attr".as_bytes()), Some(Value::Bytes(attr)) if attr.contains(&b'p'))
+}
+
+/// Walk the v2 `file tree` depth-first in key order, which is how files are indexed.
+/// A file is a dict with a single empty key, holding its `length`.
+fn walk_file_tree<'a>(
+    tree: &'a BTreeMap<BytesList, Value>,
+    path: &mut Vec<String>,
+    files: &mut Vec<TorrentFile<'a>>,
+) -> Result<(), BencodeError> {
+    for (name, node) in tree {
+        let Value::Dict(node) = node else {
+            return Err(BencodeError::Decode);
+        };
+        path.push(String::from_utf8_lossy(name).to_string());
+        match node.get("".as_bytes()) {
+            Some(Value::Dict(file)) => {
+                let Some(Value::Integer(length)) = file.get("length".as_bytes()) else {
                     return Err(BencodeError::Decode);
-                }
+                };
+                files.push(TorrentFile {
+                    path: path.clone(),
+                    length,
+                    pad: is_pad(file),
+                });
             }
-            paths.push(path_vec);
-            continue;
+            Some(_) => return Err(BencodeError::Decode),
+            None => walk_file_tree(node, path, files)?,
         }
-        return Err(BencodeError::Decode);
+        path.pop();
     }
-    Ok(paths)
+    Ok(())
 }
 
 /// Parse the torrent file from `value`, which can retrive by [`get_value`],
-/// returning the root directory name and a list of file lengths
+/// returning the root directory name and a list of file lengths by file index.
+/// Padding files are skipped, as in the file indices of qBittorrent v4.4+.
 pub fn parse_torrent<'a>(value: &'a Value) -> Result<(String, Vec<&'a i64>), BencodeError> {
     let info = get_info(value)?;
+    check(info)?;
     let root_dir = get_root_dir(info)?;
-    let files = get_files(info)?;
-    let lengths = get_file_length_list(files)?;
+    let lengths = get_files(info)?
+        .into_iter()
+        .filter(|f| !f.pad)
+        .map(|f| f.length)
+        .collect();
     Ok((root_dir, lengths))
 }
 
-pub fn get_hash(file: &[u8]) -> Result<String, BencodeError> {
+/// Paths of the files in the torrent file by file index, relative to the save path,
+/// padding files are skipped as in [`parse_torrent`]
+pub async fn get_file_paths(torrent_path: &Path) -> Result<Vec<PathBuf>, BencodeError> {
+    let value = get_value(torrent_path).await?;
+    let info = get_info(&value)?;
+    let root_dir = get_root_dir(info)?;
+    Ok(get_file_name_list(info)?
+        .into_iter()
+        .map(|(_, path)| std::iter::once(root_dir.clone()).chain(path).collect())
+        .collect())
+}
+
+/// info hashes of a torrent, see BEP 52
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InfoHash {
+    /// SHA-1 of the info dict, for v1 and hybrid torrents
+    pub v1: Option<String>,
+    /// SHA-256 of the info dict, for v2 and hybrid torrents
+    pub v2: Option<String>,
+}
+
+impl InfoHash {
+    /// The hash identifying the torrent in qBittorrent,
+    /// which is the v1 hash, or the v2 hash truncated to 20 bytes for v2-only torrents
+    pub fn id(&self) -> &str {
+        match (&self.v1, &self.v2) {
+            (Some(v1), _) => v1,
+            (None, Some(v2)) => truncate_v2(v2),
+            (None, None) => unreachable!("torrent without info hash"),
+        }
+    }
+}
+
+/// the v2 hash truncated to 20 bytes, as used where a v1 hash is expected
+pub fn truncate_v2(v2: &str) -> &str {
+    v2.get(..40).unwrap_or(v2)
+}
+
+/// Compute the info hashes of the torrent file content,
+/// SHA-1 if it has v1 `pieces`, SHA-256 if it is `meta version` 2
+pub fn get_hash(file: &[u8]) -> Result<InfoHash, BencodeError> {
     let mut decoder = Decoder::new(file);
     let obj = decoder.next_object()?.ok_or(BencodeError::Decode)?;
     let mut dict = obj.try_into_dictionary()?;
     while let Some(pair) = dict.next_pair()? {
         if let b"info" = pair.0 {
             let info_bytes = pair.1.try_into_dictionary()?.into_raw()?;
-            let hash = Sha1::digest(info_bytes)
-                .iter()
-                .map(|b| format!("{:02x}", b))
-                .collect::<String>();
+            let Value::Dict(info) = Value::from_bencode(info_bytes)? else {
+                return Err(BencodeError::Decode);
+            };
+            let is_v1 = info.contains_key("pieces".as_bytes());
+            let is_v2 = matches!(info.get("meta version".as_bytes()), Some(Value::Integer(2)));
+            let hash = InfoHash {
+                v1: is_v1.then(|| to_hex(&Sha1::digest(info_bytes))),
+                v2: is_v2.then(|| to_hex(&Sha256::digest(info_bytes))),
+            };
+            if hash.v1.is_none() && hash.v2.is_none() {
+                return Err(BencodeError::Decode);
+            }
             return Ok(hash);
         }
     }
     Err(BencodeError::Decode)
 }
 
+/// Read the torrent file and compute its info hashes, see [`get_hash`]
+pub async fn read_hash(torrent_path: &Path) -> Result<InfoHash, BencodeError> {
+    let file = fs::read(torrent_path)
+        .await
+        .convert_then_add_context("Failed to read torrent file")?;
+    get_hash(&file)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// the file paths with their file indices,
+/// padding files are hidden, and not counted in the file indices
+fn get_file_name_list(
+    info: &BTreeMap<BytesList, Value>,
+) -> Result<Vec<(usize, Vec<String>)>, BencodeError> {
+    Ok(get_files(info)?
+        .into_iter()
+        .filter(|f| !f.pad)
+        .map(|f| f.path)
+        .enumerate()
+        .collect())
+}
+
 #[derive(Debug, Serialize)]
 pub struct FileNode {
     pub id: i32,
@@ -186,10 +312,11 @@ impl FileNodeBuilder {
         }
     }
 
-    fn build(file_name_list: Vec<Vec<String>>, root_dir: String) -> Self {
+    /// build from the file paths with their file indices
+    fn build(file_name_list: Vec<(usize, Vec<String>)>, root_dir: String) -> Self {
         let mut root = Self::new(-1, root_dir);
         let mut _folder = -1;
-        for (i, path) in file_name_list.into_iter().enumerate() {
+        for (i, path) in file_name_list {
             let mut current_node = &mut root;
             let path_len = path.len();
             for (j, label) in path.into_iter().enumerate() {
@@ -232,9 +359,7 @@ impl FileNode {
         let (file_name_list, root_dir) = {
             let torrent_value = get_value(torrent_path).await?;
             let info = get_info(&torrent_value)?;
-            let files = get_files(info)?;
-
-            (get_file_name_list(files)?, get_root_dir(info)?)
+            (get_file_name_list(info)?, get_root_dir(info)?)
         };
         let tree = spawn_blocking(move || {
             let builder = FileNodeBuilder::build(file_name_list, root_dir);
@@ -257,3 +382,207 @@ impl FileNode {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(s: &str) -> Vec<u8> {
+        format!("{}:{s}", s.len()).into_bytes()
+    }
+
+    fn int(i: i64) -> Vec<u8> {
+        format!("i{i}e").into_bytes()
+    }
+
+    fn list(items: Vec<Vec<u8>>) -> Vec<u8> {
+        [b"l".to_vec(), items.concat(), b"e".to_vec()].concat()
+    }
+
+    /// a dict with its keys sorted
+    fn dict(mut entries: Vec<(&str, Vec<u8>)>) -> Vec<u8> {
+        entries.sort_by_key(|(key, _)| *key);
+        let entries = entries
+            .into_iter()
+            .map(|(key, value)| [bytes(key), value].concat());
+        [
+            b"d".to_vec(),
+            entries.collect::<Vec<_>>().concat(),
+            b"e".to_vec(),
+        ]
+        .concat()
+    }
+
+    /// v1 `files` entry, a padding file if `pad`
+    fn v1_file(path: &str, length: i64, pad: bool) -> Vec<u8> {
+        let mut entries = vec![
+            ("length", int(length)),
+            ("path", list(path.split('/').map(bytes).collect())),
+        ];
+        if pad {
+            entries.push(("attr", bytes("p")));
+        }
+        dict(entries)
+    }
+
+    /// v2 `file tree` leaf
+    fn v2_file(length: i64) -> Vec<u8> {
+        dict(vec![(
+            "",
+            dict(vec![("length", int(length)), ("pieces root", bytes("r"))]),
+        )])
+    }
+
+    fn v1_files() -> Vec<u8> {
+        list(vec![
+            v1_file("a.mkv", 100, false),
+            v1_file(".pad/28", 28, true),
+            v1_file("b.mkv", 50, false),
+            v1_file(".pad/14", 14, true),
+            v1_file("sub/c.txt", 10, false),
+        ])
+    }
+
+    fn v2_file_tree() -> Vec<u8> {
+        dict(vec![
+            ("b.mkv", v2_file(50)),
+            ("a.mkv", v2_file(100)),
+            ("sub", dict(vec![("c.txt", v2_file(10))])),
+        ])
+    }
+
+    fn torrent(info: Vec<u8>) -> Vec<u8> {
+        dict(vec![("announce", bytes("http://tracker")), ("info", info)])
+    }
+
+    struct Case {
+        name: &'static str,
+        info: Vec<u8>,
+        v1: bool,
+        v2: bool,
+    }
+
+    fn cases() -> Vec<Case> {
+        let v1 = dict(vec![
+            ("name", bytes("Show")),
+            ("piece length", int(64)),
+            ("pieces", bytes("01234567890123456789")),
+            ("files", v1_files()),
+        ]);
+        let v2 = dict(vec![
+            ("name", bytes("Show")),
+            ("piece length", int(64)),
+            ("meta version", int(2)),
+            ("file tree", v2_file_tree()),
+        ]);
+        let hybrid = dict(vec![
+            ("name", bytes("Show")),
+            ("piece length", int(64)),
+            ("pieces", bytes("01234567890123456789")),
+            ("meta version", int(2)),
+            ("files", v1_files()),
+            ("file tree", v2_file_tree()),
+        ]);
+        vec![
+            Case {
+                name: "v1 with padding files",
+                info: v1,
+                v1: true,
+                v2: false,
+            },
+            Case {
+                name: "v2 file tree",
+                info: v2,
+                v1: false,
+                v2: true,
+            },
+            Case {
+                name: "hybrid",
+                info: hybrid,
+                v1: true,
+                v2: true,
+            },
+        ]
+    }
+
+    #[test]
+    fn parse_multi_file() {
+        for case in cases() {
+            let file = torrent(case.info.clone());
+            let value = Value::from_bencode(&file).unwrap();
+            let (root_dir, lengths) = parse_torrent(&value).unwrap();
+            assert_eq!(root_dir, "Show", "{}", case.name);
+            assert_eq!(lengths, [&100, &50, &10], "{}", case.name);
+
+            let file_name_list = get_file_name_list(get_info(&value).unwrap()).unwrap();
+            let paths: Vec<_> = file_name_list
+                .iter()
+                .map(|(i, path)| (*i, path.join("/")))
+                .collect();
+            assert_eq!(
+                paths,
+                [
+                    (0, "a.mkv".to_string()),
+                    (1, "b.mkv".to_string()),
+                    (2, "sub/c.txt".to_string())
+                ],
+                "{}",
+                case.name
+            );
+
+            let hash = get_hash(&file).unwrap();
+            let expected = InfoHash {
+                v1: case.v1.then(|| to_hex(&Sha1::digest(&case.info))),
+                v2: case.v2.then(|| to_hex(&Sha256::digest(&case.info))),
+            };
+            assert_eq!(hash, expected, "{}", case.name);
+            let id = hash.id();
+            let expected_id = hash.v1.as_deref().or(hash.v2.as_deref().map(truncate_v2));
+            assert_eq!(Some(id), expected_id, "{}", case.name);
+            assert_eq!(id.len(), 40, "{}", case.name);
+        }
+    }
+
+    #[test]
+    fn file_paths_under_root_dir() {
+        let path = std::env::temp_dir().join(format!(
+            "qb-downloader-bencode-{}-paths.torrent",
+            std::process::id()
+        ));
+        std::fs::write(&path, torrent(cases().remove(0).info)).unwrap();
+        let paths = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(get_file_paths(&path));
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(
+            paths.unwrap(),
+            ["Show/a.mkv", "Show/b.mkv", "Show/sub/c.txt"].map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    fn reject_single_file() {
+        let v1 = dict(vec![
+            ("name", bytes("a.mkv")),
+            ("length", int(100)),
+            ("piece length", int(64)),
+            ("pieces", bytes("01234567890123456789")),
+        ]);
+        let v2 = dict(vec![
+            ("name", bytes("a.mkv")),
+            ("meta version", int(2)),
+            ("piece length", int(64)),
+            ("file tree", dict(vec![("a.mkv", v2_file(100))])),
+        ]);
+        for info in [v1, v2] {
+            let file = torrent(info);
+            let value = Value::from_bencode(&file).unwrap();
+            assert!(matches!(
+                parse_torrent(&value),
+                Err(BencodeError::SingleFile)
+            ));
+        }
+    }
+}
@@ -1,7 +1,10 @@
 use crate::{
     auth::{TOKEN, encode},
     errors::{CommonError, TargetContextedResult},
+    persist,
+    qb::DEFAULT_INSTANCE,
     remove_slash,
+    upload::UploadType,
 };
 use arc_swap::{ArcSwap, Guard};
 use directories_next::BaseDirs;
@@ -9,6 +12,7 @@ use directories_next::BaseDirs;
 use log::{debug, info};
 use serde::{Deserialize, Deserializer, Serialize};
 use std::{
+    collections::{BTreeMap, HashSet},
     path::PathBuf,
     sync::{Arc, OnceLock},
 };
@@ -34,10 +38,128 @@ pub struct QbConfig {
     pub qb_host: String,
     pub qb_username: String,
     pub qb_password: String,
+    /// basic auth of the reverse proxy in front of qBittorrent
+    #[serde(default)]
+    pub qb_basic_auth: Option<BasicAuth>,
+    /// extra headers sent with every request to qBittorrent, e.g. an API key of the proxy
+    #[serde(default)]
+    pub qb_headers: BTreeMap<String, String>,
     #[serde(deserialize_with = "strip_slash")]
     pub default_save_path: String,
     pub default_ratio_limit: Option<f64>,
     pub default_seeding_time_limit: Option<i32>,
+    /// interval in seconds of polling qBittorrent for torrent updates
+    #[serde(default = "default_sync_interval")]
+    pub sync_interval: u64,
+    /// category of the torrents managed by qb-downloader
+    #[serde(default = "default_category")]
+    pub category: String,
+    /// prefix of the tags set by qb-downloader, e.g. "qbd" for "qbd_waited"
+    #[serde(default = "default_tag_prefix")]
+    pub tag_prefix: String,
+    /// additional qBittorrent instances, besides the default one above
+    #[serde(default)]
+    pub instances: Vec<QbInstance>,
+}
+
+fn default_sync_interval() -> u64 {
+    2
+}
+
+fn default_category() -> String {
+    String::from("QBD")
+}
+
+fn default_tag_prefix() -> String {
+    String::from("qbd")
+}
+
+/// a named qBittorrent connection
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct QbInstance {
+    pub name: String,
+    #[serde(deserialize_with = "strip_slash")]
+    pub qb_host: String,
+    pub qb_username: String,
+    pub qb_password: String,
+    #[serde(default)]
+    pub qb_basic_auth: Option<BasicAuth>,
+    #[serde(default)]
+    pub qb_headers: BTreeMap<String, String>,
+    /// falls back to `qb.default_save_path` if empty
+    #[serde(default, deserialize_with = "strip_slash")]
+    pub default_save_path: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BasicAuth {
+    pub username: String,
+    pub password: String,
+}
+
+/// connection info of a qBittorrent instance, see [`QbConfig::connections`]
+#[derive(Clone, Copy, Debug)]
+pub struct QbConnection<'a> {
+    pub name: &'a str,
+    pub host: &'a str,
+    pub username: &'a str,
+    pub password: &'a str,
+    pub basic_auth: Option<&'a BasicAuth>,
+    pub headers: &'a BTreeMap<String, String>,
+    pub default_save_path: &'a str,
+}
+
+impl QbConfig {
+    /// all qBittorrent connections, the default one first
+    pub fn connections(&self) -> impl Iterator<Item = QbConnection<'_>> {
+        let default = QbConnection {
+            name: DEFAULT_INSTANCE,
+            host: &self.qb_host,
+            username: &self.qb_username,
+            password: &self.qb_password,
+            basic_auth: self.qb_basic_auth.as_ref(),
+            headers: &self.qb_headers,
+            default_save_path: &self.default_save_path,
+        };
+        let instances = self.instances.iter().map(|i| QbConnection {
+            name: &i.name,
+            host: &i.qb_host,
+            username: &i.qb_username,
+            password: &i.qb_password,
+            basic_auth: i.qb_basic_auth.as_ref(),
+            headers: &i.qb_headers,
+            default_save_path: if i.default_save_path.is_empty() {
+                &self.default_save_path
+            } else {
+                &i.default_save_path
+            },
+        });
+        std::iter::once(default).chain(instances)
+    }
+
+    /// get the connection of the instance by name
+    pub fn connection(&self, name: &str) -> Option<QbConnection<'_>> {
+        self.connections().find(|c| c.name == name)
+    }
+
+    /// check the names of the additional instances,
+    /// which must be non-empty, unique, and not the reserved [`DEFAULT_INSTANCE`]
+    pub fn check_instances(&self) -> Result<(), String> {
+        let mut names = HashSet::new();
+        for instance in &self.instances {
+            let name = instance.name.as_str();
+            if name.trim().is_empty() {
+                return Err("Instance name must not be empty".to_string());
+            }
+            if name == DEFAULT_INSTANCE {
+                return Err(format!("Instance name \"{name}\" is reserved"));
+            }
+            if !names.insert(name) {
+                return Err(format!("Duplicate instance name \"{name}\""));
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Default for QbConfig {
@@ -46,9 +168,15 @@ impl Default for QbConfig {
             qb_host: String::from("http://localhost:8080"),
             qb_username: String::from("admin"),
             qb_password: String::from("adminadmin"),
+            qb_basic_auth: None,
+            qb_headers: BTreeMap::new(),
             default_ratio_limit: Some(-2.0),
             default_seeding_time_limit: Some(-2),
             default_save_path: String::new(),
+            sync_interval: default_sync_interval(),
+            category: default_category(),
+            tag_prefix: default_tag_prefix(),
+            instances: Vec::new(),
         }
     }
 }
@@ -78,6 +206,31 @@ pub struct GeneralConfig {
     pub account: Account,
     #[serde(deserialize_with = "strip_slash")]
     pub default_upload_path: String,
+    /// interval in seconds of processing the task list
+    #[serde(default = "default_poll_interval")]
+    pub task_interval: u64,
+    /// interval in seconds of checking download state,
+    /// the torrent states are synced every `qb.sync_interval`
+    #[serde(default = "default_poll_interval")]
+    pub download_poll_interval: u64,
+    /// interval in seconds of checking upload job state
+    #[serde(default = "default_poll_interval")]
+    pub upload_poll_interval: u64,
+    /// delay in milliseconds after re-adding a torrent for the next part
+    #[serde(default = "default_add_part_delay")]
+    pub add_part_delay: u64,
+    /// regexes matched against the torrent name,
+    /// the named groups of the first match can be used in the upload path
+    #[serde(default)]
+    pub upload_path_patterns: Vec<String>,
+}
+
+fn default_poll_interval() -> u64 {
+    5
+}
+
+fn default_add_part_delay() -> u64 {
+    500
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -93,6 +246,11 @@ impl Default for GeneralConfig {
             multi_login: true,
             account: Account::default(),
             default_upload_path: String::new(),
+            task_interval: default_poll_interval(),
+            download_poll_interval: default_poll_interval(),
+            upload_poll_interval: default_poll_interval(),
+            add_part_delay: default_add_part_delay(),
+            upload_path_patterns: Vec::new(),
         }
     }
 }
@@ -112,6 +270,49 @@ pub struct ConfigValue {
     pub qb: QbConfig,
     pub rclone: RcloneConfig,
     pub general: GeneralConfig,
+    /// named task presets, referenced by `preset` when adding a task
+    #[serde(default)]
+    pub presets: BTreeMap<String, TaskPreset>,
+    #[serde(default)]
+    pub completion: CompletionConfig,
+}
+
+/// actions after a task is done, all disabled by default except archiving
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct CompletionConfig {
+    /// remove the empty directories left in `save_path/root_dir`
+    #[serde(default)]
+    pub remove_empty_dirs: bool,
+    /// delete the cached torrent file
+    #[serde(default)]
+    pub delete_torrent_file: bool,
+    /// move the task from the task list into the archive this many days after it's done,
+    /// right after the other actions if not set, see [`crate::task::archive`]
+    pub remove_after_days: Option<u64>,
+    /// rclone rc command run at last
+    pub rclone_command: Option<RcloneCommand>,
+}
+
+/// a rclone rc command, e.g. `operations/rmdirs`
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct RcloneCommand {
+    pub command: String,
+    /// parameters of the command, which are upload path templates
+    /// with `{dst}` for the uploaded directory, see [`crate::upload::template`]
+    #[serde(default)]
+    pub params: BTreeMap<String, String>,
+}
+
+/// Defaults of adding a task, every field is optional
+/// and is overridden by the one given in the request.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct TaskPreset {
+    pub upload_type: Option<UploadType>,
+    pub upload_path: Option<String>,
+    /// maximum size of a part in GB
+    pub max_size: Option<i64>,
+    pub seeding_time_limit: Option<i32>,
+    pub ratio_limit: Option<f64>,
 }
 
 #[derive(Debug)]
@@ -156,10 +357,7 @@ pub async fn save() -> Result<(), CommonError> {
             .await
             .convert_then_add_context("Failed to create config directory")?;
     }
-    tokio::fs::write(filepath, content)
-        .await
-        .convert_then_add_context("Failed to write config file")?;
-    Ok(())
+    persist::write(filepath, content).await
 }
 
 pub fn init(path: Option<PathBuf>) -> Result<(), CommonError> {
@@ -194,3 +392,42 @@ pub fn strip_slash<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String,
     let s = String::deserialize(deserializer)?;
     Ok(remove_slash(&s))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_instances(names: &[&str]) -> QbConfig {
+        QbConfig {
+            instances: names
+                .iter()
+                .map(|name| QbInstance {
+                    name: name.to_string(),
+                    qb_host: String::from("http://localhost:8080"),
+                    qb_username: String::from("admin"),
+                    qb_password: String::from("adminadmin"),
+                    qb_basic_auth: None,
+                    qb_headers: BTreeMap::new(),
+                    default_save_path: String::new(),
+                })
+                .collect(),
+            ..QbConfig::default()
+        }
+    }
+
+    #[test]
+    fn check_instances() {
+        assert!(with_instances(&[]).check_instances().is_ok());
+        assert!(
+            with_instances(&["nas", "seedbox"])
+                .check_instances()
+                .is_ok()
+        );
+        for names in [&["nas", " "][..], &["default"], &["nas", "seedbox", "nas"]] {
+            assert!(
+                with_instances(names).check_instances().is_err(),
+                "{names:?}"
+            );
+        }
+    }
+}
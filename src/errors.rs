@@ -61,6 +61,17 @@ pub enum CommonErrorKind {
         #[source]
         request::RequestError,
     ),
+
+    #[cfg(feature = "sqlite")]
+    #[error("Sqlite error")]
+    Sqlite(#[source] Box<rusqlite::Error>),
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for CommonErrorKind {
+    fn from(e: rusqlite::Error) -> Self {
+        CommonErrorKind::Sqlite(Box::new(e))
+    }
 }
 
 impl From<DeError> for CommonErrorKind {
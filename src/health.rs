@@ -0,0 +1,92 @@
+//! liveness and readiness state, probed at "/api/health" and "/api/ready"
+use std::{
+    sync::{Arc, LazyLock},
+    time::{Duration, Instant},
+};
+
+use arc_swap::ArcSwap;
+use serde::Serialize;
+
+use crate::{
+    config, qb, task,
+    upload::{Rclone, UploaderTrait},
+};
+
+/// the task handler is considered stalled if it hasn't ticked for this long,
+/// or three task intervals if that is longer
+const TICK_STALE: Duration = Duration::from_secs(30);
+/// the task list is considered unsaved if its changes haven't been written for this long
+const SAVE_STALE: Duration = Duration::from_secs(60);
+/// reuse the last rclone probe result within this duration
+const RCLONE_PROBE_TTL: Duration = Duration::from_secs(30);
+
+static LAST_TICK: LazyLock<ArcSwap<Option<Instant>>> =
+    LazyLock::new(|| ArcSwap::from_pointee(None));
+static LAST_RCLONE_PROBE: LazyLock<ArcSwap<Option<(Instant, bool)>>> =
+    LazyLock::new(|| ArcSwap::from_pointee(None));
+
+/// record a successful task list processing tick
+pub fn record_tick() {
+    LAST_TICK.store(Arc::new(Some(Instant::now())));
+}
+
+fn age(instant: &ArcSwap<Option<Instant>>) -> Option<Duration> {
+    instant.load().map(|i| i.elapsed())
+}
+
+/// probe rclone with `core/version`, reusing the last result if it is fresh enough
+async fn probe_rclone() -> bool {
+    if let Some((checked_at, ok)) = **LAST_RCLONE_PROBE.load()
+        && checked_at.elapsed() < RCLONE_PROBE_TTL
+    {
+        return ok;
+    }
+    let ok = {
+        let rclone_cfg = &config::value().rclone;
+        Rclone::test(
+            &rclone_cfg.rclone_host,
+            &rclone_cfg.rclone_username,
+            &rclone_cfg.rclone_password,
+        )
+        .await
+    };
+    LAST_RCLONE_PROBE.store(Arc::new(Some((Instant::now(), ok))));
+    ok
+}
+
+/// readiness report
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub qb_logined: bool,
+    pub rclone_ok: bool,
+    /// false if the task list has changes that haven't been saved for a while
+    pub task_saved_recently: bool,
+    /// seconds since the task list was last saved
+    pub last_save_secs: Option<u64>,
+    /// seconds since the last successful task list processing tick
+    pub last_tick_secs: Option<u64>,
+}
+
+/// check the dependencies of the application
+pub async fn readiness() -> Readiness {
+    let qb_logined = qb::all_logined();
+    let rclone_ok = probe_rclone().await;
+    let last_save = task::last_saved();
+    let last_tick = age(&LAST_TICK);
+
+    let task_saved_recently = task::unsaved_for().is_none_or(|age| age < SAVE_STALE);
+    let tick_stale = TICK_STALE.max(Duration::from_secs(
+        config::value().general.task_interval * 3,
+    ));
+    let tick_fresh = last_tick.is_some_and(|age| age < tick_stale);
+
+    Readiness {
+        ready: qb_logined && rclone_ok && task_saved_recently && tick_fresh,
+        qb_logined,
+        rclone_ok,
+        task_saved_recently,
+        last_save_secs: last_save.map(|d| d.as_secs()),
+        last_tick_secs: last_tick.map(|d| d.as_secs()),
+    }
+}
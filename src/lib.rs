@@ -2,6 +2,9 @@ pub mod app;
 mod bencode;
 mod config;
 mod errors;
+mod health;
+mod magnet;
+mod persist;
 mod qb;
 mod request;
 mod server;
@@ -14,14 +17,26 @@ const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 /// remove the trailing slash from a path or host.
 /// # Example
-/// ```
-/// use qb_downloader_rust::remove_slash;
-/// let path = "/home/user/torrents/";
-/// assert_eq!(remove_slash(path), "/home/user/torrents");
-/// let host = "http://localhost:8080/";
-/// assert_eq!(remove_slash(host), "http://localhost:8080");
+/// ```text
+/// "/home/user/torrents/"   -> "/home/user/torrents"
+/// "http://localhost:8080/" -> "http://localhost:8080"
 /// ```
 fn remove_slash<T: AsRef<str>>(path_or_host: T) -> String {
     let s = path_or_host.as_ref();
     s.trim().strip_suffix('/').unwrap_or(s).to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remove_trailing_slash() {
+        assert_eq!(remove_slash("/home/user/torrents/"), "/home/user/torrents");
+        assert_eq!(
+            remove_slash("http://localhost:8080/"),
+            "http://localhost:8080"
+        );
+        assert_eq!(remove_slash("/home/user"), "/home/user");
+    }
+}
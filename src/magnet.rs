@@ -0,0 +1,313 @@
+//! magnet URI parsing, see BEP 9 and BEP 52
+use base32::Alphabet;
+use thiserror::Error;
+
+use crate::bencode;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum MagnetError {
+    #[error("Not a magnet link")]
+    NotMagnet,
+
+    #[error("Invalid magnet query")]
+    InvalidQuery,
+
+    #[error("Missing BitTorrent info hash")]
+    MissingHash,
+
+    #[error("Invalid info hash: {0}")]
+    InvalidHash(String),
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Magnet {
+    /// v1 info hash from `urn:btih:`, lowercase hex
+    pub v1: Option<String>,
+    /// v2 info hash from `urn:btmh:`, lowercase hex of the full SHA-256
+    pub v2: Option<String>,
+    /// display name `dn`
+    pub name: Option<String>,
+    /// trackers `tr`
+    pub trackers: Vec<String>,
+}
+
+impl Magnet {
+    /// The hash identifying the torrent in qBittorrent,
+    /// which is the v1 hash, or the v2 hash truncated to 20 bytes for v2-only torrents.
+    /// A hybrid torrent given by `btmh` only is identified by its v1 hash in qBittorrent,
+    /// which can't be known before metadata arrives.
+    pub fn hash(&self) -> &str {
+        match (&self.v1, &self.v2) {
+            (Some(v1), _) => v1,
+            (None, Some(v2)) => bencode::truncate_v2(v2),
+            (None, None) => unreachable!("magnet without hash"),
+        }
+    }
+}
+
+/// Parse a magnet link, parameters may be in any order and percent-encoded.
+/// Only the first `btih` and `btmh` are taken, other `xt` are ignored.
+/// # Error
+/// [`MagnetError`] if not a magnet link, or no valid BitTorrent info hash
+pub fn parse(url: &str) -> Result<Magnet, MagnetError> {
+    let url = url.trim();
+    let query = strip_prefix_ignore_case(url, "magnet:?").ok_or(MagnetError::NotMagnet)?;
+    let params: Vec<(String, String)> =
+        serde_urlencoded::from_str(query).map_err(|_| MagnetError::InvalidQuery)?;
+
+    let mut magnet = Magnet::default();
+    for (key, value) in params {
+        // numbered keys are allowed, e.g. "xt.1"
+        let key = key
+            .split('.')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        match key.as_str() {
+            "xt" => parse_xt(&value, &mut magnet)?,
+            "dn" if magnet.name.is_none() && !value.is_empty() => magnet.name = Some(value),
+            "tr" if !value.is_empty() => magnet.trackers.push(value),
+            _ => {}
+        }
+    }
+    if magnet.v1.is_none() && magnet.v2.is_none() {
+        return Err(MagnetError::MissingHash);
+    }
+    Ok(magnet)
+}
+
+fn parse_xt(xt: &str, magnet: &mut Magnet) -> Result<(), MagnetError> {
+    if let Some(hash) = strip_prefix_ignore_case(xt, "urn:btih:") {
+        if magnet.v1.is_none() {
+            magnet.v1 = Some(parse_btih(hash)?);
+        }
+    } else if let Some(hash) = strip_prefix_ignore_case(xt, "urn:btmh:")
+        && magnet.v2.is_none()
+    {
+        magnet.v2 = Some(parse_btmh(hash)?);
+    }
+    Ok(())
+}
+
+/// 40 hex or 32 base32 characters
+fn parse_btih(hash: &str) -> Result<String, MagnetError> {
+    let invalid = || MagnetError::InvalidHash(hash.to_string());
+    match hash.len() {
+        40 if is_hex(hash) => Ok(hash.to_ascii_lowercase()),
+        32 => base32::decode(
+            Alphabet::Rfc4648 { padding: false },
+            &hash.to_ascii_uppercase(),
+        )
+        .filter(|raw| raw.len() == 20)
+        .map(|raw| to_hex(&raw))
+        .ok_or_else(invalid),
+        _ => Err(invalid()),
+    }
+}
+
+/// hex multihash, only SHA-256 (code 0x12, length 0x20) is used by BitTorrent v2
+fn parse_btmh(hash: &str) -> Result<String, MagnetError> {
+    hash.strip_prefix("1220")
+        .filter(|digest| digest.len() == 64 && is_hex(digest))
+        .map(str::to_ascii_lowercase)
+        .ok_or_else(|| MagnetError::InvalidHash(hash.to_string()))
+}
+
+fn strip_prefix_ignore_case<'a>(s: &'a str, prefix: &str) -> Option<&'a str> {
+    s.get(..prefix.len())
+        .filter(|head| head.eq_ignore_ascii_case(prefix))
+        .map(|_| &s[prefix.len()..])
+}
+
+fn is_hex(s: &str) -> bool {
+    s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V1: &str = "c12fe1c06bba254a9dc9f519b335aa7c1367a88a";
+    const V1_BASE32: &str = "YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK";
+    const V2: &str = "d8dd32ac93357c368556af3ac1d95c9d76bd0dff6fa9833ecdac3d53134efabb";
+
+    struct Case {
+        url: &'static str,
+        hash: &'static str,
+        v2: Option<&'static str>,
+        name: Option<&'static str>,
+        trackers: &'static [&'static str],
+    }
+
+    const fn case(url: &'static str, hash: &'static str) -> Case {
+        Case {
+            url,
+            hash,
+            v2: None,
+            name: None,
+            trackers: &[],
+        }
+    }
+
+    #[test]
+    fn parse_valid() {
+        let cases = [
+            case(
+                "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a",
+                V1,
+            ),
+            // upper-case hex
+            case(
+                "magnet:?xt=urn:btih:C12FE1C06BBA254A9DC9F519B335AA7C1367A88A",
+                V1,
+            ),
+            // base32, upper and lower case
+            case("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK", V1),
+            case("magnet:?xt=urn:btih:yex6dqdlxisuvhoj6um3gnnkpqjwpkek", V1),
+            // case-insensitive scheme, keys and urn
+            case(
+                "MAGNET:?XT=URN:BTIH:c12fe1c06bba254a9dc9f519b335aa7c1367a88a",
+                V1,
+            ),
+            // surrounding whitespace
+            case(
+                "  magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a\n",
+                V1,
+            ),
+            // percent-encoded urn
+            case(
+                "magnet:?xt=urn%3Abtih%3Ac12fe1c06bba254a9dc9f519b335aa7c1367a88a",
+                V1,
+            ),
+            // numbered xt
+            case(
+                "magnet:?xt.1=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a",
+                V1,
+            ),
+            // other urns ignored
+            case(
+                "magnet:?xt=urn:ed2k:31d6cfe0d16ae931b73c59d7e0c089c0&xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a",
+                V1,
+            ),
+            // dn before xt, percent-encoded and plus as space
+            Case {
+                name: Some("Big Buck Bunny [1080p]"),
+                ..case(
+                    "magnet:?dn=Big+Buck%20Bunny%20%5B1080p%5D&xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a",
+                    V1,
+                )
+            },
+            // trackers
+            Case {
+                name: Some("Show S01"),
+                trackers: &[
+                    "udp://tracker.example.org:1337/announce",
+                    "https://tracker.example.com/announce?k=v",
+                ],
+                ..case(
+                    "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn=Show%20S01&tr=udp%3A%2F%2Ftracker.example.org%3A1337%2Fannounce&tr=https%3A%2F%2Ftracker.example.com%2Fannounce%3Fk%3Dv",
+                    V1,
+                )
+            },
+            // empty dn and tr ignored
+            case(
+                "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn=&tr=",
+                V1,
+            ),
+            // first dn wins
+            Case {
+                name: Some("first"),
+                ..case(
+                    "magnet:?dn=first&xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn=second",
+                    V1,
+                )
+            },
+            // v2 only, identified by the truncated v2 hash
+            Case {
+                v2: Some(V2),
+                ..case(
+                    "magnet:?xt=urn:btmh:1220d8dd32ac93357c368556af3ac1d95c9d76bd0dff6fa9833ecdac3d53134efabb",
+                    &V2[..40],
+                )
+            },
+            // v2 upper-case
+            Case {
+                v2: Some(V2),
+                ..case(
+                    "magnet:?xt=urn:btmh:1220D8DD32AC93357C368556AF3AC1D95C9D76BD0DFF6FA9833ECDAC3D53134EFABB",
+                    &V2[..40],
+                )
+            },
+            // hybrid, identified by the v1 hash
+            Case {
+                v2: Some(V2),
+                ..case(
+                    "magnet:?xt=urn:btmh:1220d8dd32ac93357c368556af3ac1d95c9d76bd0dff6fa9833ecdac3d53134efabb&xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a",
+                    V1,
+                )
+            },
+        ];
+
+        for c in cases {
+            let magnet = parse(c.url).unwrap_or_else(|e| panic!("{}: {e}", c.url));
+            assert_eq!(magnet.hash(), c.hash, "{}", c.url);
+            assert_eq!(magnet.v2.as_deref(), c.v2, "{}", c.url);
+            assert_eq!(magnet.name.as_deref(), c.name, "{}", c.url);
+            assert_eq!(magnet.trackers, c.trackers, "{}", c.url);
+        }
+    }
+
+    #[test]
+    fn base32_matches_hex() {
+        let url = format!("magnet:?xt=urn:btih:{V1_BASE32}");
+        assert_eq!(parse(&url).unwrap().v1.as_deref(), Some(V1));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        use MagnetError::*;
+        let cases = [
+            ("", NotMagnet),
+            ("http://example.com/file.torrent", NotMagnet),
+            ("magnet:", NotMagnet),
+            ("c12fe1c06bba254a9dc9f519b335aa7c1367a88a", NotMagnet),
+            ("magnet:?", MissingHash),
+            ("magnet:?dn=name", MissingHash),
+            (
+                "magnet:?xt=urn:ed2k:31d6cfe0d16ae931b73c59d7e0c089c0",
+                MissingHash,
+            ),
+            (
+                "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88",
+                InvalidHash("c12fe1c06bba254a9dc9f519b335aa7c1367a88".into()),
+            ),
+            (
+                "magnet:?xt=urn:btih:g12fe1c06bba254a9dc9f519b335aa7c1367a88a",
+                InvalidHash("g12fe1c06bba254a9dc9f519b335aa7c1367a88a".into()),
+            ),
+            (
+                "magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKE1",
+                InvalidHash("YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKE1".into()),
+            ),
+            // sha1 multihash is not BitTorrent v2
+            (
+                "magnet:?xt=urn:btmh:1114c12fe1c06bba254a9dc9f519b335aa7c1367a88a",
+                InvalidHash("1114c12fe1c06bba254a9dc9f519b335aa7c1367a88a".into()),
+            ),
+            (
+                "magnet:?xt=urn:btmh:1220d8dd32ac93357c368556af3ac1d95c9d76bd0dff6fa9833ecdac3d53134efa",
+                InvalidHash(
+                    "1220d8dd32ac93357c368556af3ac1d95c9d76bd0dff6fa9833ecdac3d53134efa".into(),
+                ),
+            ),
+        ];
+
+        for (url, err) in cases {
+            assert_eq!(parse(url), Err(err), "{url}");
+        }
+    }
+}
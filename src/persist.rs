@@ -0,0 +1,180 @@
+//! crash-safe file persistence
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use tokio::fs;
+
+use crate::errors::{CommonError, TargetContextedResult};
+
+/// numbers the temp files of [`write`], so concurrent writes never share one
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Write `contents` to `path` atomically.
+/// The contents is written to a temp file next to the target and fsynced,
+/// then renamed over the target, so the target is either the old or the new content.
+/// Each write has its own temp file, concurrent writes leave the target with one of the contents.
+pub async fn write(path: &Path, contents: impl AsRef<[u8]>) -> Result<(), CommonError> {
+    let tmp_path = with_suffix(
+        path,
+        &format!(
+            "tmp.{}.{}",
+            std::process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ),
+    );
+    let result = write_tmp(&tmp_path, path, contents.as_ref()).await;
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path).await;
+    }
+    result?;
+
+    // make the rename itself durable
+    if let Some(parent) = path.parent()
+        && let Ok(dir) = fs::File::open(parent).await
+    {
+        let _ = dir.sync_all().await;
+    }
+    Ok(())
+}
+
+/// write and fsync `tmp_path`, then rename it to `path`
+async fn write_tmp(tmp_path: &Path, path: &Path, contents: &[u8]) -> Result<(), CommonError> {
+    fs::write(tmp_path, contents)
+        .await
+        .convert_then_with_context(|| {
+            format!("Failed to write temp file: {}", tmp_path.display()).into()
+        })?;
+    fs::File::open(tmp_path)
+        .await
+        .convert_then_add_context("Failed to open temp file")?
+        .sync_all()
+        .await
+        .convert_then_add_context("Failed to sync temp file")?;
+    fs::rename(tmp_path, path)
+        .await
+        .convert_then_with_context(|| format!("Failed to rename to {}", path.display()).into())
+}
+
+/// Shift the backups of `path` by one and copy the current file to the newest backup,
+/// keeping at most `keep` backups.
+/// Not safe to run concurrently for the same `path`, the caller serializes its writes.
+pub async fn rotate_backups(path: &Path, keep: usize) -> Result<(), CommonError> {
+    if keep == 0 || !fs::try_exists(path).await.unwrap_or(false) {
+        return Ok(());
+    }
+    let backups = backup_paths(path, keep);
+    for i in (1..backups.len()).rev() {
+        if fs::try_exists(&backups[i - 1]).await.unwrap_or(false) {
+            fs::rename(&backups[i - 1], &backups[i])
+                .await
+                .convert_then_add_context("Failed to rotate backup")?;
+        }
+    }
+    fs::copy(path, &backups[0])
+        .await
+        .convert_then_add_context("Failed to create backup")?;
+    Ok(())
+}
+
+/// Backup paths of `path`, newest first, e.g. `tasks.json.bak.1`, `tasks.json.bak.2`
+pub fn backup_paths(path: &Path, keep: usize) -> Vec<PathBuf> {
+    (1..=keep)
+        .map(|i| with_suffix(path, &format!("bak.{i}")))
+        .collect()
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut s = path.as_os_str().to_os_string();
+    s.push(".");
+    s.push(suffix);
+    PathBuf::from(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// an empty directory for the test, removed when dropped
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "qb-downloader-persist-{}-{name}",
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn block_on<F: Future>(f: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(f)
+    }
+
+    fn read(path: &Path) -> String {
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn write_replaces_target() {
+        let dir = TestDir::new("write");
+        let path = dir.0.join("tasks.json");
+        block_on(write(&path, "old")).unwrap();
+        assert_eq!(read(&path), "old");
+        block_on(write(&path, "new")).unwrap();
+        assert_eq!(read(&path), "new");
+        assert_eq!(std::fs::read_dir(&dir.0).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn concurrent_writes() {
+        let dir = TestDir::new("write-concurrent");
+        let path = dir.0.join("tasks.json");
+        let contents: Vec<String> = (0..16).map(|i| i.to_string()).collect();
+        // tokio fs runs on blocking threads, so the writes interleave
+        let results = block_on(futures_util::future::join_all(
+            contents.iter().map(|contents| write(&path, contents)),
+        ));
+        assert!(results.iter().all(Result::is_ok));
+        assert!(contents.contains(&read(&path)));
+        // no temp file is left behind
+        assert_eq!(std::fs::read_dir(&dir.0).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn rotate_keeps_newest_backups() {
+        let dir = TestDir::new("rotate");
+        let path = dir.0.join("tasks.json");
+        for contents in ["1", "2", "3", "4"] {
+            std::fs::write(&path, contents).unwrap();
+            block_on(rotate_backups(&path, 2)).unwrap();
+        }
+        let backups = backup_paths(&path, 3);
+        assert_eq!(read(&backups[0]), "4");
+        assert_eq!(read(&backups[1]), "3");
+        assert!(!backups[2].exists());
+        assert_eq!(read(&path), "4");
+    }
+
+    #[test]
+    fn rotate_without_file() {
+        let dir = TestDir::new("rotate-missing");
+        let path = dir.0.join("tasks.json");
+        block_on(rotate_backups(&path, 2)).unwrap();
+        assert!(!backup_paths(&path, 1)[0].exists());
+    }
+}
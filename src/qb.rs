@@ -1,20 +1,24 @@
 //! This module provides API to interact with qBittorrent
 mod qb_request;
+pub mod sync;
 use crate::errors::{IntoContextedError, TargetContextedResult};
 use crate::qb::qb_request::QbRequest;
 use crate::request::multipart::MultipartBuilder;
-use crate::request::{MyRequest, MyRequestBuilder, RequestError};
-use crate::{config, errors::CommonError, remove_slash, request};
+use crate::request::{MyRequestBuilder, RequestError};
+use crate::{
+    config::{self, BasicAuth, QbConfig, QbConnection},
+    errors::{CommonError, format_error_chain},
+    remove_slash, request,
+};
 use arc_swap::ArcSwap;
-use base32::Alphabet;
+use futures_util::future::join_all;
 use log::{error, info, warn};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::LazyLock;
 use std::{borrow::Cow, fmt::Display, fs::File, io::Write, path::Path, sync::Arc};
 use thiserror::Error;
-const CATEGORY: &str = "QBD";
 
 #[derive(Debug, Error)]
 pub enum QbError {
@@ -33,8 +37,8 @@ pub enum QbError {
     #[error(transparent)]
     CommonError(#[from] CommonError),
 
-    #[error("Failed to parse magnet")]
-    ParseMagnet,
+    #[error("Torrent list not synced yet")]
+    NotSynced,
 }
 
 impl From<RequestError> for QbError {
@@ -51,158 +55,329 @@ impl From<nyquest::Error> for QbError {
     }
 }
 
-/// qBittorrent tag
+/// qBittorrent tag, prefixed by `qb.tag_prefix`
 pub enum Tag {
     // new added torrent, but haven't added to task list yet
     Waited,
 }
 
 impl Tag {
-    pub const fn as_str(&self) -> &'static str {
+    const fn suffix(&self) -> &'static str {
         match self {
-            Tag::Waited => "qbd_waited",
+            Tag::Waited => "waited",
         }
     }
+
+    /// the tag name with the configured prefix
+    pub fn name(&self) -> String {
+        self.with_prefix(&config::value().qb.tag_prefix)
+    }
+
+    pub fn with_prefix(&self, prefix: &str) -> String {
+        format!("{prefix}_{}", self.suffix())
+    }
 }
 
 impl Display for Tag {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.as_str())
+        write!(f, "{}", self.name())
     }
 }
 
+/// the configured category of the torrents managed by qb-downloader
+pub fn category() -> String {
+    config::value().qb.category.clone()
+}
+
 #[derive(Debug)]
 pub struct Qb {
+    name: Arc<str>,
     host: Arc<str>,
+    auth: ProxyAuth,
     logined: bool,
     version: u8,
+    /// none if qBittorrent bypasses authentication, e.g. for whitelisted IPs
     cookie: Option<String>,
 }
 
+/// authentication besides qBittorrent login, e.g. of a reverse proxy in front of it
+#[derive(Debug, Clone, Default)]
+pub struct ProxyAuth {
+    basic_auth: Option<BasicAuth>,
+    headers: Vec<(String, String)>,
+}
+
+impl ProxyAuth {
+    pub fn new(basic_auth: Option<&BasicAuth>, headers: &BTreeMap<String, String>) -> Self {
+        Self {
+            basic_auth: basic_auth.cloned(),
+            headers: headers
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+        }
+    }
+
+    /// attach the basic auth and extra headers to the request
+    fn apply<B: MyRequestBuilder>(&self, mut builder: B) -> B {
+        if let Some(auth) = &self.basic_auth {
+            builder = builder.basic_auth(&auth.username, &auth.password);
+        }
+        for (name, value) in &self.headers {
+            builder = builder.header(name.clone(), value.clone());
+        }
+        builder
+    }
+}
+
+impl From<QbConnection<'_>> for ProxyAuth {
+    fn from(conn: QbConnection<'_>) -> Self {
+        Self::new(conn.basic_auth, conn.headers)
+    }
+}
+
 #[derive(Clone, Deserialize, Debug)]
 pub struct TorrentInfo {
     pub hash: String,
     pub state: String,
     pub progress: f64,
+    #[serde(default)]
+    pub category: String,
+}
+
+/// torrent not managed by qb-downloader, see [`get_foreign_torrents`]
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct TorrentSummary {
+    pub hash: String,
+    pub name: String,
+    pub save_path: String,
+    pub state: String,
+    pub progress: f64,
+    pub size: i64,
+    #[serde(default)]
+    pub category: String,
 }
 
-static QB: LazyLock<ArcSwap<Qb>> = LazyLock::new(|| ArcSwap::from_pointee(Qb::new()));
+/// name of the instance configured by the top-level `qb` fields
+pub const DEFAULT_INSTANCE: &str = "default";
+
+/// logged-in state of every configured qBittorrent instance, by name
+static QB: LazyLock<ArcSwap<HashMap<Arc<str>, Arc<Qb>>>> =
+    LazyLock::new(|| ArcSwap::from_pointee(HashMap::new()));
 
 impl Qb {
-    pub fn new() -> Self {
+    fn new(name: &str, host: &str, auth: ProxyAuth) -> Self {
         Qb {
-            host: Arc::from(config::value().qb.qb_host.as_str()),
+            name: Arc::from(name),
+            host: Arc::from(host),
+            auth,
             logined: false,
             version: 0,
             cookie: None,
         }
     }
 }
-pub fn is_logined() -> bool {
-    QB.load().logined
+
+fn store(qb: Qb) {
+    let qb = Arc::new(qb);
+    QB.rcu(|map| {
+        let mut map = HashMap::clone(map);
+        map.insert(qb.name.clone(), qb.clone());
+        map
+    });
+}
+
+/// whether the instance is logged in
+pub fn is_logined(instance: &str) -> bool {
+    QB.load().get(instance).is_some_and(|qb| qb.logined)
 }
 
-/// get the host if logged in, else return error
-fn host() -> Result<Arc<str>, QbError> {
-    let qb = QB.load();
-    if qb.logined {
-        Ok(qb.host.clone())
-    } else {
-        Err(QbError::NotLogin)
+/// whether any configured instance is logged in
+pub fn any_logined() -> bool {
+    instances().iter().any(|i| is_logined(i))
+}
+
+/// whether all configured instances are logged in
+pub fn all_logined() -> bool {
+    instances().iter().all(|i| is_logined(i))
+}
+
+/// names of all configured instances, the default one first
+pub fn instances() -> Vec<String> {
+    config::value()
+        .qb
+        .connections()
+        .map(|c| c.name.to_string())
+        .collect()
+}
+
+/// get the instance if logged in, else return error
+fn session(instance: &str) -> Result<Arc<Qb>, QbError> {
+    match QB.load().get(instance) {
+        Some(qb) if qb.logined => Ok(qb.clone()),
+        _ => Err(QbError::NotLogin),
     }
 }
 
-fn version() -> u8 {
-    QB.load().version
+fn version(instance: &str) -> u8 {
+    QB.load().get(instance).map_or(0, |qb| qb.version)
 }
 
-/// try to login with qb info in config
+/// Choose the instance to add a new torrent to.
+/// The explicitly chosen instance must be logged in,
+/// otherwise the logged-in instance with the most free space is chosen.
+/// # Error
+/// [`QbError::NotLogin`] if no suitable instance is logged in
+pub fn place(instance: Option<&str>) -> Result<String, QbError> {
+    if let Some(instance) = instance {
+        return if is_logined(instance) {
+            Ok(instance.to_string())
+        } else {
+            Err(QbError::NotLogin)
+        };
+    }
+    instances()
+        .into_iter()
+        .filter(|i| is_logined(i))
+        // the first one wins on ties, e.g. free space unknown
+        .rev()
+        .max_by_key(|i| sync::free_space(i).unwrap_or(0))
+        .ok_or(QbError::NotLogin)
+}
+
+/// try to login all instances in config, instances no longer configured are dropped
 pub async fn login() {
-    let qb_cfg = &config::value().qb;
-    login_with(&qb_cfg.qb_host, &qb_cfg.qb_username, &qb_cfg.qb_password).await;
+    let names = instances();
+    join_all(names.iter().map(|name| login_instance(name))).await;
+    QB.rcu(|map| {
+        let mut map = HashMap::clone(map);
+        map.retain(|name, _| names.iter().any(|n| n.as_str() == name.as_ref()));
+        map
+    });
+}
+
+/// try to login the instance with its info in config
+pub async fn login_instance(instance: &str) {
+    let (host, username, password, auth) = {
+        let c = config::value();
+        let Some(conn) = c.qb.connection(instance) else {
+            warn!("qBittorrent instance {instance} is not configured");
+            return;
+        };
+        (
+            conn.host.to_string(),
+            conn.username.to_string(),
+            conn.password.to_string(),
+            ProxyAuth::from(conn),
+        )
+    };
+    login_with(instance, &host, &username, &password, auth).await;
 }
 
-/// login to qBittorrent, and update the host and logined status
+/// login to qBittorrent, and update the host and logined status of the instance
 /// # Precondition
 /// - host has been normalized.
-pub async fn login_with(host: &str, username: &str, password: &str) {
-    match test_login(host, username, password).await {
-        None => {
-            QB.store(Arc::new(Qb {
-                host: Arc::from(host),
-                logined: false,
-                version: 0,
-                cookie: None,
-            }));
-            warn!("qBittorrent login failed");
+async fn login_with(instance: &str, host: &str, username: &str, password: &str, auth: ProxyAuth) {
+    let cookie = match test_login(host, username, password, &auth).await {
+        Ok(cookie) => cookie,
+        Err(e) => {
+            warn!(
+                "qBittorrent {instance} login failed\n{}",
+                format_error_chain(e)
+            );
+            store(Qb::new(instance, host, auth));
+            return;
         }
-        Some(cookie) => match get_version(host, cookie.clone()).await {
-            Ok(v) => {
-                QB.store(Arc::new(Qb {
-                    host: Arc::from(host),
-                    logined: true,
-                    version: v,
-                    cookie: Some(cookie),
-                }));
-                info!("qBittorrent login successful");
+    };
+    // the version request also verifies the session, which is the only check without cookie
+    match get_version(host, cookie.as_deref(), &auth).await {
+        Ok(v) => {
+            if cookie.is_none() {
+                info!("qBittorrent {instance} authentication bypassed");
             }
-            Err(e) => {
-                if let QbError::UnsupportedVersion = e {
-                    warn!("qBittorrent version is not supported");
-                } else {
-                    error!("Failed to get qBittorrent version");
+            store(Qb {
+                logined: true,
+                version: v,
+                cookie,
+                ..Qb::new(instance, host, auth)
+            });
+            info!("qBittorrent {instance} login successful");
+        }
+        Err(e) => {
+            match e {
+                QbError::NotLogin => warn!("qBittorrent {instance} login failed"),
+                QbError::UnsupportedVersion => {
+                    warn!("qBittorrent {instance} version is not supported")
                 }
-                QB.store(Arc::new(Qb {
-                    host: Arc::from(host),
-                    logined: false,
-                    version: 0,
-                    cookie: None,
-                }));
+                _ => error!("Failed to get qBittorrent {instance} version"),
             }
-        },
+            store(Qb::new(instance, host, auth));
+        }
     }
 }
 
-/// get qbitrorrent version, require cookie explicitly
-pub async fn get_version(host: &str, cookie: String) -> Result<u8, QbError> {
-    request::get(format!("{host}/api/v2/app/version"))
-        .header(nyquest::header::COOKIE, cookie)
-        .send_and_then(async |res| {
-            let ver = res.text().await?;
-            let c = ver
-                .strip_prefix("v")
-                .map(|v| v.split('.').collect::<Vec<&str>>())
-                .unwrap();
-            let first = c[0].parse::<u8>().unwrap();
-            if first == 5 {
-                return Ok(first);
-            } else if first == 4 {
-                let second = c[1].parse::<u8>().unwrap();
-                if second >= 1 {
-                    return Ok(first);
-                }
-            }
-            Err(QbError::UnsupportedVersion)
-        })
-        .await
+/// get qbitrorrent version, require cookie explicitly,
+/// no cookie if qBittorrent bypasses authentication
+/// # Error
+/// [`QbError::NotLogin`] if not authorized
+pub async fn get_version(
+    host: &str,
+    cookie: Option<&str>,
+    auth: &ProxyAuth,
+) -> Result<u8, QbError> {
+    let mut req = auth.apply(request::get(format!("{host}/api/v2/app/version")));
+    if let Some(cookie) = cookie {
+        req = req.header(nyquest::header::COOKIE, cookie.to_string());
+    }
+    let res = match req.send().await {
+        Err(RequestError::Response(403)) => return Err(QbError::NotLogin),
+        res => res?,
+    };
+    parse_version(&res.text().await?)
+}
+
+/// Parse the major version from e.g. "v5.0.1", only v4.4+ is supported,
+/// which leaves padding files out of the file indices, see [`crate::bencode::parse_torrent`]
+fn parse_version(ver: &str) -> Result<u8, QbError> {
+    let mut parts = ver
+        .trim()
+        .strip_prefix('v')
+        .ok_or(QbError::UnsupportedVersion)?
+        .split('.')
+        .map(|p| p.parse::<u8>());
+    match (parts.next(), parts.next()) {
+        (Some(Ok(5)), _) => Ok(5),
+        (Some(Ok(4)), Some(Ok(minor))) if minor >= 4 => Ok(4),
+        _ => Err(QbError::UnsupportedVersion),
+    }
 }
 
-/// return cookie if login successfully
+/// Login to qBittorrent, return the cookie if set.
+/// qBittorrent doesn't set cookie if authentication is bypassed,
+/// verify the login by [`get_version`] then.
 /// accept any form of host, e.g. "http://example.com" or "example.com"
-pub async fn test_login(host: &str, username: &str, pass: &str) -> Option<String> {
+/// # Error
+/// if the login request failed
+pub async fn test_login(
+    host: &str,
+    username: &str,
+    pass: &str,
+    auth: &ProxyAuth,
+) -> Result<Option<String>, QbError> {
     let refined_host = remove_slash(host);
     let form = [
         ("username", username.to_string()),
         ("password", pass.to_string()),
     ];
 
-    let res = request::post(format!("{refined_host}/api/v2/auth/login"))
+    let res = auth
+        .apply(request::post(format!("{refined_host}/api/v2/auth/login")))
         .form(form)
         .send()
-        .await
-        .ok()?;
+        .await?;
 
-    res.get_header(nyquest::header::SET_COOKIE)
+    Ok(res
+        .get_header(nyquest::header::SET_COOKIE)
         .ok()
         .and_then(|mut res| {
             if res.is_empty() {
@@ -210,28 +385,39 @@ pub async fn test_login(host: &str, username: &str, pass: &str) -> Option<String
             } else {
                 Some(res.swap_remove(0))
             }
-        })
+        }))
 }
 
-/// get all torrent infos with CATEGORY
-pub async fn get_torrent_info() -> Result<Vec<TorrentInfo>, QbError> {
-    let host = host()?;
-    QbRequest::get(format!("{host}/api/v2/torrents/info"))
-        .query([("category", CATEGORY)])
-        .send_and_then(async |res| {
-            let torrent_info_list: Vec<TorrentInfo> = res.json().await?;
-            Ok(torrent_info_list)
-        })
-        .await
+/// get all torrent infos with [`category`] of the instance from the synced torrent list, see [`sync`]
+/// # Error
+/// [`QbError::NotSynced`] if the torrent list hasn't been synced yet
+pub fn get_torrent_info(instance: &str) -> Result<Vec<TorrentInfo>, QbError> {
+    if !sync::is_synced(instance) {
+        return Err(QbError::NotSynced);
+    }
+    Ok(sync::torrents_in_category(instance, &category()))
 }
 
-async fn manage_tag(hash: &str, tag: Tag, action: &'static str) -> Result<(), QbError> {
-    let host = host()?;
-    let param = [
-        ("hashes", Cow::from(hash.to_string())),
-        ("tags", Cow::from(tag.as_str())),
-    ];
-    QbRequest::post(format!("{host}/api/v2/torrents/{action}"))
+/// get the torrents of the instance not in [`category`] from the synced torrent list,
+/// which may be adopted as tasks
+/// # Error
+/// [`QbError::NotSynced`] if the torrent list hasn't been synced yet
+pub fn get_foreign_torrents(instance: &str) -> Result<Vec<TorrentSummary>, QbError> {
+    if !sync::is_synced(instance) {
+        return Err(QbError::NotSynced);
+    }
+    Ok(sync::torrents_not_in_category(instance, &category()))
+}
+
+async fn manage_tag(
+    instance: &str,
+    hash: &str,
+    tag: String,
+    action: &'static str,
+) -> Result<(), QbError> {
+    let qb = session(instance)?;
+    let param = [("hashes", hash.to_string()), ("tags", tag)];
+    QbRequest::post(&qb, &format!("/api/v2/torrents/{action}"))
         .form(param)
         .send()
         .await?;
@@ -239,19 +425,65 @@ async fn manage_tag(hash: &str, tag: Tag, action: &'static str) -> Result<(), Qb
 }
 
 /// remove the tag of the corresponding torrent
-pub async fn remove_tag(hash: &str, tag: Tag) -> Result<(), QbError> {
-    manage_tag(hash, tag, "removeTags").await
+pub async fn remove_tag(instance: &str, hash: &str, tag: Tag) -> Result<(), QbError> {
+    manage_tag(instance, hash, tag.name(), "removeTags").await
 }
 
-// /// add tag to the corresponding torrent
-// pub async fn add_tag(hash: &str, tag: Tag) -> Result<(), QbError> {
-//     manage_tag(hash, tag, "addTags").await
-// }
+/// set the category of the torrents, `hashes` separated by `|`,
+/// the category is created first if not exists, an empty category removes it
+pub async fn set_category(instance: &str, hashes: &str, category: &str) -> Result<(), QbError> {
+    let qb = session(instance)?;
+    let categories: HashMap<String, Value> = QbRequest::get(&qb, "/api/v2/torrents/categories")
+        .send_and_then(async |res| Ok::<_, QbError>(res.json().await?))
+        .await?;
+    if !category.is_empty() && !categories.contains_key(category) {
+        QbRequest::post(&qb, "/api/v2/torrents/createCategory")
+            .form([("category", category.to_string())])
+            .send()
+            .await?;
+    }
+    QbRequest::post(&qb, "/api/v2/torrents/setCategory")
+        .form([
+            ("hashes", hashes.to_string()),
+            ("category", category.to_string()),
+        ])
+        .send()
+        .await?;
+    Ok(())
+}
 
+/// Move the torrents of the instance from the category and tags in `old` to the configured ones,
+/// after the category or tag prefix is changed.
+pub async fn migrate_naming(instance: &str, old: &QbConfig) -> Result<(), QbError> {
+    let new_category = category();
+    if old.category != new_category {
+        let hashes = torrent_hashes(instance, &[("category", old.category.clone())]).await?;
+        if !hashes.is_empty() {
+            set_category(instance, &hashes.join("|"), &new_category).await?;
+            info!(
+                "Moved {} torrents of qBittorrent {instance} from category {} to {new_category}",
+                hashes.len(),
+                old.category
+            );
+        }
+    }
+    let new_prefix = &config::value().qb.tag_prefix;
+    if &old.tag_prefix != new_prefix {
+        let tag = Tag::Waited;
+        let old_tag = tag.with_prefix(&old.tag_prefix);
+        let hashes = torrent_hashes(instance, &[("tag", old_tag.clone())]).await?;
+        if !hashes.is_empty() {
+            let hashes = hashes.join("|");
+            manage_tag(instance, &hashes, tag.name(), "addTags").await?;
+            manage_tag(instance, &hashes, old_tag, "removeTags").await?;
+        }
+    }
+    Ok(())
+}
 /// manage torrent task
-async fn manage(hash: &str, action: &'static str) -> Result<(), QbError> {
-    let host = host()?;
-    QbRequest::post(format!("{host}/api/v2/torrents/{action}"))
+async fn manage(instance: &str, hash: &str, action: &'static str) -> Result<(), QbError> {
+    let qb = session(instance)?;
+    QbRequest::post(&qb, &format!("/api/v2/torrents/{action}"))
         .form([("hashes", hash.to_string())])
         .send()
         .await?;
@@ -259,26 +491,47 @@ async fn manage(hash: &str, action: &'static str) -> Result<(), QbError> {
 }
 
 /// start a torrent
-pub async fn start(hash: &str) -> Result<(), QbError> {
-    if version() < 5 {
-        manage(hash, "resume").await
+pub async fn start(instance: &str, hash: &str) -> Result<(), QbError> {
+    if version(instance) < 5 {
+        manage(instance, hash, "resume").await
     } else {
-        manage(hash, "start").await
+        manage(instance, hash, "start").await
     }
 }
 
 /// stop a torrent
-pub async fn stop(hash: &str) -> Result<(), QbError> {
-    if version() < 5 {
-        manage(hash, "pause").await
+pub async fn stop(instance: &str, hash: &str) -> Result<(), QbError> {
+    if version(instance) < 5 {
+        manage(instance, hash, "pause").await
     } else {
-        manage(hash, "stop").await
+        manage(instance, hash, "stop").await
     }
 }
 
+/// move of a torrent in the download queue
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueuePrio {
+    Top,
+    Bottom,
+    Increase,
+    Decrease,
+}
+
+/// change the queue priority of a torrent, which requires torrent queueing enabled in qBittorrent
+pub async fn set_queue_prio(instance: &str, hash: &str, prio: QueuePrio) -> Result<(), QbError> {
+    let action = match prio {
+        QueuePrio::Top => "topPrio",
+        QueuePrio::Bottom => "bottomPrio",
+        QueuePrio::Increase => "increasePrio",
+        QueuePrio::Decrease => "decreasePrio",
+    };
+    manage(instance, hash, action).await
+}
+
 /// delete a torrent
-pub async fn delete(hash: &str, delete_files: bool) -> Result<(), QbError> {
-    let host = host()?;
+pub async fn delete(instance: &str, hash: &str, delete_files: bool) -> Result<(), QbError> {
+    let qb = session(instance)?;
     let param = [
         ("hashes", Cow::from(hash.to_string())),
         (
@@ -286,7 +539,7 @@ pub async fn delete(hash: &str, delete_files: bool) -> Result<(), QbError> {
             Cow::from(if delete_files { "true" } else { "false" }),
         ),
     ];
-    QbRequest::post(format!("{host}/api/v2/torrents/delete"))
+    QbRequest::post(&qb, "/api/v2/torrents/delete")
         .form(param)
         .send()
         .await?;
@@ -296,9 +549,9 @@ pub async fn delete(hash: &str, delete_files: bool) -> Result<(), QbError> {
 /// get the state of a torrent
 /// # Error
 /// [QbError::Cancelled] if the regarding torrent not found in qBittorrent
-pub async fn get_state(hash: &str) -> Result<String, QbError> {
-    let host = host()?;
-    QbRequest::get(format!("{host}/api/v2/torrents/info"))
+pub async fn get_state(instance: &str, hash: &str) -> Result<String, QbError> {
+    let qb = session(instance)?;
+    QbRequest::get(&qb, "/api/v2/torrents/info")
         .query([("hashes", hash)])
         .send_and_then(async |res| {
             let json_array: Vec<Value> = res.json().await?;
@@ -313,20 +566,31 @@ pub async fn get_state(hash: &str) -> Result<String, QbError> {
         .await
 }
 
+/// check that the torrent doesn't exist in any logged-in instance
+/// # Error
+/// [`QbError::NoNewTorrents`] if the torrent exists
 pub async fn torrent_exists(hash: &str) -> Result<(), QbError> {
-    match get_state(hash).await {
-        Ok(_) => Err(QbError::NoNewTorrents),
-        Err(QbError::Cancelled) => Ok(()),
-        Err(e) => Err(e),
+    for instance in instances().iter().filter(|i| is_logined(i)) {
+        match get_state(instance, hash).await {
+            Ok(_) => return Err(QbError::NoNewTorrents),
+            Err(QbError::Cancelled) => {}
+            Err(e) => return Err(e),
+        }
     }
+    Ok(())
 }
 /// set the download priority of a torrent
 /// # Arguments
 /// * `hash` - The hash of the torrent.
 /// * `priority` - 1 for download, 0 for not download.
 /// * `index_list` - the index list of files need to set.
-pub async fn set_prio(hash: &str, priority: u8, index_list: &[usize]) -> Result<(), QbError> {
-    let host = host()?;
+pub async fn set_prio(
+    instance: &str,
+    hash: &str,
+    priority: u8,
+    index_list: &[usize],
+) -> Result<(), QbError> {
+    let qb = session(instance)?;
     let id = index_list
         .iter()
         .map(|i| i.to_string())
@@ -338,7 +602,7 @@ pub async fn set_prio(hash: &str, priority: u8, index_list: &[usize]) -> Result<
         ("priority", priority.to_string()),
         ("id", id.to_string()),
     ];
-    QbRequest::post(format!("{host}/api/v2/torrents/filePrio"))
+    QbRequest::post(&qb, "/api/v2/torrents/filePrio")
         .form(param)
         .send()
         .await?;
@@ -346,17 +610,24 @@ pub async fn set_prio(hash: &str, priority: u8, index_list: &[usize]) -> Result<
 }
 
 /// set the task not download, usually used when a new part of task is added
-pub async fn set_not_download(hash: &str, file_num: usize) -> Result<(), QbError> {
-    set_prio(hash, 0, (0..file_num).collect::<Vec<usize>>().as_slice()).await
+pub async fn set_not_download(instance: &str, hash: &str, file_num: usize) -> Result<(), QbError> {
+    set_prio(
+        instance,
+        hash,
+        0,
+        (0..file_num).collect::<Vec<usize>>().as_slice(),
+    )
+    .await
 }
 
 /// set the share limit of a torrent
 pub async fn set_share_limit(
+    instance: &str,
     hash: &str,
     ratio_limit: f64,
     seeding_time_limit: i32,
 ) -> Result<(), QbError> {
-    let host = host()?;
+    let qb = session(instance)?;
     let param = [
         ("hashes", Cow::from(hash.to_string())),
         ("ratioLimit", Cow::from(ratio_limit.to_string())),
@@ -366,7 +637,7 @@ pub async fn set_share_limit(
         ),
         ("inactiveSeedingTimeLimit", Cow::from("-2")),
     ];
-    QbRequest::post(format!("{host}/api/v2/torrents/setShareLimits"))
+    QbRequest::post(&qb, "/api/v2/torrents/setShareLimits")
         .form(param)
         .send()
         .await?;
@@ -376,9 +647,9 @@ pub async fn set_share_limit(
 /// export .torrent file to a specified path
 /// # Precondition
 /// - qbittorrent have fetched meta data
-pub async fn export(hash: &str, path: &Path) -> Result<(), QbError> {
-    let host = host()?;
-    QbRequest::post(format!("{host}/api/v2/torrents/export"))
+pub async fn export(instance: &str, hash: &str, path: &Path) -> Result<(), QbError> {
+    let qb = session(instance)?;
+    QbRequest::post(&qb, "/api/v2/torrents/export")
         .form([("hash", hash.to_string())])
         .send_and_then(async |res| {
             let data = res.bytes().await?;
@@ -392,10 +663,15 @@ pub async fn export(hash: &str, path: &Path) -> Result<(), QbError> {
 }
 
 /// get the hash list of torrents with a specific tag
-pub async fn get_tag_torrent_list(tag: Tag) -> Result<Vec<String>, QbError> {
-    let host = host()?;
-    QbRequest::get(format!("{host}/api/v2/torrents/info"))
-        .query([("category", CATEGORY), ("tag", tag.as_str())])
+pub async fn get_tag_torrent_list(instance: &str, tag: Tag) -> Result<Vec<String>, QbError> {
+    torrent_hashes(instance, &[("category", category()), ("tag", tag.name())]).await
+}
+
+/// get the hash list of torrents matching the `torrents/info` filter
+async fn torrent_hashes(instance: &str, filter: &[(&str, String)]) -> Result<Vec<String>, QbError> {
+    let qb = session(instance)?;
+    QbRequest::get(&qb, "/api/v2/torrents/info")
+        .query(filter)
         .send_and_then(async |res| {
             let json_array: Vec<Value> = res.json().await?;
             let hash_list = json_array
@@ -407,19 +683,19 @@ pub async fn get_tag_torrent_list(tag: Tag) -> Result<Vec<String>, QbError> {
         .await
 }
 
-/// add a torrent to qBittorrent by URL, with [`CATEGORY`] and [Tag::Waited]
+/// add a torrent to qBittorrent by URL, with [`category`] and [Tag::Waited]
 /// if url is a magnet link, means hash is known, else add [`Tag::New`] and wait for [`get_hash`] to fetch the meta data
-pub async fn add_by_url(url: &str, save_path: &str) -> Result<(), QbError> {
-    let host = host()?;
+pub async fn add_by_url(instance: &str, url: &str, save_path: &str) -> Result<(), QbError> {
+    let qb = session(instance)?;
     let param = HashMap::from([
         ("urls", Cow::from(url.to_string())),
         ("savepath", Cow::from(save_path.to_string())),
-        ("category", Cow::from(CATEGORY)),
+        ("category", Cow::from(category())),
         ("stopCondition", Cow::from("MetadataReceived")),
-        ("tags", Cow::from(Tag::Waited.as_str())),
+        ("tags", Cow::from(Tag::Waited.name())),
     ]);
 
-    QbRequest::post(format!("{host}/api/v2/torrents/add"))
+    QbRequest::post(&qb, "/api/v2/torrents/add")
         .form(param)
         .send()
         .await?;
@@ -429,21 +705,22 @@ pub async fn add_by_url(url: &str, save_path: &str) -> Result<(), QbError> {
 /// add a torrent to qBittorrent by file path,
 /// aiming to fast recover from cached .torrrent file
 pub async fn add_by_file(
+    instance: &str,
     torrent_path: &Path,
     save_path: &str,
     seeding_time_limit: i32,
     ratio_limit: f64,
 ) -> Result<(), QbError> {
-    let host = host()?;
+    let qb = session(instance)?;
     let multipart = MultipartBuilder::new()
         .path("torrents", torrent_path.to_path_buf())
-        .text("category", CATEGORY)
+        .text("category", category())
         .text("savepath", save_path.to_string())
         .text("seedingTimeLimit", seeding_time_limit.to_string())
         .text("ratioLimit", ratio_limit.to_string())
         .text("stopped", "true");
 
-    QbRequest::post(format!("{host}/api/v2/torrents/add"))
+    QbRequest::post(&qb, "/api/v2/torrents/add")
         .multipart(multipart)
         .send()
         .await?;
@@ -452,49 +729,44 @@ pub async fn add_by_file(
 
 /// add a torrent to qBittorrent by bytes
 pub async fn add_by_bytes(
+    instance: &str,
     file_name: &str,
     save_path: &str,
     data: Cow<'static, [u8]>,
 ) -> Result<(), QbError> {
-    let host = host()?;
+    let qb = session(instance)?;
 
     let form = MultipartBuilder::new()
         .bytes("torrents", data, file_name.to_string())
         .text("savepath", save_path.to_string())
-        .text("category", CATEGORY)
+        .text("category", category())
         .text("stopped", "true")
-        .text("tags", Tag::Waited.as_str());
-    QbRequest::post(format!("{host}/api/v2/torrents/add"))
+        .text("tags", Tag::Waited.name());
+    QbRequest::post(&qb, "/api/v2/torrents/add")
         .multipart(form)
         .send()
         .await?;
     Ok(())
 }
 
-/// Try to parse the hash from a url first, usually used to parse magnet link
-/// If failed, it means the url is probably an http link, e.g. "http://example.com/file.torrent", which is not supported
-/// # Errors
-/// [QbError::ParseMagnet]
-pub fn try_parse_hash(url: &str) -> Result<String, QbError> {
-    if let Some(mut hash) = url.strip_prefix("magnet:?xt=urn:btih:") {
-        if let Some(end) = hash.find('&') {
-            hash = &hash[..end];
-        }
-        let mut hash = hash.to_string();
-        // Base32
-        if hash.len() == 32 {
-            if let Some(hash_raw) = base32::decode(Alphabet::Rfc4648 { padding: false }, &hash) {
-                hash = hash_raw
-                    .iter()
-                    .map(|b| format!("{:02x}", b))
-                    .collect::<String>();
-            } else {
-                // parse base32 failed
-                return Err(QbError::ParseMagnet);
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn supported_versions() {
+        // (version, major)
+        let cases = [
+            ("v5.0.1", Some(5)),
+            ("v4.6.7\n", Some(4)),
+            ("v4.4.0", Some(4)),
+            ("v4.3.9", None),
+            ("v4.1.0", None),
+            ("v3.3.16", None),
+            ("5.0.1", None),
+        ];
+        for (version, major) in cases {
+            assert_eq!(parse_version(version).ok(), major, "{version}");
         }
-        Ok(hash)
-    } else {
-        Err(QbError::ParseMagnet)
     }
 }
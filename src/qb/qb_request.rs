@@ -1,23 +1,42 @@
-//! qb request, which will attach cookie
+//! qb request to an instance, which will attach cookie
 //! should never be called without login success
 
+use std::sync::Arc;
+
 use nyquest::header;
 
 use crate::{
-    qb::{self, QB, QbError},
-    request::{self, MyRequest, MyRequestBuilder, RequestError, Res},
+    qb::{self, QB, Qb, QbError},
+    request::{self, MyRequestBuilder, MyRequestBuilderImpl, RequestError, Res},
 };
 
 pub(super) struct QbRequest;
 
 pub(super) struct QbRequestBuilder {
-    inner: crate::request::MyRequestBuilderImpl,
+    instance: Arc<str>,
+    inner: MyRequestBuilderImpl,
+}
+
+/// current cookie of the instance, none if authentication is bypassed
+fn current_cookie(instance: &str) -> Result<Option<String>, QbError> {
+    QB.load()
+        .get(instance)
+        .map(|qb| qb.cookie.clone())
+        .ok_or(QbError::NotLogin)
+}
+
+fn with_cookie(builder: MyRequestBuilderImpl, cookie: Option<String>) -> MyRequestBuilderImpl {
+    match cookie {
+        Some(cookie) => builder.header(header::COOKIE, cookie),
+        None => builder,
+    }
 }
 
 impl MyRequestBuilder for QbRequestBuilder {
     type Err = QbError;
     fn basic_auth(self, username: &str, password: &str) -> Self {
         Self {
+            instance: self.instance,
             inner: self.inner.basic_auth(username, password),
         }
     }
@@ -29,12 +48,14 @@ impl MyRequestBuilder for QbRequestBuilder {
         V: Into<std::borrow::Cow<'static, str>>,
     {
         Self {
+            instance: self.instance,
             inner: self.inner.form(fields),
         }
     }
 
     fn json<T: serde::Serialize>(self, value: T) -> Self {
         Self {
+            instance: self.instance,
             inner: self.inner.json(value),
         }
     }
@@ -45,47 +66,46 @@ impl MyRequestBuilder for QbRequestBuilder {
         value: impl Into<std::borrow::Cow<'static, str>>,
     ) -> Self {
         Self {
+            instance: self.instance,
             inner: self.inner.header(name, value),
         }
     }
 
     fn multipart(self, parts: crate::request::multipart::MultipartBuilder) -> Self {
         Self {
+            instance: self.instance,
             inner: self.inner.multipart(parts),
         }
     }
 
     fn query<T: serde::Serialize>(self, input: T) -> Self {
         Self {
+            instance: self.instance,
             inner: self.inner.query(input),
         }
     }
 
     async fn send(self) -> Result<Res, QbError> {
         let sender = self.inner.clone();
-        let cookie = QB.load().cookie.clone().unwrap();
+        let cookie = current_cookie(&self.instance)?;
 
-        match self.inner.header(header::COOKIE, cookie).send().await {
+        match with_cookie(self.inner, cookie).send().await {
             Err(e) => {
                 let err = e;
                 if let RequestError::Response(code) = err
                     && code == 403
                 {
-                    qb::login().await;
-                    let cookie = QB.load().cookie.clone().unwrap();
-                    sender
-                        .header(header::COOKIE, cookie)
-                        .send()
-                        .await
-                        .map_err(|e| {
-                            if let RequestError::Response(code) = e
-                                && code == 403
-                            {
-                                QbError::NotLogin
-                            } else {
-                                QbError::from(e)
-                            }
-                        })
+                    qb::login_instance(&self.instance).await;
+                    let cookie = current_cookie(&self.instance)?;
+                    with_cookie(sender, cookie).send().await.map_err(|e| {
+                        if let RequestError::Response(code) = e
+                            && code == 403
+                        {
+                            QbError::NotLogin
+                        } else {
+                            QbError::from(e)
+                        }
+                    })
                 } else {
                     Err(QbError::from(err))
                 }
@@ -94,17 +114,20 @@ impl MyRequestBuilder for QbRequestBuilder {
         }
     }
 }
-impl MyRequest for QbRequest {
-    type RequestBuilder = QbRequestBuilder;
-    fn get(url: impl Into<std::borrow::Cow<'static, str>>) -> Self::RequestBuilder {
-        Self::RequestBuilder {
-            inner: request::get(url),
+impl QbRequest {
+    /// GET `path` of the instance, e.g. "/api/v2/torrents/info"
+    pub fn get(qb: &Qb, path: &str) -> QbRequestBuilder {
+        QbRequestBuilder {
+            instance: qb.name.clone(),
+            inner: qb.auth.apply(request::get(format!("{}{path}", qb.host))),
         }
     }
 
-    fn post(url: impl Into<std::borrow::Cow<'static, str>>) -> Self::RequestBuilder {
-        Self::RequestBuilder {
-            inner: request::post(url),
+    /// POST `path` of the instance
+    pub fn post(qb: &Qb, path: &str) -> QbRequestBuilder {
+        QbRequestBuilder {
+            instance: qb.name.clone(),
+            inner: qb.auth.apply(request::post(format!("{}{path}", qb.host))),
         }
     }
 }
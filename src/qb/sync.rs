@@ -0,0 +1,299 @@
+//! Shared poller of qBittorrent `sync/maindata`.
+//! It keeps a local copy of all torrents of every instance, updated incrementally by `rid`,
+//! which feeds both the task handler and metadata fetchers.
+use std::{
+    collections::HashMap,
+    sync::{LazyLock, RwLock},
+    time::Duration,
+};
+
+use futures_util::{FutureExt, future::join_all, select};
+use log::{debug, warn};
+use serde::{Deserialize, de::DeserializeOwned};
+use serde_json::{Map, Value};
+use tokio::{
+    sync::{broadcast, watch},
+    time::sleep,
+};
+
+use crate::{
+    bencode, config,
+    errors::format_error_chain,
+    qb::{
+        QbError, TorrentInfo, TorrentSummary, instances, is_logined, qb_request::QbRequest, session,
+    },
+    request::MyRequestBuilder,
+};
+
+type TorrentMap = HashMap<String, Map<String, Value>>;
+
+#[derive(Debug, Default)]
+struct SyncState {
+    rid: i64,
+    /// whether a full update has been received since the last reset
+    synced: bool,
+    /// count of the full updates received
+    full_updates: u64,
+    torrents: TorrentMap,
+    /// id of the torrent by its v1 hash and truncated v2 hash, see [`torrent`]
+    aliases: HashMap<String, String>,
+    /// free space of the default save path in bytes
+    free_space: Option<u64>,
+}
+
+/// sync state of every instance, by name
+static SYNC_STATE: LazyLock<RwLock<HashMap<String, SyncState>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
+/// bumped after every applied update
+static UPDATED: LazyLock<watch::Sender<u64>> = LazyLock::new(|| watch::channel(0).0);
+
+#[derive(Debug, Deserialize)]
+struct MainData {
+    rid: i64,
+    #[serde(default)]
+    full_update: bool,
+    /// changed torrents, with only changed fields if not full update
+    #[serde(default)]
+    torrents: TorrentMap,
+    #[serde(default)]
+    torrents_removed: Vec<String>,
+    /// changed fields only if not full update
+    server_state: Option<ServerState>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ServerState {
+    free_space_on_disk: Option<u64>,
+}
+
+/// poll every qBittorrent instance every `qb.sync_interval` seconds until shutdown
+pub async fn run(mut shutdown_rx: broadcast::Receiver<()>) {
+    loop {
+        let instances = instances();
+        SYNC_STATE
+            .write()
+            .unwrap()
+            .retain(|name, _| instances.contains(name));
+        join_all(instances.iter().map(|instance| sync(instance))).await;
+
+        let interval = sync_interval();
+        select! {
+            _ = shutdown_rx.recv().fuse() => break,
+            _ = sleep(interval).fuse() => {}
+        }
+    }
+    debug!("qBittorrent sync stopped");
+}
+
+/// the configured poll interval
+pub fn sync_interval() -> Duration {
+    Duration::from_secs(config::value().qb.sync_interval.max(1))
+}
+
+async fn sync(instance: &str) {
+    if !is_logined(instance) {
+        reset(instance);
+    } else if let Err(e) = poll(instance).await {
+        warn!(
+            "Failed to sync qBittorrent {instance}\n{}",
+            format_error_chain(e)
+        );
+        reset(instance);
+    }
+}
+
+async fn poll(instance: &str) -> Result<(), QbError> {
+    let qb = session(instance)?;
+    let rid = state(instance, |state| state.rid).unwrap_or(0);
+    let data: MainData = QbRequest::get(&qb, "/api/v2/sync/maindata")
+        .query([("rid", rid)])
+        .send_and_then(async |res| Ok::<_, QbError>(res.json().await?))
+        .await?;
+    apply(instance, data);
+    UPDATED.send_modify(|v| *v += 1);
+    Ok(())
+}
+
+fn apply(instance: &str, data: MainData) {
+    let mut states = SYNC_STATE.write().unwrap();
+    let state = states.entry(instance.to_string()).or_default();
+    if data.full_update {
+        state.torrents.clear();
+        state.aliases.clear();
+        state.synced = true;
+        state.full_updates += 1;
+    }
+    for (hash, fields) in data.torrents {
+        for (key, truncate) in [("infohash_v1", false), ("infohash_v2", true)] {
+            if let Some(alias) = fields.get(key).and_then(Value::as_str)
+                && !alias.is_empty()
+            {
+                let alias = if truncate {
+                    bencode::truncate_v2(alias)
+                } else {
+                    alias
+                };
+                state.aliases.insert(alias.to_string(), hash.clone());
+            }
+        }
+        state.torrents.entry(hash).or_default().extend(fields);
+    }
+    if !data.torrents_removed.is_empty() {
+        for hash in &data.torrents_removed {
+            state.torrents.remove(hash);
+        }
+        state
+            .aliases
+            .retain(|_, id| !data.torrents_removed.contains(id));
+    }
+    if let Some(free_space) = data.server_state.and_then(|s| s.free_space_on_disk) {
+        state.free_space = Some(free_space);
+    }
+    state.rid = data.rid;
+}
+
+/// drop the session, the next poll will request a full update
+fn reset(instance: &str) {
+    if let Some(state) = SYNC_STATE.write().unwrap().get_mut(instance) {
+        state.rid = 0;
+        state.synced = false;
+    }
+}
+
+/// request a full update by the next poll, keeping the current torrent list until then
+pub fn request_full_update(instance: &str) {
+    if let Some(state) = SYNC_STATE.write().unwrap().get_mut(instance) {
+        state.rid = 0;
+    }
+}
+
+/// count of the full updates of the instance, see [`request_full_update`]
+pub fn full_updates(instance: &str) -> u64 {
+    state(instance, |state| state.full_updates).unwrap_or(0)
+}
+
+/// read the sync state of the instance
+fn state<T>(instance: &str, f: impl FnOnce(&SyncState) -> T) -> Option<T> {
+    SYNC_STATE.read().unwrap().get(instance).map(f)
+}
+
+/// whether the local torrent list of the instance is complete
+pub fn is_synced(instance: &str) -> bool {
+    state(instance, |state| state.synced).unwrap_or(false)
+}
+
+/// free space of the instance's default save path, if reported
+pub fn free_space(instance: &str) -> Option<u64> {
+    state(instance, |state| state.free_space).flatten()
+}
+
+/// subscribe to updates, see [`wait_update`]
+pub fn subscribe() -> watch::Receiver<u64> {
+    UPDATED.subscribe()
+}
+
+/// wait for the next update, or at most `timeout`
+pub async fn wait_update(rx: &mut watch::Receiver<u64>, timeout: Duration) {
+    let _ = tokio::time::timeout(timeout, rx.changed()).await;
+}
+
+fn to_info<T: DeserializeOwned>(hash: &str, fields: &Map<String, Value>) -> Option<T> {
+    let mut fields = fields.clone();
+    fields.insert("hash".into(), Value::String(hash.to_string()));
+    serde_json::from_value(Value::Object(fields)).ok()
+}
+
+/// get the torrent infos in the category from the local torrent list of the instance
+pub fn torrents_in_category(instance: &str, category: &str) -> Vec<TorrentInfo> {
+    state(instance, |state| {
+        state
+            .torrents
+            .iter()
+            .filter(|(_, fields)| fields.get("category").and_then(Value::as_str) == Some(category))
+            .filter_map(|(hash, fields)| to_info(hash, fields))
+            .collect()
+    })
+    .unwrap_or_default()
+}
+
+/// get the torrents not in the category from the local torrent list of the instance
+pub fn torrents_not_in_category(instance: &str, category: &str) -> Vec<TorrentSummary> {
+    state(instance, |state| {
+        state
+            .torrents
+            .iter()
+            .filter(|(_, fields)| fields.get("category").and_then(Value::as_str) != Some(category))
+            .filter_map(|(hash, fields)| to_info(hash, fields))
+            .collect()
+    })
+    .unwrap_or_default()
+}
+
+/// get the torrent summary from the local torrent list of the instance
+pub fn torrent_summary(instance: &str, hash: &str) -> Option<TorrentSummary> {
+    state(instance, |state| to_info(hash, state.torrents.get(hash)?)).flatten()
+}
+
+/// Get the torrent info from the local torrent list of the instance,
+/// `hash` may be its id, the v1 hash or the truncated v2 hash.
+/// The id of a hybrid torrent added by v2 magnet may change to the v1 hash once metadata arrives.
+pub fn torrent(instance: &str, hash: &str) -> Option<TorrentInfo> {
+    state(instance, |state| {
+        let id = if state.torrents.contains_key(hash) {
+            hash
+        } else {
+            state.aliases.get(hash)?
+        };
+        to_info(id, state.torrents.get(id)?)
+    })
+    .flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V1: &str = "0123456789abcdef0123456789abcdef01234567";
+    const V2: &str = "89abcdef0123456789abcdef0123456789abcdef0123456789abcdef01234567";
+
+    fn main_data(json: &str) -> MainData {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn torrent_by_alias() {
+        let instance = "sync-alias";
+        let truncated = bencode::truncate_v2(V2);
+        apply(
+            instance,
+            main_data(&format!(
+                r#"{{ "rid": 1, "full_update": true, "torrents": {{ "{truncated}": {{
+                    "state": "metaDL", "progress": 0.0, "infohash_v1": "", "infohash_v2": "{V2}"
+                }} }} }}"#
+            )),
+        );
+        assert_eq!(torrent(instance, truncated).unwrap().hash, truncated);
+
+        // the id changes to the v1 hash once metadata arrives
+        apply(
+            instance,
+            main_data(&format!(
+                r#"{{ "rid": 2, "torrents": {{ "{V1}": {{
+                    "state": "stoppedDL", "progress": 0.0, "infohash_v1": "{V1}", "infohash_v2": "{V2}"
+                }} }}, "torrents_removed": ["{truncated}"] }}"#
+            )),
+        );
+        let info = torrent(instance, truncated).unwrap();
+        assert_eq!((info.hash.as_str(), info.state.as_str()), (V1, "stoppedDL"));
+        assert_eq!(torrent(instance, V1).unwrap().hash, V1);
+
+        apply(
+            instance,
+            main_data(&format!(r#"{{ "rid": 3, "torrents_removed": ["{V1}"] }}"#)),
+        );
+        assert!(torrent(instance, V1).is_none());
+        assert!(torrent(instance, truncated).is_none());
+        assert_eq!(full_updates(instance), 1);
+    }
+}
@@ -1,6 +1,6 @@
 //! http request wrapper
 //! # Usage
-//! ```
+//! ```text
 //! request::post("http://example.com")
 //!     .basic_auth("username", "password") // to set basic auth
 //!     .query([("key", "value")]) // to set query parameters
@@ -14,7 +14,7 @@
 //!     }).await;
 //! ```
 //! use multipart form
-//! ```
+//! ```text
 //! // create a file part from file path
 //! let file_part = FilePart::path("path/to/file.txt").await?; // async read file
 //! // or from bytes
@@ -63,10 +63,16 @@ macro_rules! define_routes {
 define_routes! {
     "/api/config" => api::config_api::ConfigAPI,
     "/api/task" => api::task_api::TaskAPI,
+    "/api/task/archive" => api::archive_api::ArchiveAPI,
+    "/api/task/bulk" => api::bulk_api::BulkAPI,
     "/api/torrent" => api::torrent_api::TorrentAPI,
+    "/api/torrent/batch" => api::batch_api::BatchAPI,
+    "/api/import" => api::import_api::ImportAPI,
     "/api/login" => api::login_api::LoginAPI,
     "/api/test" => api::test_api::TestAPI,
     "/api/version" => api::version_api::VersionAPI,
+    "/api/health" => api::health_api::HealthAPI,
+    "/api/ready" => api::health_api::ReadyAPI,
 }
 
 pub async fn run(
@@ -273,4 +279,19 @@ impl<T: Serialize> ResultResponse<T> {
             .body(full(json))
             .unwrap()
     }
+
+    /// Create a response with data and a custom status code
+    fn data_with_code(data: T, code: StatusCode) -> Response<BoxBody> {
+        let result = Self {
+            message: code.canonical_reason().map(Cow::from),
+            data: Some(data),
+            code: code.as_u16(),
+        };
+        let json = serde_json::to_string(&result).unwrap();
+        Response::builder()
+            .status(code)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(full(json))
+            .unwrap()
+    }
 }
@@ -1,7 +1,12 @@
 //! This module provides tools when building the server API.
 //! api route defined at [`super`]
+pub(super) mod archive_api;
 pub(super) mod asset_api;
+pub(super) mod batch_api;
+pub(super) mod bulk_api;
 pub(super) mod config_api;
+pub(super) mod health_api;
+pub(super) mod import_api;
 pub(super) mod login_api;
 pub(super) mod task_api;
 pub(super) mod test_api;
@@ -39,7 +44,7 @@ pub trait Action: Send + Sync {
 
 /// Extracts a parameter from the request URL query
 /// # Example
-/// ```
+/// ```text
 /// let params = get_param_map(&req).unwrap();
 /// let value: u32 = get_option_param(&params, "key").await;
 /// ```
@@ -87,7 +92,7 @@ fn url_decode(query: &str) -> HashMap<String, String> {
 
 /// Zero-copy get json body, always cooperate with [from_json]
 /// # Example
-/// ```
+/// ```text
 /// let bytes = get_json_body(req).await?;
 /// let value: MyStruct = from_json(&bytes)?;
 /// ```
@@ -0,0 +1,92 @@
+//! end point at "/api/task/archive", tasks moved out of the task list once done
+//!
+//! GET: list archived tasks newest first, response with [`ArchiveRes`]
+//! POST: rerun an archived task from the cached torrent file, see [`rerun_task`]
+use crate::{
+    errors::TargetContextedResult,
+    qb,
+    server::{
+        ResultResponse,
+        api::{get_option_param, get_param_map, get_required_param},
+        error::ServerError,
+    },
+    task::archive::{self, ArchivedTask},
+};
+
+use hyper::{Method, Response, StatusCode};
+use serde::Serialize;
+
+use super::{
+    Action, BoxBody, Req, ServerResult,
+    task_api::{get_parts_param, rerun_task},
+};
+
+const DEFAULT_PAGE_SIZE: usize = 20;
+const MAX_PAGE_SIZE: usize = 100;
+
+#[derive(Debug, Default)]
+pub struct ArchiveAPI;
+
+impl Action for ArchiveAPI {
+    async fn execute(&self, req: Req) -> ServerResult<Response<BoxBody>> {
+        match *req.method() {
+            Method::GET => get(req).await,
+            Method::POST => post(req).await,
+            _ => Ok(ResultResponse::error_with_code(
+                StatusCode::METHOD_NOT_ALLOWED,
+            )),
+        }
+    }
+}
+
+/// # query parameters
+/// - search (optional): part of the name, or the hash
+/// - page (optional): 0-based, 0 by default
+/// - page_size (optional): 20 by default, at most 100
+async fn get(req: Req) -> ServerResult<Response<BoxBody>> {
+    if !qb::any_logined() {
+        return Ok(ResultResponse::error_msg("Qbittorrent is not logged in"));
+    }
+    let params = get_param_map(&req).unwrap_or_default();
+    let search = get_option_param::<String>(&params, "search").unwrap_or_default();
+    let page = get_option_param::<usize>(&params, "page").unwrap_or(0);
+    let page_size = get_option_param::<usize>(&params, "page_size")
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let (total, tasks) = archive::list(search.trim(), page.saturating_mul(page_size), page_size)
+        .await
+        .convert_then_add_context("Failed to list archived tasks")?;
+    Ok(ResultResponse::success_data(ArchiveRes {
+        total,
+        page,
+        page_size,
+        tasks,
+    }))
+}
+
+/// # query parameters
+/// - hash (required)
+/// - parts (optional): comma-separated 0-based part indices, all parts if not set
+async fn post(req: Req) -> ServerResult<Response<BoxBody>> {
+    if !qb::any_logined() {
+        return Ok(ResultResponse::error_msg("Qbittorrent is not logged in"));
+    }
+    let (hash, parts) = {
+        let params = get_param_map(&req).ok_or(ServerError::MissingParams("hash"))?;
+        (
+            get_required_param::<String>(&params, "hash")?,
+            get_parts_param(&params)?,
+        )
+    };
+    rerun_task(&hash, parts).await
+}
+
+/// a page of archived tasks
+#[derive(Debug, Serialize)]
+pub struct ArchiveRes {
+    /// count of all matched tasks
+    pub total: usize,
+    pub page: usize,
+    pub page_size: usize,
+    pub tasks: Vec<ArchivedTask>,
+}
@@ -0,0 +1,251 @@
+//! end point at "/api/torrent/batch", add many torrents in one request
+//!
+//! POST: add torrents concurrently to the same instance, respond a [`BatchItemRes`] per item,
+//! duplicate files and links are added once.
+//! If content-type is multipart/form-data, every `torrent` field is a file,
+//! and the optional `urls` field is a newline-separated list of links.
+//! If application/json, request body is [`BatchReq`].
+use std::collections::HashSet;
+
+use crate::{
+    config::strip_slash,
+    errors::format_error_chain,
+    magnet, qb,
+    server::{
+        ResultResponse,
+        api::{ReqExt, from_json, get_json_body},
+        error::ServerError,
+    },
+    task::{self, error::TaskError},
+};
+
+use futures_util::{StreamExt, stream};
+use hyper::{Method, Response, StatusCode, body::Bytes};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    Action, BoxBody, Req, ServerResult,
+    torrent_api::{
+        FileParam, default_save_path, get_add_by_file_param, get_torrent_name_from_hash,
+    },
+};
+
+/// maximum number of torrents being added at the same time
+const MAX_CONCURRENT_ADDS: usize = 4;
+
+#[derive(Debug, Default)]
+pub struct BatchAPI;
+
+impl Action for BatchAPI {
+    async fn execute(&self, req: Req) -> ServerResult<Response<BoxBody>> {
+        if !qb::any_logined() {
+            return Ok(ResultResponse::error_msg("Qbittorrent is not logged in"));
+        }
+        match *req.method() {
+            Method::POST => post(req).await,
+            _ => Ok(ResultResponse::error_with_code(
+                StatusCode::METHOD_NOT_ALLOWED,
+            )),
+        }
+    }
+}
+
+async fn post(req: Req) -> ServerResult<Response<BoxBody>> {
+    let BatchParam {
+        files,
+        urls,
+        save_path,
+        instance,
+    } = if req.is_multipart() {
+        let FileParam {
+            files,
+            urls,
+            save_path,
+            instance,
+        } = get_add_by_file_param(req).await?;
+        BatchParam {
+            files,
+            urls: split_urls(&urls),
+            save_path,
+            instance,
+        }
+    } else {
+        get_json_param(req).await?
+    };
+    let (files, urls) = dedupe(files, urls);
+    if files.is_empty() && urls.is_empty() {
+        return Err(ServerError::MissingParams("torrent"));
+    }
+    let instance = match qb::place(instance.as_deref()) {
+        Ok(instance) => instance,
+        Err(_) => {
+            return Ok(ResultResponse::error_msg(
+                "No qbittorrent instance available",
+            ));
+        }
+    };
+    let save_path = match save_path {
+        Some(save_path) => save_path,
+        None => default_save_path(&instance)?,
+    };
+
+    let items = files
+        .into_iter()
+        .map(|(file_name, data)| (Some(Vec::from(data)), file_name))
+        .chain(urls.into_iter().map(|url| (None, url)));
+    let res: Vec<_> = stream::iter(items)
+        .map(|(file, source)| add_one(&instance, file, source, &save_path))
+        .buffered(MAX_CONCURRENT_ADDS)
+        .collect()
+        .await;
+    Ok(ResultResponse::success_data(res))
+}
+
+/// drop the files of the same content and the same links, keeping the first ones
+fn dedupe(files: Vec<(String, Bytes)>, urls: Vec<String>) -> (Vec<(String, Bytes)>, Vec<String>) {
+    let mut seen_files = HashSet::new();
+    let files = files
+        .into_iter()
+        .filter(|(_, data)| seen_files.insert(data.clone()))
+        .collect();
+    let mut seen_urls = HashSet::new();
+    let urls = urls
+        .into_iter()
+        .filter(|url| seen_urls.insert(url.clone()))
+        .collect();
+    (files, urls)
+}
+
+/// add a single torrent of the batch, never fails as the error is put into the result
+async fn add_one(
+    instance: &str,
+    file: Option<Vec<u8>>,
+    source: String,
+    save_path: &str,
+) -> BatchItemRes {
+    let is_downloaded = file.is_some() || task::is_http_url(&source);
+    let result = match task::add_torrent(instance, file, &source, save_path).await {
+        Ok(hash) if is_downloaded => torrent_name(&hash).await.map(|name| (hash, Some(name))),
+        Ok(hash) => {
+            let name = magnet::parse(&source).ok().and_then(|m| m.name);
+            Ok((hash, name))
+        }
+        Err(e) => Err(describe(e)),
+    };
+    let (hash, torrent_name, error) = match result {
+        Ok((hash, name)) => (Some(hash), name, None),
+        Err(e) => (None, None, Some(e)),
+    };
+    BatchItemRes {
+        source,
+        hash,
+        torrent_name,
+        save_path: save_path.to_string(),
+        fetching: error.is_none() && !is_downloaded,
+        error,
+    }
+}
+
+/// name of the added torrent, a single-file torrent is removed
+async fn torrent_name(hash: &str) -> Result<String, String> {
+    get_torrent_name_from_hash(hash).await.map_err(|e| match e {
+        ServerError::Internal(msg) => msg.into_owned(),
+        e => format_error_chain(e),
+    })
+}
+
+fn describe(e: TaskError) -> String {
+    match e {
+        TaskError::TorrentUrl(msg) => msg.into_owned(),
+        TaskError::Magnet(e) => e.to_string(),
+        e => format_error_chain(e),
+    }
+}
+
+/// parameters of adding torrents in batch
+struct BatchParam {
+    /// file name and content
+    files: Vec<(String, Bytes)>,
+    urls: Vec<String>,
+    save_path: Option<String>,
+    /// the chosen qBittorrent instance, placed automatically if not set
+    instance: Option<String>,
+}
+
+/// split the newline-separated links, skipping blank lines
+fn split_urls(urls: &str) -> Vec<String> {
+    urls.lines()
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+async fn get_json_param(req: Req) -> ServerResult<BatchParam> {
+    let body = get_json_body(req).await?;
+    let batch_req: BatchReq = from_json(&body)?;
+    Ok(BatchParam {
+        files: Vec::new(),
+        urls: split_urls(&batch_req.urls),
+        save_path: Some(batch_req.save_path).filter(|path| !path.is_empty()),
+        instance: batch_req.instance.map(String::from),
+    })
+}
+
+/// add torrents by urls
+#[derive(Debug, Deserialize)]
+pub struct BatchReq<'a> {
+    /// newline-separated links
+    pub urls: String,
+    #[serde(default, deserialize_with = "strip_slash")]
+    pub save_path: String,
+    /// name of the qBittorrent instance to add to
+    #[serde(borrow)]
+    pub instance: Option<&'a str>,
+}
+
+/// result of a single torrent in the batch, either `hash` or `error` is set
+#[derive(Debug, Serialize)]
+pub struct BatchItemRes {
+    /// file name or link
+    pub source: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// torrent name, or display name of the magnet link before metadata arrives
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub torrent_name: Option<String>,
+    /// save path should be nomalized before serialized
+    pub save_path: String,
+    /// whether metadata is still being fetched, see PUT "/api/torrent"
+    pub fetching: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_urls_skips_blank_lines() {
+        let urls = "magnet:?xt=urn:btih:a\n\n  https://example.com/b.torrent  \r\n\t\n";
+        assert_eq!(
+            split_urls(urls),
+            ["magnet:?xt=urn:btih:a", "https://example.com/b.torrent"]
+        );
+    }
+
+    #[test]
+    fn dedupe_keeps_first() {
+        let files = vec![
+            ("a.torrent".to_string(), Bytes::from_static(b"a")),
+            ("b.torrent".to_string(), Bytes::from_static(b"b")),
+            ("a copy.torrent".to_string(), Bytes::from_static(b"a")),
+        ];
+        let urls = ["x", "y", "x", "x"].map(String::from).to_vec();
+        let (files, urls) = dedupe(files, urls);
+        let names: Vec<_> = files.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["a.torrent", "b.torrent"]);
+        assert_eq!(urls, ["x", "y"]);
+    }
+}
@@ -0,0 +1,283 @@
+//! end point at "/api/task/bulk", manage many tasks in one request
+//!
+//! POST: apply an action to the tasks of `hashes`, or else the tasks matching `filter`,
+//! request body is [`BulkReq`], respond a [`BulkItemRes`] per task.
+//! The same hash is managed once, and a few tasks are managed at a time.
+use crate::{
+    errors::{TaskError, format_error_chain},
+    qb::{self, QueuePrio},
+    server::{
+        ResultResponse,
+        api::{from_json_owned, task_api::start_task},
+        error::ServerError,
+    },
+    task::{self, Status, task_map},
+};
+
+use std::collections::HashSet;
+
+use futures_util::{StreamExt, stream};
+use hyper::{Method, Response, StatusCode};
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use super::{Action, BoxBody, Req, ServerResult};
+
+/// maximum number of tasks being managed at the same time
+const MAX_CONCURRENT_TASKS: usize = 4;
+
+#[derive(Debug, Default)]
+pub struct BulkAPI;
+
+impl Action for BulkAPI {
+    async fn execute(&self, req: Req) -> ServerResult<Response<BoxBody>> {
+        if !qb::any_logined() {
+            return Ok(ResultResponse::error_msg("Qbittorrent is not logged in"));
+        }
+        match *req.method() {
+            Method::POST => post(req).await,
+            _ => Ok(ResultResponse::error_with_code(
+                StatusCode::METHOD_NOT_ALLOWED,
+            )),
+        }
+    }
+}
+
+async fn post(req: Req) -> ServerResult<Response<BoxBody>> {
+    let bulk_req: BulkReq = from_json_owned(req).await?;
+    bulk_req.check()?;
+    let hashes = match (bulk_req.hashes, bulk_req.filter) {
+        (Some(hashes), _) => hashes,
+        (None, Some(filter)) => filter.hashes(),
+        (None, None) => return Err(ServerError::MissingParams("hashes or filter")),
+    };
+    let res: Vec<BulkItemRes> = stream::iter(dedupe(hashes))
+        .map(|hash| async move {
+            let error = apply(&hash, bulk_req.action, bulk_req.skip, bulk_req.priority)
+                .await
+                .err();
+            BulkItemRes { hash, error }
+        })
+        .buffered(MAX_CONCURRENT_TASKS)
+        .collect()
+        .await;
+    if bulk_req.action == BulkAction::Delete
+        && let Err(e) = task::save().await
+    {
+        error!("Failed to save task list\n{}", format_error_chain(e));
+    }
+    Ok(ResultResponse::success_data(res))
+}
+
+/// drop the same hashes, keeping the first ones
+fn dedupe(hashes: Vec<String>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    hashes
+        .into_iter()
+        .filter(|hash| seen.insert(hash.clone()))
+        .collect()
+}
+
+/// apply the action to a single task, the error is described for the result
+async fn apply(
+    hash: &str,
+    action: BulkAction,
+    skip: Option<bool>,
+    priority: Option<QueuePrio>,
+) -> Result<(), String> {
+    match action {
+        BulkAction::Start => start_task(hash, skip).await.map_err(format_error_chain),
+        BulkAction::Stop => task::stop(hash).await.map_err(|e| match e {
+            TaskError::Abort => "Task is not running".to_string(),
+            e => format_error_chain(e),
+        }),
+        BulkAction::Delete => {
+            if !task_map().contains_key(hash) {
+                return Err("Task not found".to_string());
+            }
+            // the task list is saved once all are deleted
+            task::delete_unsaved(hash, true)
+                .await
+                .map_err(format_error_chain)
+        }
+        BulkAction::Priority => {
+            let priority = priority.ok_or("Missing priority")?;
+            let instance = task_map()
+                .get(hash)
+                .map(|task| task.instance.clone())
+                .ok_or("Task not found")?;
+            qb::set_queue_prio(&instance, hash, priority)
+                .await
+                .map_err(format_error_chain)
+        }
+    }
+}
+
+/// action to apply to every task
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkAction {
+    /// start paused tasks, and resume errored tasks, see `skip`
+    Start,
+    Stop,
+    Delete,
+    /// move the torrents in the qBittorrent download queue, see `priority`
+    Priority,
+}
+
+/// tasks to match when no hash is given, all of the conditions should be met,
+/// at least one of them is required
+#[derive(Debug, Deserialize)]
+pub struct TaskFilter {
+    pub status: Option<Status>,
+    /// part of the task name, case-insensitive
+    pub name: Option<String>,
+}
+
+impl TaskFilter {
+    /// true if no condition is set, a blank name is not a condition
+    fn is_empty(&self) -> bool {
+        self.status.is_none()
+            && self
+                .name
+                .as_deref()
+                .is_none_or(|name| name.trim().is_empty())
+    }
+
+    /// if a task of `name` in `status` meets all of the conditions
+    fn matches(&self, name: &str, status: Status) -> bool {
+        self.status.is_none_or(|filter| filter == status)
+            && self
+                .name
+                .as_deref()
+                .is_none_or(|filter| name.to_lowercase().contains(&filter.trim().to_lowercase()))
+    }
+
+    /// hashes of the matched tasks
+    fn hashes(&self) -> Vec<String> {
+        task_map()
+            .values()
+            .filter(|task| self.matches(&task.name, task.state().status))
+            .map(|task| task.hash.clone())
+            .collect()
+    }
+}
+
+/// Request of managing tasks in bulk
+#[derive(Debug, Deserialize)]
+pub struct BulkReq {
+    /// hashes of the tasks, `filter` is ignored if set, should not be empty
+    pub hashes: Option<Vec<String>>,
+    pub filter: Option<TaskFilter>,
+    pub action: BulkAction,
+    /// skip the error when resuming errored tasks, only for skipable errors
+    pub skip: Option<bool>,
+    /// required by [`BulkAction::Priority`]
+    pub priority: Option<QueuePrio>,
+}
+
+impl BulkReq {
+    /// reject a request selecting no task or missing the priority,
+    /// an empty filter is rejected rather than matching every task
+    fn check(&self) -> ServerResult<()> {
+        match (&self.hashes, &self.filter) {
+            (Some(hashes), _) if hashes.is_empty() => {
+                return Err(ServerError::MissingParams("hashes"));
+            }
+            (None, Some(filter)) if filter.is_empty() => {
+                return Err(ServerError::MissingParams("filter"));
+            }
+            (None, None) => return Err(ServerError::MissingParams("hashes or filter")),
+            _ => {}
+        }
+        if self.action == BulkAction::Priority && self.priority.is_none() {
+            return Err(ServerError::MissingParams("priority"));
+        }
+        Ok(())
+    }
+}
+
+/// result of a single task, succeeded if `error` is not set
+#[derive(Debug, Serialize)]
+pub struct BulkItemRes {
+    pub hash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(status: Option<Status>, name: Option<&str>) -> TaskFilter {
+        TaskFilter {
+            status,
+            name: name.map(String::from),
+        }
+    }
+
+    fn bulk_req(hashes: Option<&[&str]>, filter: Option<TaskFilter>) -> BulkReq {
+        BulkReq {
+            hashes: hashes.map(|hashes| hashes.iter().map(|&hash| hash.to_string()).collect()),
+            filter,
+            action: BulkAction::Stop,
+            skip: None,
+            priority: None,
+        }
+    }
+
+    #[test]
+    fn filter_matches() {
+        // (status, name, task name, task status, expected)
+        let cases = [
+            (Some(Status::Paused), None, "Foo", Status::Paused, true),
+            (Some(Status::Paused), None, "Foo", Status::Error, false),
+            (None, Some("oo"), "Foo", Status::Done, true),
+            (None, Some(" FOO "), "a foo b", Status::Done, true),
+            (None, Some("bar"), "Foo", Status::Done, false),
+            (Some(Status::Error), Some("foo"), "Foo", Status::Error, true),
+            (
+                Some(Status::Error),
+                Some("foo"),
+                "Foo",
+                Status::Paused,
+                false,
+            ),
+        ];
+        for (status, name, task_name, task_status, expected) in cases {
+            assert_eq!(
+                filter(status, name).matches(task_name, task_status),
+                expected,
+                "{status:?} {name:?} against {task_name} {task_status:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn dedupe_keeps_first() {
+        let hashes = ["b", "a", "b", "c", "a"].map(String::from).to_vec();
+        assert_eq!(dedupe(hashes), ["b", "a", "c"]);
+    }
+
+    #[test]
+    fn reject_empty_selection() {
+        for req in [
+            bulk_req(None, Some(filter(None, None))),
+            bulk_req(None, Some(filter(None, Some("  ")))),
+            bulk_req(Some(&[]), None),
+            bulk_req(Some(&[]), Some(filter(Some(Status::Paused), None))),
+            bulk_req(None, None),
+        ] {
+            assert!(
+                matches!(req.check(), Err(ServerError::MissingParams(_))),
+                "{req:?}"
+            );
+        }
+        assert!(bulk_req(Some(&["a"]), None).check().is_ok());
+        assert!(
+            bulk_req(None, Some(filter(None, Some("a"))))
+                .check()
+                .is_ok()
+        );
+    }
+}
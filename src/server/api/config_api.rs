@@ -1,21 +1,23 @@
 //!  end point at "/api/config"
-use std::sync::Arc;
+use std::{borrow::Cow, sync::Arc};
 
 use super::{Action, BoxBody, Req, ServerResult};
 use crate::{
     auth::{Login, TOKEN, encode},
-    config::{self, Account, ConfigValue},
-    errors::{CommonError, TargetContextedResult},
+    config::{self, Account, ConfigValue, QbConfig},
+    errors::{CommonError, TargetContextedResult, format_error_chain},
     qb,
     server::{
         ResultResponse,
         api::{from_json_owned, login_api::gen_key},
         error::ServerError,
     },
+    task::task_map,
+    upload::template,
 };
 
-use futures_util::future::join;
 use hyper::{Method, Response};
+use log::error;
 
 #[derive(Default)]
 pub struct ConfigAPI;
@@ -32,7 +34,31 @@ impl Action for ConfigAPI {
 /// Change config from post
 async fn post(req: Req) -> ServerResult<Response<BoxBody>> {
     let mut config: ConfigValue = from_json_owned(req).await?;
+    if config.qb.category.is_empty() || config.qb.tag_prefix.is_empty() {
+        return Err(ServerError::BadRequest(Some(
+            "category and tag_prefix must not be empty".into(),
+        )));
+    }
+    config
+        .qb
+        .check_instances()
+        .map_err(|e| ServerError::BadRequest(Some(e.into())))?;
+    check_upload_paths(&config).map_err(|e| ServerError::BadRequest(Some(e)))?;
+    // tasks would be left on an unknown instance
+    if let Some(task) = task_map()
+        .values()
+        .find(|task| config.qb.connection(&task.instance).is_none())
+    {
+        return Err(ServerError::BadRequest(Some(
+            format!(
+                "Instance \"{}\" still has tasks, e.g. {}",
+                task.instance, task.name
+            )
+            .into(),
+        )));
+    }
     let account_bak = config::value().general.account.clone();
+    let qb_bak = config::value().qb.clone();
 
     // account info has changed
     let account_changed =
@@ -53,21 +79,49 @@ async fn post(req: Req) -> ServerResult<Response<BoxBody>> {
     }
 
     let config = Arc::from(config);
-    let (_, config_res) = join(
-        qb::login_with(
-            &config.qb.qb_host,
-            &config.qb.qb_username,
-            &config.qb.qb_password,
-        ),
-        update_config(config.clone(), account_changed),
-    )
-    .await;
+    let config_res = update_config(config, account_changed).await;
+    // login all instances with the updated config
+    qb::login().await;
+    migrate_naming(&qb_bak).await;
     config_res.convert_then_add_context("error updating config")?;
     Ok(ResultResponse::success_msg(
         "Configuration updated successfully",
     ))
 }
 
+/// check the default upload path and the ones of the presets against the new patterns
+fn check_upload_paths(config: &ConfigValue) -> Result<(), Cow<'static, str>> {
+    let patterns = &config.general.upload_path_patterns;
+    template::check(&config.general.default_upload_path, patterns)
+        .map_err(|e| format!("Invalid default upload path: {e}"))?;
+    for (name, preset) in &config.presets {
+        if let Some(upload_path) = &preset.upload_path {
+            template::check(upload_path, patterns)
+                .map_err(|e| format!("Invalid upload path of preset {name}: {e}"))?;
+        }
+    }
+    Ok(())
+}
+
+/// move managed torrents to the new category and tags if they are changed
+async fn migrate_naming(old: &QbConfig) {
+    let changed = {
+        let new = &config::value().qb;
+        old.category != new.category || old.tag_prefix != new.tag_prefix
+    };
+    if !changed {
+        return;
+    }
+    for instance in qb::instances().iter().filter(|i| qb::is_logined(i)) {
+        if let Err(e) = qb::migrate_naming(instance, old).await {
+            error!(
+                "Failed to migrate category and tags of qBittorrent {instance}\n{}",
+                format_error_chain(e)
+            );
+        }
+    }
+}
+
 /// update config with config value
 async fn update_config(config: Arc<ConfigValue>, account_changed: bool) -> Result<(), CommonError> {
     config::set_value(config);
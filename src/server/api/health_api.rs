@@ -0,0 +1,51 @@
+//! endpoint at "/api/health" and "/api/ready"
+//! # GET "/api/health"
+//! liveness probe, always success if the server is responsive
+//! # GET "/api/ready"
+//! readiness probe, response with [`Readiness`] and 503 if not ready
+use super::{Action, BoxBody, Req, ServerResult};
+use crate::{
+    health::{self, Readiness},
+    server::ResultResponse,
+};
+use hyper::{Method, Response, StatusCode};
+
+#[derive(Default, Debug)]
+pub struct HealthAPI;
+impl Action for HealthAPI {
+    fn needs_auth(&self) -> bool {
+        false
+    }
+    async fn execute(&self, req: Req) -> ServerResult<Response<BoxBody>> {
+        match *req.method() {
+            Method::GET => Ok(ResultResponse::success()),
+            _ => Ok(ResultResponse::error_with_code(
+                StatusCode::METHOD_NOT_ALLOWED,
+            )),
+        }
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct ReadyAPI;
+impl Action for ReadyAPI {
+    fn needs_auth(&self) -> bool {
+        false
+    }
+    async fn execute(&self, req: Req) -> ServerResult<Response<BoxBody>> {
+        match *req.method() {
+            Method::GET => {
+                let readiness: Readiness = health::readiness().await;
+                let code = if readiness.ready {
+                    StatusCode::OK
+                } else {
+                    StatusCode::SERVICE_UNAVAILABLE
+                };
+                Ok(ResultResponse::data_with_code(readiness, code))
+            }
+            _ => Ok(ResultResponse::error_with_code(
+                StatusCode::METHOD_NOT_ALLOWED,
+            )),
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! end point at "/api/import", adopt torrents already in qBittorrent as tasks
+//!
+//! GET: list torrents not managed by qb-downloader, of `instance` or all instances
+//! POST: export the torrent file of `instance` and `hash`, respond [`TorrentRes`]
+//! PUT: add the exported torrent as task, request body is [`ImportReq`].
+//! The torrent is moved into the managed category once the request is valid,
+//! and put back into its original category if adding failed.
+//! After each part is uploaded, the torrent is deleted from qBittorrent with the files of that part,
+//! including the ones downloaded before importing, the files of the later parts are kept.
+//! DELETE: cancel importing `hash`, the torrent in qBittorrent is kept
+use crate::{
+    bencode::{self, BencodeError},
+    errors::{IntoContextedError, QbError, TargetContextedResult},
+    qb::{self, TorrentSummary},
+    server::{
+        ResultResponse,
+        api::{from_json_owned, get_param_map, get_required_param},
+        error::ServerError,
+    },
+    task,
+};
+
+use hyper::{Method, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    Action, BoxBody, Req, ServerResult,
+    task_api::{TaskReq, add_task},
+    torrent_api::TorrentRes,
+};
+
+#[derive(Debug, Default)]
+pub struct ImportAPI;
+
+impl Action for ImportAPI {
+    async fn execute(&self, req: Req) -> ServerResult<Response<BoxBody>> {
+        if !qb::any_logined() {
+            return Ok(ResultResponse::error_msg("Qbittorrent is not logged in"));
+        }
+        match *req.method() {
+            Method::GET => get(req),
+            Method::POST => post(req).await,
+            Method::PUT => put(req).await,
+            Method::DELETE => delete(req).await,
+            _ => Ok(ResultResponse::error_with_code(
+                StatusCode::METHOD_NOT_ALLOWED,
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ImportItem {
+    instance: String,
+    #[serde(flatten)]
+    torrent: TorrentSummary,
+}
+
+/// # query parameters
+/// - instance (optional)
+fn get(req: Req) -> ServerResult<Response<BoxBody>> {
+    let instance = get_param_map(&req).and_then(|params| params.get("instance").cloned());
+    let instances = match instance {
+        Some(instance) => vec![instance],
+        None => qb::instances(),
+    };
+    let mut items = Vec::new();
+    for instance in instances.into_iter().filter(|i| qb::is_logined(i)) {
+        let torrents = match qb::get_foreign_torrents(&instance) {
+            Ok(torrents) => torrents,
+            Err(QbError::NotSynced) => continue,
+            Err(e) => Err(e).convert_then_add_context("Failed to get torrents")?,
+        };
+        items.extend(torrents.into_iter().map(|torrent| ImportItem {
+            instance: instance.clone(),
+            torrent,
+        }));
+    }
+    Ok(ResultResponse::success_data(items))
+}
+
+/// # query parameters
+/// - instance (required)
+/// - hash (required)
+async fn post(req: Req) -> ServerResult<Response<BoxBody>> {
+    let (instance, hash) = {
+        let params = get_param_map(&req).ok_or(ServerError::MissingParams("instance or hash"))?;
+        (
+            get_required_param::<String>(&params, "instance")?,
+            get_required_param::<String>(&params, "hash")?,
+        )
+    };
+    if task::find_task(&hash).is_some() {
+        return Ok(ResultResponse::error_msg("Torrent is already a task"));
+    }
+    let torrent = qb::sync::torrent_summary(&instance, &hash)
+        .ok_or(ServerError::create_internal("Torrent not found"))?;
+    task::import(&instance, &hash)
+        .await
+        .convert_then_add_context("Failed to import torrent")?;
+    let torrent_name = bencode::get_torrent_name(&hash).await.map_err(|e| {
+        // only clean the exported torrent, keep the torrent in qBittorrent
+        tokio::spawn(task::cancel_import(String::from(&hash)));
+
+        if let BencodeError::SingleFile = e {
+            ServerError::create_internal("Not a multi-file torrent")
+        } else {
+            ServerError::from(e.into_contexted_error("Failed to parse torrent"))
+        }
+    })?;
+    Ok(ResultResponse::success_data(TorrentRes {
+        torrent_name,
+        hash,
+        save_path: torrent.save_path,
+    }))
+}
+
+/// add the imported torrent as task, see [`TaskReq`]
+#[derive(Debug, Deserialize)]
+pub struct ImportReq {
+    #[serde(flatten)]
+    pub task_req: TaskReq,
+    /// index of the part to start at
+    #[serde(default)]
+    pub start_part: usize,
+}
+
+async fn put(req: Req) -> ServerResult<Response<BoxBody>> {
+    let import_req: ImportReq = from_json_owned(req).await?;
+    add_task(import_req.task_req, import_req.start_part, true).await
+}
+
+async fn delete(req: Req) -> ServerResult<Response<BoxBody>> {
+    let hash = {
+        let params = get_param_map(&req).ok_or(ServerError::MissingParams("hash"))?;
+        get_required_param::<String>(&params, "hash")?
+    };
+    task::cancel_import(hash)
+        .await
+        .convert_then_add_context("Failed to cancel importing")?;
+    Ok(ResultResponse::success())
+}
@@ -2,24 +2,28 @@
 //!
 //! GET: get task status
 //! POST: add new task
-//! PUT: manage tasks - pause, start/resume
+//! PUT: manage tasks - pause, start/resume, rerun a done or archived task,
+//! start from, skip or redo a part of a paused or errored task
+//! PATCH: edit a paused or errored task, request body is [`TaskEditReq`]
 //! DELETE: delete task
 use crate::{
-    config::{self, strip_slash},
+    config::{self, TaskPreset, strip_slash},
     errors::{TargetContextedResult, TaskError, format_error_chain},
-    qb,
+    qb, remove_slash,
     server::{
         ResultResponse,
         api::{from_json_owned, get_option_param, get_param_map, get_required_param},
         error::ServerError,
     },
-    task::{self, task_map},
-    upload::Uploader,
+    task::{self, PartControl, TaskEdit, archive, task_map},
+    upload::{UploadType, Uploader, template},
 };
 
+use std::collections::HashMap;
+
 use hyper::{Method, Response, StatusCode};
 use log::{error, warn};
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 
 use super::{Action, BoxBody, Req, ServerResult, torrent_api::TorrentRes};
 
@@ -28,7 +32,7 @@ pub struct TaskAPI;
 
 impl Action for TaskAPI {
     async fn execute(&self, req: Req) -> ServerResult<Response<BoxBody>> {
-        if !qb::is_logined() {
+        if !qb::any_logined() {
             return Ok(ResultResponse::success());
         }
 
@@ -36,6 +40,7 @@ impl Action for TaskAPI {
             Method::GET => get(),
             Method::POST => post(req).await,
             Method::PUT => put(req).await,
+            Method::PATCH => patch(req).await,
             Method::DELETE => delete(req).await,
             _ => Ok(ResultResponse::error_with_code(
                 StatusCode::METHOD_NOT_ALLOWED,
@@ -58,7 +63,17 @@ fn get() -> ServerResult<Response<BoxBody>> {
 /// # Precondition
 /// - save_path is valid when passing [`TorrentRes`], meaning the path exists
 async fn post(req: Req) -> ServerResult<Response<BoxBody>> {
-    let mut task_req: TaskReq = from_json_owned(req).await?;
+    let task_req: TaskReq = from_json_owned(req).await?;
+    add_task(task_req, 0, false).await
+}
+
+/// add a task from [`TaskReq`], launching from `start_part`,
+/// an imported torrent is adopted once the request is valid if `adopt` is set, see [`task::adopt`]
+pub(super) async fn add_task(
+    mut task_req: TaskReq,
+    start_part: usize,
+    adopt: bool,
+) -> ServerResult<Response<BoxBody>> {
     if !task_req.custom_content {
         task_req.selected_file_index = None;
     } else if let Some(ref selected_file_index) = task_req.selected_file_index
@@ -68,42 +83,96 @@ async fn post(req: Req) -> ServerResult<Response<BoxBody>> {
     }
 
     let c = config::value();
+    // fields given in the request override the preset, which overrides the config defaults
+    let preset = match task_req.preset.as_deref() {
+        Some(name) => match c.presets.get(name) {
+            Some(preset) => preset.clone(),
+            None => {
+                return Ok(ResultResponse::error_msg(format!(
+                    "Preset not found: {name}"
+                )));
+            }
+        },
+        None => TaskPreset::default(),
+    };
     if task_req.upload_path.is_empty() {
-        let general_cfg = &c.general;
-        if general_cfg.default_upload_path.is_empty() {
+        task_req.upload_path = preset
+            .upload_path
+            .map(|path| remove_slash(&path))
+            .filter(|path| !path.is_empty())
+            .unwrap_or_else(|| c.general.default_upload_path.clone());
+        if task_req.upload_path.is_empty() {
             return Err(ServerError::MissingParams("upload_path"));
-        } else {
-            task_req.upload_path = general_cfg.default_upload_path.clone();
         }
     }
+    template::check(&task_req.upload_path, &c.general.upload_path_patterns)
+        .map_err(|e| ServerError::BadRequest(Some(e)))?;
+    let category = task_req.category.or(task_req.preset).unwrap_or_default();
+
+    let uploader = task_req
+        .upload_type
+        .or(preset.upload_type)
+        .map(UploadType::uploader)
+        .ok_or(ServerError::MissingParams("upload_type"))?;
+
+    let max_size = task_req
+        .max_size
+        .or(preset.max_size)
+        .ok_or(ServerError::MissingParams("max_size"))?;
+
     let seeding_time_limit = task_req
         .seeding_time_limit
+        .or(preset.seeding_time_limit)
         .or(c.qb.default_seeding_time_limit)
         .ok_or(ServerError::MissingParams("seeding_time_limit"))?;
 
     let ratio_limit = task_req
         .ratio_limit
+        .or(preset.ratio_limit)
         .or(c.qb.default_ratio_limit)
         .ok_or(ServerError::MissingParams("ratio_limit"))?;
 
+    let hash = task_req.torrent_res.hash.clone();
+    let original_category = if adopt {
+        let adopt_res = task::adopt(&hash).await;
+        if adopt_res.is_err()
+            && let Err(e) = task::cancel_import(&hash).await
+        {
+            error!("Failed to cancel importing\n{}", format_error_chain(e));
+        }
+        Some(adopt_res.convert_then_add_context("Failed to adopt torrent")?)
+    } else {
+        None
+    };
     if let Err(e) = task::add(
         task_req.torrent_res.hash,
         task_req.torrent_res.torrent_name,
         task_req.torrent_res.save_path,
         task_req.upload_path,
-        task_req.upload_type,
+        category,
+        uploader,
         task_req.selected_file_index,
-        task_req.max_size * 1024 * 1024 * 1024, // default in GB
+        max_size * 1024 * 1024 * 1024, // default in GB
         ratio_limit,
         seeding_time_limit,
+        start_part,
+        adopt,
     )
     .await
     {
+        if let Some(category) = original_category
+            && let Err(e) = task::cancel_adopt(&hash, &category).await
+        {
+            error!("Failed to cancel adopting\n{}", format_error_chain(e));
+        }
         if let TaskError::OverSize = e {
             let msg = "Selected files exceed maximum length";
             warn!("{msg}");
             return Ok(ResultResponse::error_msg(msg));
         }
+        if let TaskError::PartOutOfRange = e {
+            return Ok(ResultResponse::error_msg("Start part out of range"));
+        }
         let msg = "Failed to add a task";
         error!("{msg}\n{}", format_error_chain(e));
         return Ok(ResultResponse::error_msg(msg));
@@ -115,30 +184,192 @@ async fn post(req: Req) -> ServerResult<Response<BoxBody>> {
 /// - hash (required)
 /// - type (required)
 /// - skip (optional)
+/// - parts (optional): parts to rerun, see [`get_parts_param`]
+/// - part (required by start_from, skip_part and redo_part): 0-based part index
 async fn put(req: Req) -> ServerResult<Response<BoxBody>> {
-    let (hash, manipulate_type, skip) = {
+    let (hash, manipulate_type, skip, parts, part) = {
         let params = get_param_map(&req).ok_or(ServerError::MissingParams("hash or type"))?;
         (
             get_required_param::<String>(&params, "hash")?,
             get_required_param::<String>(&params, "type")?,
             get_option_param::<bool>(&params, "skip"),
+            get_parts_param(&params)?,
+            get_option_param::<usize>(&params, "part"),
         )
     };
+    let part_control = match manipulate_type.as_str() {
+        "start_from" => Some(PartControl::StartFrom),
+        "skip_part" => Some(PartControl::Skip),
+        "redo_part" => Some(PartControl::Redo),
+        _ => None,
+    };
+    if let Some(control) = part_control {
+        let part = part.ok_or(ServerError::MissingParams("part"))?;
+        return control_part(&hash, part, control).await;
+    }
     match manipulate_type.as_str() {
+        "rerun" => return rerun_task(&hash, parts).await,
         "start" => start_task(&hash, skip).await?,
-        "stop" => task::stop(&hash)
-            .await
-            .convert_then_add_context("Failed to stop task")?,
+        "stop" => match task::stop(&hash).await {
+            Err(TaskError::Abort) => {
+                return Ok(ResultResponse::error_msg("Task is not running"));
+            }
+            res => res.convert_then_add_context("Failed to stop task")?,
+        },
         _ => {
             return Ok(ResultResponse::bad_request(Some("Invalid type".into())));
         }
     }
-    task::save()
-        .await
-        .convert_then_add_context("Failed to save tasks")?;
     Ok(ResultResponse::success())
 }
 
+/// start from, skip or redo a part of a paused or errored task
+async fn control_part(
+    hash: &str,
+    part: usize,
+    control: PartControl,
+) -> ServerResult<Response<BoxBody>> {
+    let task = task_map()
+        .get(hash)
+        .cloned()
+        .ok_or(ServerError::create_internal("Task not found"))?;
+    if !matches!(
+        task.state().status,
+        task::Status::Paused | task::Status::Error
+    ) {
+        return Ok(ResultResponse::error_msg(
+            "Task is not in a paused or error state",
+        ));
+    }
+    match task::control_part(task, part, control).await {
+        Ok(()) => Ok(ResultResponse::success()),
+        Err(TaskError::PartOutOfRange) => Ok(ResultResponse::error_msg("Part out of range")),
+        Err(e) => {
+            let msg = "Failed to control the task part";
+            error!("{msg}\n{}", format_error_chain(e));
+            Ok(ResultResponse::error_msg(msg))
+        }
+    }
+}
+
+/// comma-separated 0-based part indices in `parts`, `None` if not set
+pub(super) fn get_parts_param(
+    params: &HashMap<String, String>,
+) -> ServerResult<Option<Vec<usize>>> {
+    let Some(parts) = params.get("parts").filter(|parts| !parts.is_empty()) else {
+        return Ok(None);
+    };
+    parts
+        .split(',')
+        .map(|part| part.trim().parse())
+        .collect::<Result<_, _>>()
+        .map(Some)
+        .map_err(|_| ServerError::BadRequest(Some("Invalid parts".into())))
+}
+
+/// Rerun the parts of a done task in the task list, or else the latest archived run of the torrent,
+/// all parts if `parts` is None
+pub(super) async fn rerun_task(
+    hash: &str,
+    parts: Option<Vec<usize>>,
+) -> ServerResult<Response<BoxBody>> {
+    if !task::get_torrent_path(hash).exists() {
+        return Ok(ResultResponse::error_msg("Torrent file has been deleted"));
+    }
+    let task = task_map().get(hash).cloned();
+    let res = match task {
+        Some(task) if task.state().status != task::Status::Done => {
+            return Ok(ResultResponse::error_msg("Task is not done"));
+        }
+        Some(task) => task::rerun(task, parts).await,
+        None => {
+            let archived = archive::find(hash)
+                .await
+                .convert_then_add_context("Failed to read archive")?;
+            let Some(archived) = archived else {
+                return Ok(ResultResponse::error_msg("Task not found"));
+            };
+            archive::rerun(archived, parts).await
+        }
+    };
+    match res {
+        Ok(()) => Ok(ResultResponse::success_msg("Task rerun successfully")),
+        Err(TaskError::PartOutOfRange) => Ok(ResultResponse::error_msg("Part out of range")),
+        Err(e) => {
+            let msg = "Failed to rerun the task";
+            error!("{msg}\n{}", format_error_chain(e));
+            Ok(ResultResponse::error_msg(msg))
+        }
+    }
+}
+
+/// edit a task, the parts not downloaded yet are planned again
+/// if `max_size` or `selected_file_index` is set
+async fn patch(req: Req) -> ServerResult<Response<BoxBody>> {
+    let edit_req: TaskEditReq = from_json_owned(req).await?;
+    let task = task_map()
+        .get(&edit_req.hash)
+        .cloned()
+        .ok_or(ServerError::create_internal("Task not found"))?;
+    if !matches!(
+        task.state().status,
+        task::Status::Paused | task::Status::Error
+    ) {
+        return Ok(ResultResponse::error_msg(
+            "Task is not in a paused or error state",
+        ));
+    }
+    if let Some(ref selected_file_index) = edit_req.selected_file_index {
+        if selected_file_index.is_empty() {
+            return Ok(ResultResponse::error_msg("Selected none content"));
+        }
+        if selected_file_index
+            .iter()
+            .any(|&index| index >= task.file_num)
+        {
+            return Ok(ResultResponse::bad_request(Some(
+                "Invalid file index".into(),
+            )));
+        }
+    }
+    if !edit_req.upload_path.is_empty()
+        && let Err(e) = template::check(
+            &edit_req.upload_path,
+            &config::value().general.upload_path_patterns,
+        )
+    {
+        return Ok(ResultResponse::bad_request(Some(e)));
+    }
+    let edit = TaskEdit {
+        upload_path: Some(edit_req.upload_path).filter(|path| !path.is_empty()),
+        uploader: edit_req.upload_type.map(UploadType::uploader),
+        max_size: edit_req.max_size.map(|size| size * 1024 * 1024 * 1024), // in GB
+        seeding_time_limit: edit_req.seeding_time_limit,
+        ratio_limit: edit_req.ratio_limit,
+        selected_file_index: edit_req.selected_file_index,
+    };
+    if let Err(e) = task::edit(task, edit).await {
+        match e {
+            TaskError::OverSize => {
+                return Ok(ResultResponse::error_msg(
+                    "Selected files exceed maximum length",
+                ));
+            }
+            TaskError::PartOutOfRange => {
+                return Ok(ResultResponse::error_msg(
+                    "No file left to download, or a queued part is out of range",
+                ));
+            }
+            e => {
+                let msg = "Failed to edit the task";
+                error!("{msg}\n{}", format_error_chain(e));
+                return Ok(ResultResponse::error_msg(msg));
+            }
+        }
+    }
+    Ok(ResultResponse::success_msg("Task edited successfully"))
+}
+
 async fn delete(req: Req) -> ServerResult<Response<BoxBody>> {
     let hash = {
         let params = get_param_map(&req).ok_or(ServerError::MissingParams("hash"))?;
@@ -152,20 +383,67 @@ async fn delete(req: Req) -> ServerResult<Response<BoxBody>> {
     Ok(ResultResponse::success())
 }
 
+/// Request of adding a task, the optional fields fall back to the `preset`,
+/// and then to the config defaults
 #[derive(Debug, Deserialize)]
 pub struct TaskReq {
     pub torrent_res: TorrentRes,
-    pub upload_type: Uploader,
-    #[serde(deserialize_with = "strip_slash")]
+    /// name of the preset in config `presets`
+    #[serde(default)]
+    pub preset: Option<String>,
+    /// the type name, or the tagged [`Uploader`] sent before, e.g. `{"type": "Rclone"}`
+    #[serde(default, deserialize_with = "upload_type_or_uploader")]
+    pub upload_type: Option<UploadType>,
+    /// upload path template, see [`crate::upload`]
+    #[serde(default, deserialize_with = "strip_slash")]
     pub upload_path: String,
-    pub max_size: i64,
+    /// the preset name if not set
+    #[serde(default)]
+    pub category: Option<String>,
+    /// maximum size of a part in GB
+    pub max_size: Option<i64>,
     pub seeding_time_limit: Option<i32>,
     pub ratio_limit: Option<f64>,
     pub custom_content: bool,
     pub selected_file_index: Option<Vec<usize>>,
 }
 
-async fn start_task(hash: &str, skip: Option<bool>) -> ServerResult<()> {
+/// accept both the [`UploadType`] name and the tagged [`Uploader`]
+fn upload_type_or_uploader<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<UploadType>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum UploadTypeReq {
+        Type(UploadType),
+        Uploader(Uploader),
+    }
+    Ok(
+        Option::<UploadTypeReq>::deserialize(deserializer)?.map(|req| match req {
+            UploadTypeReq::Type(upload_type) => upload_type,
+            UploadTypeReq::Uploader(uploader) => uploader.upload_type(),
+        }),
+    )
+}
+
+/// Request of editing a task, fields not set are kept
+#[derive(Debug, Deserialize)]
+pub struct TaskEditReq {
+    pub hash: String,
+    pub upload_type: Option<UploadType>,
+    /// kept if empty
+    #[serde(default, deserialize_with = "strip_slash")]
+    pub upload_path: String,
+    /// maximum size of a part in GB
+    pub max_size: Option<i64>,
+    pub seeding_time_limit: Option<i32>,
+    pub ratio_limit: Option<f64>,
+    /// files to download, selected in qBittorrent right away for a paused task,
+    /// or when an errored task is resumed
+    pub selected_file_index: Option<Vec<usize>>,
+}
+
+pub(super) async fn start_task(hash: &str, skip: Option<bool>) -> ServerResult<()> {
     let skip = skip.unwrap_or(false);
     let task = task_map()
         .get(hash)
@@ -198,3 +476,48 @@ async fn start_task(hash: &str, skip: Option<bool>) -> ServerResult<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task_req(upload_type: &str) -> TaskReq {
+        serde_json::from_str(&format!(
+            r#"{{
+                "torrent_res": {{"torrent_name": "a", "hash": "b", "save_path": "/c"}},
+                "upload_type": {upload_type},
+                "custom_content": false
+            }}"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn upload_type_shapes() {
+        // (upload_type, expected)
+        let cases = [
+            (r#""Rclone""#, Some(UploadType::Rclone)),
+            (r#"{"type": "Rclone"}"#, Some(UploadType::Rclone)),
+            (
+                r#"{"type": "Rclone", "job": null}"#,
+                Some(UploadType::Rclone),
+            ),
+            ("null", None),
+        ];
+        for (upload_type, expected) in cases {
+            assert_eq!(task_req(upload_type).upload_type, expected, "{upload_type}");
+        }
+    }
+
+    #[test]
+    fn upload_type_optional() {
+        let req: TaskReq = serde_json::from_str(
+            r#"{
+                "torrent_res": {"torrent_name": "a", "hash": "b", "save_path": "/c"},
+                "custom_content": false
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(req.upload_type, None);
+    }
+}
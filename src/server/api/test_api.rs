@@ -6,9 +6,12 @@
 //! check if qbittorrent is logged in
 //! Else return success to test authentication
 use super::{Action, BoxBody, Req, ServerResult};
+use std::collections::BTreeMap;
+
 use crate::{
+    config::BasicAuth,
     errors::{QbError, TargetContextedResult},
-    qb,
+    qb::{self, ProxyAuth},
     server::{
         ResultResponse,
         api::{from_json, get_json_body},
@@ -36,19 +39,26 @@ async fn post(req: Req) -> ServerResult<Response<BoxBody>> {
     let test_req: TestReq = from_json(&data)?;
 
     match test_req.test_type {
-        "qb" => match qb::test_login(test_req.host, test_req.username, test_req.password).await {
-            Some(cookie) => {
-                if let Err(e) = qb::get_version(test_req.host, cookie).await {
-                    if let QbError::UnsupportedVersion = e {
-                        return Ok(ResultResponse::error_msg("Unsupported qbittorrent version"));
-                    } else {
-                        Err(e).convert_then_add_context("Failed to get qbittorrent version")?
+        "qb" => {
+            let auth = ProxyAuth::new(test_req.basic_auth.as_ref(), &test_req.headers);
+            match qb::test_login(test_req.host, test_req.username, test_req.password, &auth).await {
+                Ok(cookie) => {
+                    match qb::get_version(test_req.host, cookie.as_deref(), &auth).await {
+                        Ok(_) => Ok(ResultResponse::success()),
+                        Err(QbError::NotLogin) => {
+                            Ok(ResultResponse::error_msg("Qbittorrent failed to login"))
+                        }
+                        Err(QbError::UnsupportedVersion) => {
+                            Ok(ResultResponse::error_msg("Unsupported qbittorrent version"))
+                        }
+                        Err(e) => {
+                            Err(e).convert_then_add_context("Failed to get qbittorrent version")?
+                        }
                     }
                 }
-                Ok(ResultResponse::success())
+                Err(_) => Ok(ResultResponse::error_msg("Qbittorrent failed to login")),
             }
-            None => Ok(ResultResponse::error_msg("Qbittorrent failed to login")),
-        },
+        }
         "Rclone" => {
             if Rclone::test(test_req.host, test_req.username, test_req.password).await {
                 Ok(ResultResponse::success())
@@ -72,9 +82,15 @@ struct TestReq<'a> {
     username: &'a str,
     #[serde(borrow)]
     password: &'a str,
+    /// basic auth of the reverse proxy in front of qbittorrent
+    #[serde(default)]
+    basic_auth: Option<BasicAuth>,
+    /// extra headers of qbittorrent requests
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
 }
 
 async fn get() -> ServerResult<Response<BoxBody>> {
-    let qb_ok = qb::is_logined();
+    let qb_ok = qb::any_logined();
     Ok(ResultResponse::success_data(qb_ok))
 }
@@ -1,7 +1,8 @@
 //! torrent api
 //! # POST:
 //! add torrent, if content-type is multipart/form-data, add by file,
-//! if application/json, add by url, request body is [`TorrentReq`]
+//! if application/json, add by url, request body is [`TorrentReq`].
+//! The torrent is added to the chosen instance, or the one with the most free space
 //! GET: get torrent content tree
 //! DELETE: delete torrent. which is not added as task
 
@@ -11,7 +12,7 @@ use crate::{
     bencode::{self, BencodeError, FileNode},
     config::{self, strip_slash},
     errors::{IntoContextedError, TargetContextedResult},
-    qb, remove_slash,
+    magnet, qb, remove_slash,
     server::{
         ResultResponse,
         api::{
@@ -32,7 +33,7 @@ pub struct TorrentAPI;
 
 impl Action for TorrentAPI {
     async fn execute(&self, req: Req) -> ServerResult<Response<BoxBody>> {
-        if !qb::is_logined() {
+        if !qb::any_logined() {
             return Ok(ResultResponse::error_msg("Qbittorrent is not logged in"));
         }
         match *req.method() {
@@ -49,13 +50,46 @@ impl Action for TorrentAPI {
 
 async fn post(req: Req) -> ServerResult<Response<BoxBody>> {
     let is_file = req.is_multipart();
-    let (file, url, save_path) = if is_file {
-        get_add_by_file_param(req).await?
+    let AddParam {
+        file,
+        url,
+        save_path,
+        instance,
+    } = if is_file {
+        let FileParam {
+            mut files,
+            save_path,
+            instance,
+            ..
+        } = get_add_by_file_param(req).await?;
+        if files.is_empty() {
+            return Err(ServerError::MissingParams("torrent file"));
+        }
+        let (file_name, data) = files.swap_remove(0);
+        AddParam {
+            file: Some(data),
+            url: file_name,
+            save_path,
+            instance,
+        }
     } else {
         get_add_by_url_param(req).await?
     };
+    let instance = match qb::place(instance.as_deref()) {
+        Ok(instance) => instance,
+        Err(_) => {
+            return Ok(ResultResponse::error_msg(
+                "No qbittorrent instance available",
+            ));
+        }
+    };
+    let save_path = match save_path {
+        Some(save_path) => save_path,
+        None => default_save_path(&instance)?,
+    };
 
     let hash = match task::add_torrent(
+        &instance,
         // TODO: zero-copy here
         file.map(|f| {
             let bytes: Vec<_> = f.into();
@@ -68,10 +102,13 @@ async fn post(req: Req) -> ServerResult<Response<BoxBody>> {
     {
         Ok(h) => h,
         Err(TaskError::Abort) => return Ok(ResultResponse::success()),
+        Err(TaskError::TorrentUrl(msg)) => return Ok(ResultResponse::error_msg(msg)),
+        Err(TaskError::Magnet(e)) => return Ok(ResultResponse::error_msg(e.to_string())),
         Err(e) => Err(e).convert_then_add_context("Failed to add torrent")?,
     };
 
-    if is_file {
+    // torrent files and http(s) links have been downloaded
+    if is_file || task::is_http_url(&url) {
         // response with full torrent info
         let torrent_name = get_torrent_name_from_hash(&hash).await?;
         let res = TorrentRes {
@@ -82,15 +119,20 @@ async fn post(req: Req) -> ServerResult<Response<BoxBody>> {
         Ok(ResultResponse::success_data(res))
     } else {
         // response with hash and save_path first, fetching metadata asynchronously
-        let res = AsyncTorrentRes { hash, save_path };
+        let res = AsyncTorrentRes {
+            hash,
+            save_path,
+            torrent_name: magnet::parse(&url).ok().and_then(|m| m.name),
+        };
         Ok(ResultResponse::success_data(res))
     }
 }
 
-async fn get_torrent_name_from_hash(hash: &str) -> ServerResult<String> {
-    let torrent_name = bencode::get_torrent_name(&hash).await.map_err(|e| {
+/// name of the added torrent, a single-file torrent is removed
+pub(super) async fn get_torrent_name_from_hash(hash: &str) -> ServerResult<String> {
+    let torrent_name = bencode::get_torrent_name(hash).await.map_err(|e| {
         // clean added torrent
-        tokio::spawn(task::delete(hash.to_string(), false));
+        tokio::spawn(task::delete(String::from(hash), false));
 
         if let BencodeError::SingleFile = e {
             ServerError::create_internal("Not a multi-file torrent")
@@ -101,11 +143,47 @@ async fn get_torrent_name_from_hash(hash: &str) -> ServerResult<String> {
     Ok(torrent_name)
 }
 
-async fn get_add_by_file_param(req: Req) -> ServerResult<(Option<Bytes>, String, String)> {
+/// parameters of adding a torrent
+struct AddParam {
+    file: Option<Bytes>,
+    /// file name when adding by file, otherwise the url
+    url: String,
+    save_path: Option<String>,
+    /// the chosen qBittorrent instance, placed automatically if not set
+    instance: Option<String>,
+}
+
+/// fields of a multipart request adding torrents by files, see [`get_add_by_file_param`]
+pub(super) struct FileParam {
+    /// file name and content of every `torrent` field
+    pub files: Vec<(String, Bytes)>,
+    /// the `urls` field, newline-separated links
+    pub urls: String,
+    pub save_path: Option<String>,
+    /// the chosen qBittorrent instance, placed automatically if not set
+    pub instance: Option<String>,
+}
+
+/// the default save path of the instance
+pub(super) fn default_save_path(instance: &str) -> ServerResult<String> {
+    let c = config::value();
+    let default_path =
+        c.qb.connection(instance)
+            .map(|conn| conn.default_save_path)
+            .unwrap_or_default();
+    if default_path.is_empty() {
+        Err(ServerError::MissingParams("save_path"))
+    } else {
+        Ok(default_path.to_string())
+    }
+}
+
+pub(super) async fn get_add_by_file_param(req: Req) -> ServerResult<FileParam> {
     let mut multipart = req.into_multipart()?;
-    let mut data = None;
+    let mut files = Vec::new();
+    let mut urls = String::new();
     let mut save_path = None;
-    let mut file_name = None;
+    let mut instance = None;
     while let Some(field) = multipart
         .next_field()
         .await
@@ -113,13 +191,18 @@ async fn get_add_by_file_param(req: Req) -> ServerResult<(Option<Bytes>, String,
     {
         match field.name() {
             Some("torrent") => {
-                file_name = field.file_name().map(|s| s.to_string());
-                data = Some(
-                    field
-                        .bytes()
-                        .await
-                        .convert_then_add_context("Failed to read torrent file")?,
-                );
+                let file_name = field.file_name().unwrap_or_default().to_string();
+                let data = field
+                    .bytes()
+                    .await
+                    .convert_then_add_context("Failed to read torrent file")?;
+                files.push((file_name, data));
+            }
+            Some("urls") => {
+                urls = field
+                    .text()
+                    .await
+                    .convert_then_add_context("Failed to read urls")?;
             }
             Some("save_path") => {
                 let path = field
@@ -130,41 +213,38 @@ async fn get_add_by_file_param(req: Req) -> ServerResult<(Option<Bytes>, String,
                     save_path = Some(path);
                 }
             }
+            Some("instance") => {
+                let name = field
+                    .text()
+                    .await
+                    .convert_then_add_context("Failed to read instance")?;
+                if !name.is_empty() {
+                    instance = Some(name);
+                }
+            }
             _ => {}
         }
     }
 
-    if data.is_none() {
-        return Err(ServerError::MissingParams("torrent file"));
-    }
-    if save_path.is_none() {
-        let default_path = config::value().qb.default_save_path.clone();
-        if default_path.is_empty() {
-            return Err(ServerError::MissingParams("save_path"));
-        } else {
-            save_path = Some(default_path);
-        }
-    }
-    Ok((data, file_name.unwrap_or_default(), save_path.unwrap()))
+    Ok(FileParam {
+        files,
+        urls,
+        save_path,
+        instance,
+    })
 }
 
-async fn get_add_by_url_param(req: Req) -> ServerResult<(Option<Bytes>, String, String)> {
+async fn get_add_by_url_param(req: Req) -> ServerResult<AddParam> {
     let body = get_json_body(req).await?;
     let torrent_req: TorrentReq = from_json(&body)?;
-    let save_path = {
-        if torrent_req.save_path.is_empty() {
-            let default_path = config::value().qb.default_save_path.clone();
-            if default_path.is_empty() {
-                return Err(ServerError::MissingParams("save_path"));
-            } else {
-                default_path
-            }
-        } else {
-            torrent_req.save_path
-        }
-    };
+    let save_path = Some(torrent_req.save_path).filter(|path| !path.is_empty());
     let url = torrent_req.url.trim();
-    Ok((None, url.to_string(), save_path))
+    Ok(AddParam {
+        file: None,
+        url: url.to_string(),
+        save_path,
+        instance: torrent_req.instance.map(String::from),
+    })
 }
 
 /// get torrent content tree
@@ -230,6 +310,9 @@ struct AsyncTorrentRes {
     pub hash: String,
     /// save path should be nomalized before serialized
     pub save_path: String,
+    /// display name of the magnet link, if any, before metadata arrives
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub torrent_name: Option<String>,
 }
 
 /// add torrent by url
@@ -239,4 +322,7 @@ pub struct TorrentReq<'a> {
     pub url: &'a str,
     #[serde(deserialize_with = "strip_slash")]
     pub save_path: String,
+    /// name of the qBittorrent instance to add to
+    #[serde(borrow)]
+    pub instance: Option<&'a str>,
 }
@@ -1,60 +1,114 @@
 //! qb-downloader task manager
+pub mod archive;
+mod completion;
 pub mod error;
 pub mod handle;
 mod metadata;
+mod migration;
 mod resume;
+pub mod store;
 use std::{
     borrow::Cow,
-    collections::BTreeMap,
-    path::{Path, PathBuf},
-    sync::{Arc, OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    sync::{
+        Arc, LazyLock, Mutex, OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
 use arc_swap::ArcSwap;
 use directories_next::BaseDirs;
-use futures_util::future::{join, join_all};
+use futures_util::{
+    AsyncReadExt,
+    future::{join, join_all},
+};
 use log::{debug, error, info};
 use serde::{Deserialize, Serialize};
 use tokio::fs;
 
 use crate::{
     bencode,
-    errors::{AppError, CommonError, ContextedResult, TargetContextedResult, TaskError},
-    format_error_chain, qb,
+    errors::{
+        AppError, CommonError, ContextedResult, IntoContextedError, QbError, TargetContextedResult,
+        TaskError,
+    },
+    format_error_chain, magnet, qb,
+    request::{self, MyRequestBuilder, RequestError},
     task::{
         self,
         error::{RuntimeTaskError, RuntimeTaskErrorKind},
         resume::{resume_from_error, skip_task},
+        store::{PartEvent, Store, StoreKind, TaskStore},
     },
     upload::Uploader,
 };
 
-const TASK_FILE_NAME: &str = "tasks.json";
 const TORRENT_DIR_NAME: &str = "torrents";
+/// maximum size of a downloaded .torrent file
+const MAX_TORRENT_SIZE: u64 = 20 * 1024 * 1024;
+/// accepted content types of a downloaded .torrent file
+const TORRENT_CONTENT_TYPES: [&str; 4] = [
+    "application/x-bittorrent",
+    "application/octet-stream",
+    "binary/octet-stream",
+    "application/force-download",
+];
+/// minimum interval between two debounced saves, see [`save_if_dirty`]
+const SAVE_DEBOUNCE: Duration = Duration::from_secs(10);
 
 pub static TASK_LIST: OnceLock<Task> = OnceLock::new();
 static TORRENT_DIR: OnceLock<PathBuf> = OnceLock::new();
+/// instances of the torrents added to qBittorrent but not to the task list yet
+static PENDING: LazyLock<Mutex<HashMap<String, String>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
 
 pub type TaskMap = BTreeMap<String, Arc<TaskValue>>;
 #[derive(Debug)]
 pub struct Task {
-    pub filepath: PathBuf,
+    pub store: Store,
     pub value: RwLock<TaskMap>,
+    /// bumped on every change of the task list, see [`mark_dirty`]
+    generation: AtomicU64,
+    /// the generation written by the last save
+    saved_generation: AtomicU64,
+    /// when the task list first changed since the last save
+    dirty_since: Mutex<Option<Instant>>,
+    last_save: Mutex<Option<Instant>>,
+    /// a deferred save is waiting for the end of [`SAVE_DEBOUNCE`]
+    save_scheduled: AtomicBool,
+    /// held by [`save`] from taking the snapshot until it's stored, so saves run one at a time
+    /// and a newer snapshot is never overwritten by an older one
+    save_lock: tokio::sync::Mutex<()>,
 }
 
 /// task value
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TaskValue {
     pub hash: String,
+    /// full v2 info hash for v2 and hybrid torrents,
+    /// a hybrid task is also found by it when given as v2 only, see [`find_task`]
+    pub hash_v2: Option<String>,
+    /// name of the qBittorrent instance the torrent is added to
+    pub instance: String,
     pub name: String,
     pub save_path: String,
     pub root_dir: String,
+    /// upload path template, see [`crate::upload`]
     pub upload_path: String,
+    /// free-form category of the task, the preset name by default
+    pub category: String,
     pub total_part_num: usize,
     pub task_order: Vec<Vec<usize>>,
     /// total file count, which is used to set not download.
     pub file_num: usize,
     pub torrent_path: PathBuf,
+    /// when the task is added, in RFC 3339
+    pub added_at: Option<String>,
+    /// imported from a torrent already in qBittorrent, whose files of the later parts
+    /// may have been downloaded, so only the files of an uploaded part are deleted
+    pub adopted: bool,
     pub max_size: i64,
     pub seeding_time_limit: i32,
     pub ratio_limit: f64,
@@ -70,6 +124,13 @@ pub struct State {
     pub status: Status,
     pub is_seeding: bool,
     pub progress: f64,
+    /// when the task is done, in RFC 3339
+    pub done_at: Option<String>,
+    /// parts left to run after the current one, see [`rerun`] and [`control_part`],
+    /// the parts after the current one run in order if not set
+    pub rerun_parts: Option<Vec<usize>>,
+    /// status before the task is paused, restored by [`start`]
+    pub paused_status: Option<Status>,
 }
 
 /// task status
@@ -103,19 +164,32 @@ impl TaskValue {
             .expect("Failed to acquire read lock on task status")
     }
 
+    /// get the state write lock, which marks the task list dirty
     pub fn state_mut(&self) -> RwLockWriteGuard<'_, State> {
+        mark_dirty();
         self.state
             .write()
             .expect("Failed to acquire write lock on task status")
     }
 
+    /// update the download progress, which is only informative
+    /// and doesn't mark the task list dirty
+    pub fn set_progress(&self, progress: f64) {
+        self.state
+            .write()
+            .expect("Failed to acquire write lock on task status")
+            .progress = progress;
+    }
+
     pub fn error_info(&self) -> Arc<Option<RuntimeTaskError>> {
         self.error_info.load().clone()
     }
     pub fn clean_error_info(&self) {
+        mark_dirty();
         self.error_info.store(Arc::from(None));
     }
     pub fn set_error_info(&self, error: RuntimeTaskError) {
+        mark_dirty();
         self.error_info.store(Arc::from(Some(error)));
     }
 
@@ -134,54 +208,51 @@ impl TaskValue {
     /// may return [`RuntimeTaskError::RuntimeUpload`]
     pub async fn run_check(self: Arc<Self>) -> Result<(), TaskError> {
         if self.uploader.check(self.clone()).await? {
-            self.state_mut().status = Status::Finished;
+            let mut state = self.state_mut();
+            state.status = Status::Finished;
+            record_part(&self.hash, state.current_part_num, PartEvent::Uploaded);
+            drop(state);
             info!("Upload completed for task: {}", &self.name);
         }
         Ok(())
     }
 }
 impl Task {
-    fn new(path: Option<PathBuf>) -> Self {
-        let filepath = if let Some(filepath) = path {
-            filepath
-        } else {
-            BaseDirs::new()
-                .expect("Failed to get data dir")
-                .data_dir()
-                .join("qb-downloader")
-                .join(TASK_FILE_NAME)
-        };
+    fn new(store: Store) -> Self {
         Task {
-            filepath,
+            store,
             value: RwLock::new(BTreeMap::new()),
+            generation: AtomicU64::new(0),
+            saved_generation: AtomicU64::new(0),
+            dirty_since: Mutex::new(None),
+            last_save: Mutex::new(None),
+            save_scheduled: AtomicBool::new(false),
+            save_lock: tokio::sync::Mutex::new(()),
         }
     }
 
     fn load(task_list: &mut Task) -> Result<(), CommonError> {
-        let path = &task_list.filepath;
-        if !path.exists() {
-            return Ok(());
-        }
-        let task_file = std::fs::File::open(path)
-            .convert_then_add_context(format!("Failed to open task file: {}", path.display()))?;
-        let reader = std::io::BufReader::new(task_file);
-        let task_map: TaskMap = serde_json::from_reader(reader)
-            .convert_then_add_context("Failed to parse task file")?;
+        let task_map = task_list.store.load()?;
         task_list.value = RwLock::new(task_map);
         Ok(())
     }
 }
 
-pub fn init(path: Option<PathBuf>) -> Result<(), AppError> {
-    let mut task_list = Task::new(path);
+pub fn init(path: Option<PathBuf>, store_kind: StoreKind) -> Result<(), AppError> {
+    let store =
+        Store::open(store_kind, path).convert_then_add_context("Failed to open task store")?;
+    let mut task_list = Task::new(store);
     Task::load(&mut task_list).convert_then_with_context(|| {
         format!(
             "Failed to load task list from: {}",
-            task_list.filepath.display()
+            task_list.store.path().display()
         )
         .into()
     })?;
-    debug!("Task list loaded from: {}", &task_list.filepath.display());
+    debug!(
+        "Task list loaded from: {}",
+        task_list.store.path().display()
+    );
     debug!("Task list content: {:?}", &task_list.value);
     TASK_LIST.set(task_list).expect("failed to set task list");
     TORRENT_DIR
@@ -199,21 +270,110 @@ pub fn init(path: Option<PathBuf>) -> Result<(), AppError> {
     Ok(())
 }
 
+/// save the task list immediately, waiting for a running save to finish first
 pub async fn save() -> Result<(), CommonError> {
-    let path = filepath();
+    let task_list = task_list();
+    let _guard = task_list.save_lock.lock().await;
+    let generation = task_list.generation.load(Ordering::Acquire);
+    let task_map = task_map().clone();
+    task_list.store.save(&task_map).await?;
 
-    let contents = {
-        let task_map = task_map().clone();
-        serde_json::to_vec(&task_map).convert_then_add_context("Failed to serialize task list")?
-    };
-    fs::write(path, contents)
-        .await
-        .convert_then_add_context("Failed to write task list to file")?;
+    task_list
+        .saved_generation
+        .fetch_max(generation, Ordering::AcqRel);
+    {
+        let mut dirty_since = task_list.dirty_since.lock().unwrap();
+        if task_list.generation.load(Ordering::Acquire) == generation {
+            *dirty_since = None;
+        }
+    }
+    *task_list.last_save.lock().unwrap() = Some(Instant::now());
     Ok(())
 }
 
-fn filepath() -> &'static Path {
-    &TASK_LIST.get().expect("task list not initialized").filepath
+/// Save the task list if it has changed since the last save, at most once per [`SAVE_DEBOUNCE`].
+/// Within the interval the save is deferred to its end, so the last change is always written.
+///
+/// Only for status and progress changes, structural changes of the task list
+/// such as adding, editing and deleting a task are saved immediately by [`save`].
+pub async fn save_if_dirty() -> Result<(), CommonError> {
+    if !is_dirty() {
+        return Ok(());
+    }
+    let task_list = task_list();
+    let wait = task_list
+        .last_save
+        .lock()
+        .unwrap()
+        .map(|last| SAVE_DEBOUNCE.saturating_sub(last.elapsed()))
+        .unwrap_or_default();
+    if wait.is_zero() {
+        return save().await;
+    }
+    if !task_list.save_scheduled.swap(true, Ordering::AcqRel) {
+        tokio::spawn(async move {
+            tokio::time::sleep(wait).await;
+            task_list.save_scheduled.store(false, Ordering::Release);
+            if is_dirty()
+                && let Err(e) = save().await
+            {
+                error!("Failed to save task list\n{}", format_error_chain(e));
+            }
+        });
+    }
+    Ok(())
+}
+
+/// whether the task list has changed since the last save
+fn is_dirty() -> bool {
+    let task_list = task_list();
+    task_list.generation.load(Ordering::Acquire)
+        > task_list.saved_generation.load(Ordering::Acquire)
+}
+
+/// mark the task list changed, to be written by the next [`save_if_dirty`]
+pub fn mark_dirty() {
+    let task_list = task_list();
+    task_list.generation.fetch_add(1, Ordering::AcqRel);
+    task_list
+        .dirty_since
+        .lock()
+        .unwrap()
+        .get_or_insert_with(Instant::now);
+}
+
+/// how long the task list has had unsaved changes
+pub fn unsaved_for() -> Option<Duration> {
+    task_list().dirty_since.lock().unwrap().map(|i| i.elapsed())
+}
+
+/// time since the last save
+pub fn last_saved() -> Option<Duration> {
+    task_list().last_save.lock().unwrap().map(|i| i.elapsed())
+}
+
+fn task_list() -> &'static Task {
+    TASK_LIST.get().expect("task list not initialized")
+}
+
+fn store() -> &'static Store {
+    &task_list().store
+}
+
+/// record a part event of the task in the task store
+pub fn record_part(hash: &str, part: usize, event: PartEvent) {
+    debug!("Task: {hash} part {} {}", part + 1, event.as_str());
+    store().record_part(hash, part, event);
+}
+
+/// record a runtime error of the task in the task store
+pub fn record_error(hash: &str, part: usize, error: &RuntimeTaskError) {
+    store().record_error(
+        hash,
+        part,
+        &format!("{:?}", error.kind),
+        &format_error_chain(error),
+    );
 }
 
 pub fn task_map() -> RwLockReadGuard<'static, TaskMap> {
@@ -225,7 +385,25 @@ pub fn task_map() -> RwLockReadGuard<'static, TaskMap> {
         .expect("Failed to acquire read lock on task list")
 }
 
+/// The task of the torrent identified by `hash` in qBittorrent,
+/// or whose v2 hash is `hash` in full or truncated, as a hybrid torrent given by v2 only
+pub fn find_task(hash: &str) -> Option<Arc<TaskValue>> {
+    let task_map = task_map();
+    task_map
+        .get(hash)
+        .or_else(|| {
+            task_map.values().find(|task| {
+                task.hash_v2
+                    .as_deref()
+                    .is_some_and(|v2| v2 == hash || bencode::truncate_v2(v2) == hash)
+            })
+        })
+        .cloned()
+}
+
+/// get the task list write lock, which marks the task list dirty
 pub fn task_map_mut() -> RwLockWriteGuard<'static, TaskMap> {
+    mark_dirty();
     TASK_LIST
         .get()
         .expect("task list not initialized")
@@ -234,14 +412,26 @@ pub fn task_map_mut() -> RwLockWriteGuard<'static, TaskMap> {
         .expect("Failed to acquire read lock on task list")
 }
 
-/// Start a task from paused state
+/// Resume a paused task to the status before it's paused.
+/// The download is started again, and a stopped upload is submitted again.
 pub async fn start(task: Arc<TaskValue>) -> Result<(), TaskError> {
-    qb::start(&task.clone().hash)
-        .await
-        .add_context("Failed to start torrent in qb")?;
-
-    task.state_mut().status = Status::Downloading;
-    save().await?;
+    let paused_status = task.state().paused_status;
+    match paused_status {
+        Some(Status::OnTask) => {
+            task.clone().run_interval().await?;
+        }
+        Some(status @ (Status::Downloaded | Status::Finished)) => {
+            task.state_mut().status = status;
+        }
+        // tasks paused before the status is remembered were downloading
+        _ => {
+            qb::start(&task.instance, &task.hash)
+                .await
+                .add_context("Failed to start torrent in qb")?;
+            task.state_mut().status = Status::Downloading;
+        }
+    }
+    task.state_mut().paused_status = None;
 
     info!("Task started for hash: {}", task.hash);
     Ok(())
@@ -263,20 +453,357 @@ pub async fn resume(
     }
 }
 
+/// Pause a task in any phase, remembering its status for [`start`].
+/// The download is stopped in qBittorrent, and the upload is stopped by the uploader.
+/// A finished part keeps seeding, but the next part is not added until resumed.
+/// # Error
+/// [`TaskError::Abort`] if the task is not found, or not running
 pub async fn stop(hash: &str) -> Result<(), TaskError> {
-    qb::stop(hash)
-        .await
-        .add_context("Failed to stop torrent in qb")?;
-
+    let task = task_map().get(hash).cloned().ok_or(TaskError::Abort)?;
+    let status = task.state().status;
+    match status {
+        Status::Downloading => qb::stop(&task.instance, hash)
+            .await
+            .add_context("Failed to stop torrent in qb")?,
+        Status::OnTask => task.uploader.stop(task.clone()).await?,
+        Status::Downloaded | Status::Finished => {}
+        Status::Paused | Status::Done | Status::Error => return Err(TaskError::Abort),
+    }
     {
-        task_map().get(hash).unwrap().state_mut().status = Status::Paused;
-        save().await?;
+        let mut state = task.state_mut();
+        state.paused_status = Some(status);
+        state.status = Status::Paused;
     }
+    handle::forget(hash);
 
     info!("Task stopped for hash: {hash}");
     Ok(())
 }
 
+/// Run the parts of a done task again from the cached torrent file, all parts if `parts` is None.
+/// The torrent is added to qBittorrent again, and the parts are downloaded and uploaded in order.
+/// # Preconditions
+/// - the task is in [`Status::Done`]
+/// # Error
+/// [`TaskError::PartOutOfRange`] if `parts` is empty or out of range
+pub async fn rerun(task: Arc<TaskValue>, parts: Option<Vec<usize>>) -> Result<(), TaskError> {
+    let mut parts = match parts {
+        Some(mut parts) => {
+            parts.sort_unstable();
+            parts.dedup();
+            if parts.is_empty() || parts.iter().any(|&part| part >= task.total_part_num) {
+                return Err(TaskError::PartOutOfRange);
+            }
+            parts
+        }
+        None => (0..task.total_part_num).collect(),
+    };
+    let first = parts.remove(0);
+    {
+        let mut state = task.state_mut();
+        state.is_seeding = false;
+        state.progress = 0.0;
+        state.rerun_parts = Some(parts);
+    }
+    task.clean_error_info();
+    if let Err(e) = handle::add_part(first, task.clone()).await {
+        task.state_mut().rerun_parts = None;
+        return Err(e);
+    }
+    task.state_mut().done_at = None;
+    info!("Task rerun from part {}: {}", first + 1, task.hash);
+    Ok(())
+}
+
+/// manual operation on a part of a task, see [`control_part`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartControl {
+    /// launch the part, then continue with the parts after it in order
+    StartFrom,
+    /// leave the part out without uploading it, the next part is launched if it's the current one
+    Skip,
+    /// launch the part, then continue with the parts left before
+    Redo,
+}
+
+/// The part to launch and the parts queued after it for [`control_part`],
+/// `pending` being the parts queued now, `None` continues in order from the launched part.
+/// A redo queues the current part again unless `current_finished`.
+/// # Error
+/// [`TaskError::PartOutOfRange`] if no part is left after skipping the current one
+fn plan_part_control(
+    control: PartControl,
+    part: usize,
+    current_part_num: usize,
+    current_finished: bool,
+    mut pending: Vec<usize>,
+) -> Result<(Option<usize>, Option<Vec<usize>>), TaskError> {
+    pending.retain(|&index| index != part);
+    Ok(match control {
+        PartControl::StartFrom => (Some(part), None),
+        PartControl::Redo => {
+            if part != current_part_num && !current_finished {
+                pending.insert(0, current_part_num);
+            }
+            (Some(part), Some(pending))
+        }
+        PartControl::Skip if part == current_part_num => {
+            if pending.is_empty() {
+                return Err(TaskError::PartOutOfRange);
+            }
+            (Some(pending.remove(0)), Some(pending))
+        }
+        PartControl::Skip => (None, Some(pending)),
+    })
+}
+
+/// Jump to, skip or redo a part of a paused or errored task, launching the part with [`launch`].
+/// The torrent is added again from the cached torrent file if it's gone from qBittorrent,
+/// as after a [`RuntimeTaskErrorKind::AddNextPart`] or [`RuntimeTaskErrorKind::TorrentNotFound`].
+/// # Preconditions
+/// - the task is in [`Status::Paused`] or [`Status::Error`]
+/// # Error
+/// [`TaskError::PartOutOfRange`] if `part` is out of range, or no part is left after skipping
+pub async fn control_part(
+    task: Arc<TaskValue>,
+    part: usize,
+    control: PartControl,
+) -> Result<(), TaskError> {
+    if part >= task.total_part_num {
+        return Err(TaskError::PartOutOfRange);
+    }
+    let (current_part_num, current_finished, torrent_gone, pending) = {
+        let state = task.state();
+        let pending = match &state.rerun_parts {
+            Some(parts) => parts.clone(),
+            None => (state.current_part_num + 1..task.total_part_num).collect(),
+        };
+        // the current part has been uploaded once it's finished
+        let error_kind = match state.status {
+            Status::Error => task.error_info().as_ref().as_ref().map(|e| e.kind),
+            _ => None,
+        };
+        let current_finished = match state.status {
+            Status::Paused => state.paused_status == Some(Status::Finished),
+            _ => matches!(error_kind, Some(RuntimeTaskErrorKind::AddNextPart)),
+        };
+        // the torrent is deleted before adding the next part, or removed by the user
+        let torrent_gone = matches!(
+            error_kind,
+            Some(RuntimeTaskErrorKind::AddNextPart | RuntimeTaskErrorKind::TorrentNotFound)
+        ) && qb::sync::torrent(&task.instance, &task.hash).is_none();
+        (
+            state.current_part_num,
+            current_finished,
+            torrent_gone,
+            pending,
+        )
+    };
+    let (launch_part, pending) =
+        plan_part_control(control, part, current_part_num, current_finished, pending)?;
+    if let Some(index) = launch_part {
+        task.set_progress(0.0);
+        if torrent_gone {
+            handle::add_part(index, task.clone()).await?;
+        } else {
+            launch(index, &task.hash, task.clone()).await?;
+        }
+        task.clean_error_info();
+    }
+    task.state_mut().rerun_parts = pending;
+    info!(
+        "Task: {} part {} {control:?}, current part {}",
+        task.hash,
+        part + 1,
+        task.state().current_part_num + 1
+    );
+    Ok(())
+}
+
+/// options of a task to change, `None` keeps the current one, see [`edit`]
+#[derive(Debug, Default)]
+pub struct TaskEdit {
+    pub upload_path: Option<String>,
+    pub uploader: Option<Uploader>,
+    /// maximum size of a part in bytes
+    pub max_size: Option<i64>,
+    pub seeding_time_limit: Option<i32>,
+    pub ratio_limit: Option<f64>,
+    /// files to download, files of the downloaded parts are kept anyway
+    pub selected_file_index: Option<Vec<usize>>,
+}
+
+/// Edit a paused or errored task, replacing it in the task list.
+/// The parts not downloaded yet are planned again if `max_size` or the file selection changes,
+/// keeping the downloaded ones. Parts queued by [`rerun`] or [`control_part`] are mapped to the
+/// new parts holding their files.
+/// The files of a paused task are selected in qBittorrent right away,
+/// an errored task selects them when it's resumed and the current part is added again.
+/// # Preconditions
+/// - the task is in [`Status::Paused`] or [`Status::Error`]
+/// # Error
+/// [`TaskError::OverSize`] if a selected file exceeds `max_size`,
+/// [`TaskError::PartOutOfRange`] if no file is left to download, or a queued part is out of range
+pub async fn edit(task: Arc<TaskValue>, edit: TaskEdit) -> Result<(), TaskError> {
+    let (current_part_num, status, paused_status) = {
+        let state = task.state();
+        (state.current_part_num, state.status, state.paused_status)
+    };
+    // the current part is downloaded unless the error happened before or while downloading,
+    // or the task is paused while downloading
+    let downloaded_part_num = match status {
+        Status::Error => match task.error_info().as_ref().as_ref().map(|e| e.kind) {
+            Some(RuntimeTaskErrorKind::Download | RuntimeTaskErrorKind::TorrentNotFound) => {
+                current_part_num
+            }
+            _ => current_part_num + 1,
+        },
+        Status::Paused => match paused_status {
+            Some(Status::Downloaded | Status::OnTask | Status::Finished) => current_part_num + 1,
+            _ => current_part_num,
+        },
+        _ => current_part_num,
+    }
+    .min(task.task_order.len());
+
+    let max_size = edit.max_size.unwrap_or(task.max_size);
+    let replan = max_size != task.max_size || edit.selected_file_index.is_some();
+    let task_order = if replan {
+        let (downloaded, pending) = task.task_order.split_at(downloaded_part_num);
+        let downloaded_files: Vec<usize> = downloaded.iter().flatten().copied().collect();
+        let pending_files: Vec<usize> = match edit.selected_file_index {
+            Some(selected) => selected
+                .into_iter()
+                .filter(|index| !downloaded_files.contains(index))
+                .collect(),
+            None => pending.iter().flatten().copied().collect(),
+        };
+        let value = bencode::get_value(&task.torrent_path).await?;
+        let (_, torrent_lengths_list) = bencode::parse_torrent(&value)?;
+        let mut task_order = downloaded.to_vec();
+        task_order.extend(get_task_order(
+            &torrent_lengths_list,
+            max_size,
+            Some(&pending_files),
+        )?);
+        if task_order.len() <= current_part_num {
+            return Err(TaskError::PartOutOfRange);
+        }
+        task_order
+    } else {
+        task.task_order.clone()
+    };
+    let rerun_parts = match task.state().rerun_parts.as_deref() {
+        Some(parts) if replan => Some(remap_parts(
+            &task.task_order,
+            &task_order,
+            parts,
+            downloaded_part_num,
+            current_part_num,
+        )),
+        parts => parts.map(<[usize]>::to_vec),
+    };
+    if rerun_parts
+        .iter()
+        .flatten()
+        .any(|&part| part >= task_order.len())
+    {
+        return Err(TaskError::PartOutOfRange);
+    }
+
+    let seeding_time_limit = edit.seeding_time_limit.unwrap_or(task.seeding_time_limit);
+    let ratio_limit = edit.ratio_limit.unwrap_or(task.ratio_limit);
+    if seeding_time_limit != task.seeding_time_limit || ratio_limit != task.ratio_limit {
+        qb::set_share_limit(&task.instance, &task.hash, ratio_limit, seeding_time_limit)
+            .await
+            .add_context("Failed to set share limit")?;
+    }
+
+    let state = {
+        let state = task.state();
+        State {
+            current_part_num: state.current_part_num,
+            status: state.status,
+            is_seeding: state.is_seeding,
+            progress: state.progress,
+            done_at: state.done_at.clone(),
+            rerun_parts,
+            paused_status: state.paused_status,
+        }
+    };
+    let task_value = TaskValue {
+        hash: task.hash.clone(),
+        hash_v2: task.hash_v2.clone(),
+        instance: task.instance.clone(),
+        name: task.name.clone(),
+        save_path: task.save_path.clone(),
+        root_dir: task.root_dir.clone(),
+        upload_path: edit.upload_path.unwrap_or_else(|| task.upload_path.clone()),
+        category: task.category.clone(),
+        total_part_num: task_order.len(),
+        task_order,
+        file_num: task.file_num,
+        torrent_path: task.torrent_path.clone(),
+        added_at: task.added_at.clone(),
+        adopted: task.adopted,
+        max_size,
+        seeding_time_limit,
+        ratio_limit,
+        error_info: ArcSwap::new(task.error_info.load_full()),
+        uploader: edit.uploader.unwrap_or_else(|| task.uploader.clone()),
+        state: RwLock::new(state),
+    };
+
+    // select the files of the planned current part, it's downloaded when the task is started;
+    // an errored task adds the current part again when resumed, selecting its files there
+    if replan && status == Status::Paused && downloaded_part_num == current_part_num {
+        let part = &task_value.task_order[current_part_num];
+        qb::set_not_download(&task.instance, &task.hash, task.file_num)
+            .await
+            .add_context("Failed to set not download in qb")?;
+        qb::set_prio(&task.instance, &task.hash, 1, part)
+            .await
+            .add_context("Failed to select target file in qb")?;
+    }
+    info!("Task edited: {}", task.hash);
+    task_map_mut().insert(task.hash.clone(), Arc::new(task_value));
+    save().await?;
+    Ok(())
+}
+
+/// Map the queued parts of the old plan to the new one after [`edit`] planned the parts from
+/// `downloaded_part_num` again. Downloaded parts keep their index, a planned part is replaced
+/// by the new parts holding any of its files, leaving out the current part.
+fn remap_parts(
+    old_order: &[Vec<usize>],
+    new_order: &[Vec<usize>],
+    parts: &[usize],
+    downloaded_part_num: usize,
+    current_part_num: usize,
+) -> Vec<usize> {
+    let (kept, planned): (Vec<usize>, Vec<usize>) =
+        parts.iter().partition(|&&part| part < downloaded_part_num);
+    let files: Vec<usize> = planned
+        .iter()
+        .filter_map(|&part| old_order.get(part))
+        .flatten()
+        .copied()
+        .collect();
+    let mut parts = kept;
+    parts.extend(
+        new_order
+            .iter()
+            .enumerate()
+            .skip(downloaded_part_num)
+            .filter(|(_, part)| part.iter().any(|index| files.contains(index)))
+            .map(|(index, _)| index),
+    );
+    parts.retain(|&part| part != current_part_num);
+    parts.sort_unstable();
+    parts.dedup();
+    parts
+}
+
 /// clean the cached torrent file according to hash
 pub async fn clean(hash: &str) -> Result<(), TaskError> {
     let path = get_torrent_path(hash);
@@ -290,12 +817,22 @@ pub async fn clean(hash: &str) -> Result<(), TaskError> {
 }
 
 /// Delete task, both qBittorrent task and cached torrent file.
-/// If `added` is true, the task will be removed from the task list.
+/// If `added` is true, the task will be removed from the task list, which is then saved.
 /// # Preconditions
 /// - torrent has been added to torrent and cached
 pub async fn delete(hash: impl AsRef<str>, added: bool) -> Result<(), TaskError> {
-    let hash = hash.as_ref();
-    let (qb_delete_res, file_clean_res) = join(qb::delete(hash, true), clean(hash)).await;
+    delete_unsaved(hash.as_ref(), added).await?;
+    if added && let Err(e) = save().await {
+        error!("Failed to save task list: {}", format_error_chain(e));
+    }
+    Ok(())
+}
+
+/// [`delete`] without saving the task list, for deleting many tasks followed by one [`save`]
+pub async fn delete_unsaved(hash: &str, added: bool) -> Result<(), TaskError> {
+    let instance = instance_of(hash);
+    let (qb_delete_res, file_clean_res) =
+        join(qb::delete(&instance, hash, true), clean(hash)).await;
     if let Err(e) = qb_delete_res {
         error!(
             "Failed to delete torrent in qBittorrent: {}",
@@ -307,63 +844,152 @@ pub async fn delete(hash: impl AsRef<str>, added: bool) -> Result<(), TaskError>
     }
     if added {
         task_map_mut().remove(hash);
-        if let Err(e) = save().await {
-            error!("Failed to save task list: {e}");
-        }
+        handle::forget(hash);
+    } else {
+        PENDING.lock().unwrap().remove(hash);
     }
     info!("Task deleted for hash: {hash}");
     Ok(())
 }
 
-/// Add a new torrent to qbittorrent and cache the torrent file, return the torrent's hash
+/// get the qBittorrent instance of a task, or a torrent pending to be added as task
+fn instance_of(hash: &str) -> String {
+    if let Some(task) = task_map().get(hash) {
+        return task.instance.clone();
+    }
+    PENDING
+        .lock()
+        .unwrap()
+        .get(hash)
+        .cloned()
+        .unwrap_or_else(|| qb::DEFAULT_INSTANCE.to_string())
+}
+
+/// whether the url is a http(s) link to a .torrent file, instead of a magnet link
+pub fn is_http_url(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+/// Download a .torrent file, return the file name and data
+/// # Error
+/// [`TaskError::TorrentUrl`] if the response is not likely a .torrent file, or too large
+async fn download_torrent(url: &str) -> Result<(String, Vec<u8>), TaskError> {
+    let res = request::get(url.to_string()).send().await?;
+    if let Some(content_type) = res
+        .get_header(nyquest::header::CONTENT_TYPE)
+        .ok()
+        .and_then(|mut v| v.pop())
+    {
+        let mime = content_type.split(';').next().unwrap_or_default().trim();
+        if !TORRENT_CONTENT_TYPES.contains(&mime.to_ascii_lowercase().as_str()) {
+            return Err(TaskError::TorrentUrl(
+                format!("Unexpected content type: {mime}").into(),
+            ));
+        }
+    }
+    if res
+        .content_length()
+        .is_some_and(|len| len > MAX_TORRENT_SIZE)
+    {
+        return Err(TaskError::TorrentUrl("Torrent file too large".into()));
+    }
+
+    // the content length may be absent, limit the read anyway
+    let mut data = Vec::new();
+    res.into_async_read()
+        .take(MAX_TORRENT_SIZE + 1)
+        .read_to_end(&mut data)
+        .await
+        .map_err(RequestError::from)?;
+    if data.len() as u64 > MAX_TORRENT_SIZE {
+        return Err(TaskError::TorrentUrl("Torrent file too large".into()));
+    }
+
+    let file_name = url
+        .split(['?', '#'])
+        .next()
+        .and_then(|path| path.rsplit('/').next())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("download.torrent");
+    let file_name = if file_name.ends_with(".torrent") {
+        file_name.to_string()
+    } else {
+        format!("{file_name}.torrent")
+    };
+    Ok((file_name, data))
+}
+
+/// Add a new torrent to qbittorrent and cache the torrent file, return the torrent's hash.
+/// A http(s) url is downloaded and added as file, see [`is_http_url`]
 /// # Parameters
-/// - `is_file`: Whether the torrent is a file or a URL
+/// - `instance`: The qBittorrent instance to add to, see [`qb::place`]
 /// - `file`: The file data
 /// - `url`: The filename of the torrent when is_file, otherwise the URL of the torrent
 /// - `save_path`: The path to save the download data
 pub async fn add_torrent<B: Into<Cow<'static, [u8]>>>(
+    instance: &str,
     file: Option<B>,
     url: &str,
     save_path: &str,
 ) -> Result<String, TaskError> {
+    let (file, url) = match file {
+        Some(file) => (Some(file.into()), Cow::Borrowed(url)),
+        None if is_http_url(url) => {
+            let (file_name, data) = download_torrent(url).await?;
+            (Some(Cow::Owned(data)), Cow::Owned(file_name))
+        }
+        None => (None, Cow::Borrowed(url)),
+    };
+    let url = url.as_ref();
     let hash = {
         if let Some(file) = file {
-            let file = file.into();
-            let hash = bencode::get_hash(&file)?;
-
-            qb::torrent_exists(&hash)
-                .await
-                .add_context("Failed to check qb torrent")?;
+            let hash = bencode::get_hash(&file)?.id().to_string();
+            check_new_torrent(&hash).await?;
 
             let path = get_torrent_path(&hash);
             fs::write(path, &file)
                 .await
                 .convert_then_add_context("Failed to write torrent file")?;
-            qb::add_by_bytes(url, save_path, file)
+            qb::add_by_bytes(instance, url, save_path, file)
                 .await
                 .add_context("Failed to add torrent by bytes in qb")?;
             hash
         } else {
-            let hash = qb::try_parse_hash(url).add_context("Failed to get hash")?;
-            qb::torrent_exists(&hash)
-                .await
-                .add_context("Failed to check qb torrent")?;
+            let hash = magnet::parse(url)?.hash().to_string();
+            check_new_torrent(&hash).await?;
 
-            qb::add_by_url(url, save_path)
+            qb::add_by_url(instance, url, save_path)
                 .await
                 .add_context("Failed to add torrent by url in qb")?;
             let path = get_torrent_path(&hash);
             metadata::insert_fetching_task(
                 hash.clone(),
-                tokio::spawn(metadata::export(hash.clone(), path)),
+                tokio::spawn(metadata::export(instance.to_string(), hash.clone(), path)),
             );
             hash
         }
     };
+    PENDING
+        .lock()
+        .unwrap()
+        .insert(hash.clone(), instance.to_string());
 
     Ok(hash)
 }
 
+/// reject a torrent which is already a task, see [`find_task`], or already in qBittorrent
+async fn check_new_torrent(hash: &str) -> Result<(), TaskError> {
+    if find_task(hash).is_some() {
+        return Err(TaskError::Qb(
+            QbError::NoNewTorrents.into_contexted_error("Torrent is already a task"),
+        ));
+    }
+    qb::torrent_exists(hash)
+        .await
+        .add_context("Failed to check qb torrent")?;
+    Ok(())
+}
+
 pub fn get_torrent_path(hash: &str) -> PathBuf {
     TORRENT_DIR.get().unwrap().join(format!("{hash}.torrent"))
 }
@@ -374,9 +1000,14 @@ pub async fn cancel_fetching(hash: &str) -> Result<(), TaskError> {
         Err(metadata::FetchingError::Cancelled) => task::delete(hash, false).await,
         Err(metadata::FetchingError::Finished) => Ok(()),
         Ok(_) => {
-            qb::delete(hash, true)
-                .await
-                .add_context("Failed to delete torrent in qBittorrent")?;
+            let instance = PENDING.lock().unwrap().remove(hash);
+            qb::delete(
+                instance.as_deref().unwrap_or(qb::DEFAULT_INSTANCE),
+                hash,
+                true,
+            )
+            .await
+            .add_context("Failed to delete torrent in qBittorrent")?;
             Ok(())
         }
     }
@@ -389,20 +1020,89 @@ pub async fn block_fetching(hash: &str) -> Result<(), TaskError> {
     Ok(())
 }
 
-/// add task from [`TaskReq`]
+/// Export the torrent file of a torrent already in qBittorrent, to be adopted as task by [`add`]
+/// # Preconditions
+/// - qBittorrent has fetched the metadata of the torrent
+pub async fn import(instance: &str, hash: &str) -> Result<(), TaskError> {
+    if find_task(hash).is_some() {
+        return Err(TaskError::Qb(
+            QbError::NoNewTorrents.into_contexted_error("Torrent is already a task"),
+        ));
+    }
+    qb::export(instance, hash, &get_torrent_path(hash))
+        .await
+        .add_context("Failed to export torrent file")?;
+    PENDING
+        .lock()
+        .unwrap()
+        .insert(hash.to_string(), instance.to_string());
+    Ok(())
+}
+
+/// Cancel importing, only the cached torrent file is cleaned,
+/// the torrent in qBittorrent is kept
+pub async fn cancel_import(hash: impl AsRef<str>) -> Result<(), TaskError> {
+    let hash = hash.as_ref();
+    PENDING.lock().unwrap().remove(hash);
+    clean(hash).await
+}
+
+/// Move an imported torrent into the managed category before [`add`],
+/// respond the original category to be restored by [`cancel_adopt`] if adding failed
+pub async fn adopt(hash: &str) -> Result<String, TaskError> {
+    let instance = instance_of(hash);
+    let category = qb::sync::torrent_summary(&instance, hash)
+        .map(|torrent| torrent.category)
+        .unwrap_or_default();
+    qb::set_category(&instance, hash, &qb::category())
+        .await
+        .add_context("Failed to set category in qb")?;
+    Ok(category)
+}
+
+/// Put an adopted torrent back into its original `category` and cancel importing,
+/// the torrent in qBittorrent is kept
+pub async fn cancel_adopt(hash: &str, category: &str) -> Result<(), TaskError> {
+    let restore_res = qb::set_category(&instance_of(hash), hash, category)
+        .await
+        .add_context("Failed to restore category in qb");
+    cancel_import(hash).await?;
+    restore_res?;
+    Ok(())
+}
+
+/// add task from [`TaskReq`], launching from `start_part`, `adopted` if imported, see [`adopt`]
 #[allow(clippy::too_many_arguments)]
 pub async fn add(
     hash: String,
     name: String,
     save_path: String,
     upload_path: String,
+    category: String,
     uploader: Uploader,
     selected_file_index: Option<Vec<usize>>,
     max_size: i64,
     ratio_limit: f64,
     seeding_time_limit: i32,
+    start_part: usize,
+    adopted: bool,
 ) -> Result<(), TaskError> {
     let torrent_path = get_torrent_path(&hash);
+    let instance = instance_of(&hash);
+    let info_hash = bencode::read_hash(&torrent_path).await?;
+    // a hybrid torrent added by v2 magnet may be identified by its v1 hash once metadata arrives
+    let (hash, torrent_path) = match qb::sync::torrent(&instance, &hash) {
+        Some(info) if info.hash != hash => {
+            let path = get_torrent_path(&info.hash);
+            fs::rename(&torrent_path, &path)
+                .await
+                .convert_then_add_context("Failed to rename torrent file")?;
+            PENDING.lock().unwrap().remove(&hash);
+            info!("Task: {hash} is identified by {} in qBittorrent", info.hash);
+            (info.hash, path)
+        }
+        _ => (hash, torrent_path),
+    };
     let (root_dir, file_num, task_order) = {
         let value = bencode::get_value(&torrent_path).await?;
         let (root_dir, torrent_lengths_list) = bencode::parse_torrent(&value)?;
@@ -414,12 +1114,18 @@ pub async fn add(
         )?;
         (root_dir, file_num, task_order)
     };
+    if start_part >= task_order.len() {
+        return Err(TaskError::PartOutOfRange);
+    }
     let task_value = TaskValue {
         hash: hash.clone(),
+        hash_v2: info_hash.v2,
+        instance,
         name,
         root_dir,
         save_path,
         upload_path,
+        category,
         uploader,
         total_part_num: task_order.len(),
         state: RwLock::new(State {
@@ -427,10 +1133,15 @@ pub async fn add(
             status: Status::Paused,
             is_seeding: false,
             progress: 0.0,
+            done_at: None,
+            rerun_parts: None,
+            paused_status: None,
         }),
         task_order,
         file_num,
         torrent_path,
+        added_at: Some(humantime::format_rfc3339_seconds(SystemTime::now()).to_string()),
+        adopted,
         max_size,
         seeding_time_limit,
         ratio_limit,
@@ -438,17 +1149,18 @@ pub async fn add(
     };
     let task_value = Arc::from(task_value);
     let (set_share_limit_res, launch_res) = join(
-        qb::set_share_limit(&hash, ratio_limit, seeding_time_limit),
-        launch(0, &hash, task_value.clone()),
+        qb::set_share_limit(&task_value.instance, &hash, ratio_limit, seeding_time_limit),
+        launch(start_part, &hash, task_value.clone()),
     )
     .await;
 
     set_share_limit_res.add_context("Failed to set share limit")?;
     launch_res?;
 
-    qb::remove_tag(&hash, qb::Tag::Waited)
+    qb::remove_tag(&task_value.instance, &hash, qb::Tag::Waited)
         .await
         .add_context("Failed to remove Waited tag in qb")?;
+    PENDING.lock().unwrap().remove(&hash);
     info!("Task added: {hash}");
     task_map_mut().insert(hash, task_value);
     save().await?;
@@ -460,18 +1172,19 @@ pub async fn add(
 /// # Preconditions
 /// - the task must have been added, with [`Status::Paused`] or [`Status::Error`]
 pub async fn launch(index: usize, hash: &str, task: Arc<TaskValue>) -> Result<(), TaskError> {
-    qb::set_not_download(hash, task.file_num)
+    qb::set_not_download(&task.instance, hash, task.file_num)
         .await
         .add_context("Failed to set not download in qb")?;
-    qb::set_prio(hash, 1, task.task_order.get(index).unwrap())
+    qb::set_prio(&task.instance, hash, 1, task.task_order.get(index).unwrap())
         .await
         .add_context("Failed to select target file in qb")?;
-    qb::start(hash)
+    qb::start(&task.instance, hash)
         .await
         .add_context("Failed to start torrent in qb")?;
     let mut state = task.state_mut();
     state.current_part_num = index;
     state.status = Status::Downloading;
+    state.paused_status = None;
     Ok(())
 }
 
@@ -535,13 +1248,25 @@ fn get_task_order(
     Ok(task_order)
 }
 
-/// clean waited torrents, always occurs when a task-adding is canceled.
+/// clean waited torrents of all logged-in instances,
+/// always occurs when a task-adding is canceled.
 pub async fn clean_waited() -> Result<(), TaskError> {
     if task::task_map().is_empty() {
         return Ok(());
     }
+    for instance in qb::instances().iter().filter(|i| qb::is_logined(i)) {
+        if let Err(e) = clean_waited_on(instance).await {
+            error!(
+                "Failed to clean waited torrents in qBittorrent {instance}: {}",
+                format_error_chain(e)
+            );
+        }
+    }
+    Ok(())
+}
 
-    let hash_list = qb::get_tag_torrent_list(qb::Tag::Waited)
+async fn clean_waited_on(instance: &str) -> Result<(), TaskError> {
+    let hash_list = qb::get_tag_torrent_list(instance, qb::Tag::Waited)
         .await
         .add_context("Failed to get waited torrents in qb")?;
 
@@ -556,16 +1281,99 @@ pub async fn clean_waited() -> Result<(), TaskError> {
 
     let clean_qb_fut = async {
         let hash = hash_list.join("|");
-        qb::delete(hash.as_str(), true)
+        qb::delete(instance, hash.as_str(), true)
             .await
             .add_context("Failed to delete waited torrents in qb")?;
         Ok::<(), TaskError>(())
     };
 
     let (_, qb_clean_result) = join(clean_file_fut, clean_qb_fut).await;
-    if let Err(e) = qb_clean_result {
-        error!("Failed to clean waited torrents in qBittorrent: {e}");
+    qb_clean_result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remap_parts_to_new_plan() {
+        let old_order = vec![vec![0], vec![1, 2], vec![3], vec![4, 5]];
+        let new_order = vec![vec![0], vec![1], vec![2, 3], vec![4], vec![5]];
+        // (parts, downloaded_part_num, current_part_num, expected)
+        let cases: &[(&[usize], usize, usize, &[usize])] = &[
+            // downloaded parts keep their index
+            (&[0], 1, 0, &[]),
+            (&[0, 3], 1, 2, &[0, 3, 4]),
+            // planned parts map to every new part holding their files
+            (&[1], 1, 0, &[1, 2]),
+            (&[2, 3], 1, 0, &[2, 3, 4]),
+            // the current part is not queued again
+            (&[1, 3], 1, 1, &[2, 3, 4]),
+            (&[], 1, 0, &[]),
+        ];
+        for &(parts, downloaded_part_num, current_part_num, expected) in cases {
+            assert_eq!(
+                remap_parts(
+                    &old_order,
+                    &new_order,
+                    parts,
+                    downloaded_part_num,
+                    current_part_num
+                ),
+                expected,
+                "parts {parts:?}, downloaded {downloaded_part_num}, current {current_part_num}"
+            );
+        }
     }
 
-    Ok(())
+    #[test]
+    fn plan_part_control_cases() {
+        use PartControl::*;
+        // (control, part, current_part_num, current_finished, pending, expected)
+        #[allow(clippy::type_complexity)]
+        let cases: &[(
+            PartControl,
+            usize,
+            usize,
+            bool,
+            &[usize],
+            Option<(Option<usize>, Option<&[usize]>)>,
+        )] = &[
+            (StartFrom, 3, 1, false, &[2, 3, 4], Some((Some(3), None))),
+            (StartFrom, 0, 1, true, &[2, 3], Some((Some(0), None))),
+            // redo queues the current part again if it's not finished
+            (
+                Redo,
+                0,
+                2,
+                false,
+                &[3, 4],
+                Some((Some(0), Some(&[2, 3, 4]))),
+            ),
+            (Redo, 0, 2, true, &[3, 4], Some((Some(0), Some(&[3, 4])))),
+            (Redo, 3, 2, false, &[3, 4], Some((Some(3), Some(&[2, 4])))),
+            (Redo, 2, 2, false, &[3, 4], Some((Some(2), Some(&[3, 4])))),
+            // skipping the current part launches the next queued one
+            (Skip, 2, 2, false, &[3, 4], Some((Some(3), Some(&[4])))),
+            (Skip, 2, 2, false, &[], None),
+            (Skip, 4, 2, false, &[3, 4], Some((None, Some(&[3])))),
+            (Skip, 0, 2, false, &[3, 4], Some((None, Some(&[3, 4])))),
+        ];
+        for &(control, part, current_part_num, current_finished, pending, expected) in cases {
+            let planned = plan_part_control(
+                control,
+                part,
+                current_part_num,
+                current_finished,
+                pending.to_vec(),
+            );
+            let expected =
+                expected.map(|(launch, pending)| (launch, pending.map(<[usize]>::to_vec)));
+            assert_eq!(
+                planned.ok(),
+                expected,
+                "{control:?} part {part}, current {current_part_num}, finished {current_finished}"
+            );
+        }
+    }
 }
@@ -0,0 +1,210 @@
+//! archive of done tasks
+//!
+//! Done tasks are moved out of the task list into the archive of the task store,
+//! see `completion.remove_after_days`. An archived task can be put back and rerun
+//! from its cached torrent file.
+use std::sync::{Arc, RwLock};
+
+use arc_swap::ArcSwap;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bencode,
+    errors::{CommonError, ContextedResult, IntoContextedError, QbError, TaskError},
+    qb,
+    task::{
+        State, Status, TaskValue, find_task, get_torrent_path, store, store::TaskStore,
+        task_map_mut,
+    },
+    upload::{UploadType, template},
+};
+
+/// summary of a done task kept in the archive
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchivedTask {
+    pub hash: String,
+    pub instance: String,
+    pub name: String,
+    pub category: String,
+    pub save_path: String,
+    /// upload path template
+    pub upload_path: String,
+    /// the expanded upload path of the task root directory
+    pub destination: String,
+    pub upload_type: UploadType,
+    /// total bytes of the selected files, unknown if the torrent file is deleted before archived
+    pub total_size: Option<i64>,
+    pub total_part_num: usize,
+    pub task_order: Vec<Vec<usize>>,
+    pub max_size: i64,
+    pub seeding_time_limit: i32,
+    pub ratio_limit: f64,
+    /// in RFC 3339
+    pub added_at: Option<String>,
+    /// in RFC 3339
+    pub done_at: Option<String>,
+    /// seconds from added to done
+    pub duration: Option<u64>,
+}
+
+impl ArchivedTask {
+    pub(super) async fn new(task: &TaskValue) -> Self {
+        let done_at = task.state().done_at.clone();
+        let duration = task
+            .added_at
+            .as_deref()
+            .zip(done_at.as_deref())
+            .and_then(|(added_at, done_at)| {
+                let added_at = humantime::parse_rfc3339(added_at).ok()?;
+                let done_at = humantime::parse_rfc3339(done_at).ok()?;
+                done_at.duration_since(added_at).ok()
+            })
+            .map(|duration| duration.as_secs());
+        let upload_path = template::upload_path(task).unwrap_or_else(|_| task.upload_path.clone());
+        Self {
+            hash: task.hash.clone(),
+            instance: task.instance.clone(),
+            name: task.name.clone(),
+            category: task.category.clone(),
+            save_path: task.save_path.clone(),
+            upload_path: task.upload_path.clone(),
+            destination: format!("{upload_path}/{}", task.root_dir),
+            upload_type: task.uploader.upload_type(),
+            total_size: total_size(task).await,
+            total_part_num: task.total_part_num,
+            task_order: task.task_order.clone(),
+            max_size: task.max_size,
+            seeding_time_limit: task.seeding_time_limit,
+            ratio_limit: task.ratio_limit,
+            added_at: task.added_at.clone(),
+            done_at,
+            duration,
+        }
+    }
+
+    /// whether the name contains `search` case-insensitively, or the hash is `search`
+    pub fn matches(&self, search: &str) -> bool {
+        search.is_empty()
+            || self.hash.eq_ignore_ascii_case(search)
+            || self.name.to_lowercase().contains(&search.to_lowercase())
+    }
+}
+
+/// total bytes of the files in the task order, read from the cached torrent file
+async fn total_size(task: &TaskValue) -> Option<i64> {
+    let value = bencode::get_value(&task.torrent_path).await.ok()?;
+    let (_, torrent_lengths_list) = bencode::parse_torrent(&value).ok()?;
+    Some(
+        task.task_order
+            .iter()
+            .flatten()
+            .filter_map(|&index| torrent_lengths_list.get(index).map(|&&length| length))
+            .sum(),
+    )
+}
+
+/// Move the done task from the task list into the archive.
+/// Skipped if the task is no longer the same done task in the list,
+/// e.g. it's rerun or deleted while the completion actions run.
+pub(super) async fn archive(
+    task: &Arc<TaskValue>,
+    archived: ArchivedTask,
+) -> Result<(), CommonError> {
+    {
+        let mut task_map = task_map_mut();
+        let unchanged = task_map.get(&task.hash).is_some_and(|current| {
+            Arc::ptr_eq(current, task) && current.state().status == Status::Done
+        });
+        if !unchanged {
+            info!("Task changed before archiving, kept: {}", &task.name);
+            return Ok(());
+        }
+        task_map.remove(&task.hash);
+    }
+    if let Err(e) = store().archive(&archived).await {
+        task_map_mut()
+            .entry(task.hash.clone())
+            .or_insert_with(|| task.clone());
+        return Err(e);
+    }
+    super::save().await?;
+    info!("Task archived: {}", &archived.name);
+    Ok(())
+}
+
+/// archived tasks matching `search`, newest first, with the total count of matches
+pub async fn list(
+    search: &str,
+    offset: usize,
+    limit: usize,
+) -> Result<(usize, Vec<ArchivedTask>), CommonError> {
+    store().archived(search, offset, limit).await
+}
+
+/// the latest archived run of the torrent
+pub async fn find(hash: &str) -> Result<Option<ArchivedTask>, CommonError> {
+    store().find_archived(hash).await
+}
+
+/// Put the archived task back into the task list and run its parts again
+/// from the cached torrent file, all parts if `parts` is None, see [`rerun`](super::rerun)
+/// # Error
+/// if the torrent is already a task, or the torrent file is deleted
+pub async fn rerun(archived: ArchivedTask, parts: Option<Vec<usize>>) -> Result<(), TaskError> {
+    if find_task(&archived.hash).is_some() {
+        return Err(TaskError::Qb(
+            QbError::NoNewTorrents.into_contexted_error("Torrent is already a task"),
+        ));
+    }
+    let task = Arc::new(restore(archived).await?);
+    task_map_mut().insert(task.hash.clone(), task.clone());
+    if let Err(e) = super::rerun(task.clone(), parts).await {
+        task_map_mut().remove(&task.hash);
+        return Err(e);
+    }
+    super::save().await?;
+    Ok(())
+}
+
+/// the done task of the archived one, on the same instance if it's still logged in
+async fn restore(archived: ArchivedTask) -> Result<TaskValue, TaskError> {
+    let torrent_path = get_torrent_path(&archived.hash);
+    let info_hash = bencode::read_hash(&torrent_path).await?;
+    let value = bencode::get_value(&torrent_path).await?;
+    let (root_dir, torrent_lengths_list) = bencode::parse_torrent(&value)?;
+    let instance = qb::place(Some(&archived.instance))
+        .or_else(|_| qb::place(None))
+        .add_context("No qbittorrent instance available")?;
+    Ok(TaskValue {
+        hash: archived.hash,
+        hash_v2: info_hash.v2,
+        instance,
+        name: archived.name,
+        save_path: archived.save_path,
+        root_dir,
+        upload_path: archived.upload_path,
+        category: archived.category,
+        total_part_num: archived.task_order.len(),
+        task_order: archived.task_order,
+        file_num: torrent_lengths_list.len(),
+        torrent_path,
+        added_at: archived.added_at,
+        // the files are downloaded again for a rerun
+        adopted: false,
+        max_size: archived.max_size,
+        seeding_time_limit: archived.seeding_time_limit,
+        ratio_limit: archived.ratio_limit,
+        error_info: ArcSwap::from_pointee(None),
+        uploader: archived.upload_type.uploader(),
+        state: RwLock::new(State {
+            current_part_num: 0,
+            status: Status::Done,
+            is_seeding: false,
+            progress: 0.0,
+            done_at: archived.done_at,
+            rerun_parts: None,
+            paused_status: None,
+        }),
+    })
+}
@@ -0,0 +1,135 @@
+//! actions after a task is done, configured by `completion`
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use log::error;
+use serde_json::{Map, Value};
+use tokio::task::spawn_blocking;
+
+use crate::{
+    config::{self, RcloneCommand},
+    errors::{TaskError, format_error_chain},
+    task::{
+        Status, TaskValue,
+        archive::{self, ArchivedTask},
+        clean, task_map,
+    },
+    upload::{Rclone, template},
+};
+
+/// run the configured actions of the done task, errors are logged only
+pub(super) async fn run(task: Arc<TaskValue>) {
+    let completion = config::value().completion.clone();
+    // summarized before the torrent file may be deleted
+    let archived = match completion.remove_after_days {
+        Some(_) => None,
+        None => Some(ArchivedTask::new(&task).await),
+    };
+    if completion.remove_empty_dirs {
+        let dir = Path::new(&task.save_path).join(&task.root_dir);
+        let res = spawn_blocking(move || remove_empty_dirs(&dir)).await;
+        if let Err(e) = res.map_err(std::io::Error::other).and_then(|res| res) {
+            error!(
+                "Failed to remove empty directories of task: {}\n{e}",
+                &task.name
+            );
+        }
+    }
+    if completion.delete_torrent_file
+        && let Err(e) = clean(&task.hash).await
+    {
+        error!(
+            "Failed to delete torrent file of task: {}\n{}",
+            &task.name,
+            format_error_chain(e)
+        );
+    }
+    if let Some(command) = completion.rclone_command
+        && let Err(e) = rclone_command(&task, &command).await
+    {
+        error!(
+            "Failed to run rclone {} for task: {}\n{}",
+            command.command,
+            &task.name,
+            format_error_chain(e)
+        );
+    }
+    if let Some(archived) = archived
+        && let Err(e) = archive::archive(&task, archived).await
+    {
+        error!(
+            "Failed to archive task: {}\n{}",
+            &task.name,
+            format_error_chain(e)
+        );
+    }
+}
+
+/// Remove `dir` and the directories in it if no file is left, returning whether `dir` is removed.
+/// Symlinks are kept as files.
+fn remove_empty_dirs(dir: &Path) -> std::io::Result<bool> {
+    if !dir.is_dir() {
+        return Ok(false);
+    }
+    let mut empty = true;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            empty &= remove_empty_dirs(&entry.path())?;
+        } else {
+            empty = false;
+        }
+    }
+    if empty {
+        std::fs::remove_dir(dir)?;
+    }
+    Ok(empty)
+}
+
+/// run the rclone command with the templates in params expanded
+async fn rclone_command(task: &TaskValue, command: &RcloneCommand) -> Result<(), TaskError> {
+    let upload_path = template::upload_path(task).map_err(|e| TaskError::Upload(Some(e)))?;
+    let dst = format!("{upload_path}/{}", task.root_dir);
+    let mut params = Map::new();
+    for (key, value) in &command.params {
+        let value = template::render(task, value, &[("dst", &dst)])
+            .map_err(|e| TaskError::Upload(Some(e)))?;
+        params.insert(key.clone(), Value::String(value));
+    }
+    Rclone::command(&command.command, Value::Object(params)).await
+}
+
+/// archive the tasks done more than `completion.remove_after_days` ago
+pub(super) async fn archive_expired() {
+    let Some(days) = config::value().completion.remove_after_days else {
+        return;
+    };
+    let max_age = Duration::from_secs(days * 24 * 60 * 60);
+    let expired: Vec<Arc<TaskValue>> = task_map()
+        .values()
+        .filter(|task| {
+            let state = task.state();
+            state.status == Status::Done
+                && state
+                    .done_at
+                    .as_deref()
+                    .and_then(|done_at| humantime::parse_rfc3339(done_at).ok())
+                    .and_then(|done_at| SystemTime::now().duration_since(done_at).ok())
+                    .is_some_and(|age| age >= max_age)
+        })
+        .cloned()
+        .collect();
+    for task in expired {
+        let archived = ArchivedTask::new(&task).await;
+        if let Err(e) = archive::archive(&task, archived).await {
+            error!(
+                "Failed to archive task: {}\n{}",
+                &task.name,
+                format_error_chain(e)
+            );
+        }
+    }
+}
@@ -6,6 +6,7 @@ use thiserror::Error;
 use crate::{
     bencode::BencodeError,
     errors::{CommonError, ContextedError, IntoContextedError, QbError},
+    magnet::MagnetError,
     request::RequestError,
 };
 
@@ -34,6 +35,19 @@ pub enum TaskError {
     #[error("File over size limit")]
     OverSize,
 
+    #[error("Part index out of range")]
+    PartOutOfRange,
+
+    #[error("{0}")]
+    TorrentUrl(Cow<'static, str>),
+
+    #[error("Failed to parse magnet")]
+    Magnet(
+        #[from]
+        #[source]
+        MagnetError,
+    ),
+
     #[error("Request error")]
     Request(
         #[from]
@@ -43,6 +57,9 @@ pub enum TaskError {
 
     #[error("Task aborted")]
     Abort,
+
+    #[error("Timed out fetching metadata")]
+    MetadataTimeout,
 }
 
 /// Error that may occur when a task is added, which is always bind to one single task
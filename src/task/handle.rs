@@ -1,21 +1,32 @@
 //! This module handle task process
 
 use crate::{
+    bencode, config,
     errors::{AppError, ContextedResult, TargetContextedResult, format_error_chain},
-    qb, request,
+    health,
+    qb::{self, TorrentInfo},
+    request,
     task::{
-        self, RuntimeTaskError, Status, TaskMap, TaskValue,
+        self, RuntimeTaskError, Status, TaskValue, completion,
         error::{RuntimeTaskErrorKind, TaskError},
-        launch, task_map, task_map_mut,
+        launch,
+        store::PartEvent,
+        task_map,
     },
 };
 
 use futures_util::{FutureExt, future::join_all, select};
 use log::{error, info, warn};
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, LazyLock, Mutex},
+    time::SystemTime,
+};
 use tokio::{
+    fs,
     sync::broadcast,
-    time::{Duration, interval, sleep},
+    time::{Duration, Instant, sleep},
 };
 
 const SEEDING: [&str; 5] = [
@@ -27,23 +38,55 @@ const SEEDING: [&str; 5] = [
 ];
 const FINISHED_SEEDING: [&str; 2] = ["stoppedUP", "pausedUP"];
 const ERROR: [&str; 2] = ["error", "missingFiles"];
+/// tolerance of tick jitter when checking if a poll is due
+const POLL_SLACK: Duration = Duration::from_millis(500);
+
+/// last time of checking torrents download state
+static LAST_DOWNLOAD_POLL: Mutex<Option<Instant>> = Mutex::new(None);
+/// last time of checking upload job state, per task
+static LAST_UPLOAD_POLL: LazyLock<Mutex<HashMap<String, Option<Instant>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+/// tasks missing in the synced torrent list, with the full update count when first missed
+static MISSING: LazyLock<Mutex<HashMap<String, u64>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// forget the polling state of the task, when it's stopped or deleted
+pub(super) fn forget(hash: &str) {
+    LAST_UPLOAD_POLL.lock().unwrap().remove(hash);
+    MISSING.lock().unwrap().remove(hash);
+}
+
+/// check if a poll with `interval` is due, and record the poll time if so.
+/// Polls slower than the task interval are throttled here.
+fn poll_due(last: &mut Option<Instant>, interval: Duration) -> bool {
+    if last.is_some_and(|last| last.elapsed() + POLL_SLACK < interval) {
+        return false;
+    }
+    *last = Some(Instant::now());
+    true
+}
 
 pub async fn run(mut shutdown_rx: broadcast::Receiver<()>) -> Result<(), AppError> {
     request::init().await;
     qb::login().await;
-    let mut task_interval = interval(Duration::from_secs(5));
+    tokio::spawn(qb::sync::run(shutdown_rx.resubscribe()));
     loop {
+        // read every tick, so that changes apply without restart
+        let task_interval = Duration::from_secs(config::value().general.task_interval.max(1));
         select! {
             _ = shutdown_rx.recv().fuse() => {
                 break;
             }
 
-            _ = task_interval.tick().fuse() => {
-                if !qb::is_logined() {
-                    continue;
+            _ = sleep(task_interval).fuse() => {
+                if qb::any_logined() {
+                    match process_task_list().await {
+                        Ok(_) => health::record_tick(),
+                        Err(e) => error!("Failed to process task list\n{e:?}"),
+                    }
                 }
-                if let Err(e) = process_task_list().await {
-                error!("Failed to process task list\n{e:?}");
+                if let Err(e) = task::save_if_dirty().await {
+                    error!("Failed to save task list\n{e:?}");
                 }
             }
         }
@@ -67,9 +110,17 @@ async fn process_task_list() -> Result<(), AppError> {
     if task_map().is_empty() {
         return Ok(());
     }
-    update_task()
-        .await
-        .convert_then_add_context("Failed to update task")?;
+    completion::archive_expired().await;
+    let download_poll_interval =
+        Duration::from_secs(config::value().general.download_poll_interval);
+    if poll_due(
+        &mut LAST_DOWNLOAD_POLL.lock().unwrap(),
+        download_poll_interval,
+    ) {
+        update_task()
+            .await
+            .convert_then_add_context("Failed to update task")?;
+    }
     let futures: Vec<_> = {
         let task_map = task_map();
         task_map
@@ -86,9 +137,6 @@ async fn process_task_list() -> Result<(), AppError> {
     };
 
     join_all(futures).await;
-    task::save()
-        .await
-        .convert_then_add_context("Failed to save task list")?;
     Ok(())
 }
 
@@ -100,6 +148,7 @@ fn handle_task_error(task: Arc<TaskValue>, e: RuntimeTaskError) {
         format_error_chain(&e)
     );
     let mut state = task.state_mut();
+    task::record_error(&task.hash, state.current_part_num, &e);
     state.status = Status::Error;
     task.set_error_info(e);
 }
@@ -115,7 +164,23 @@ async fn process_task(task: Arc<TaskValue>) -> Result<(), RuntimeTaskError> {
 
     match status {
         Status::OnTask => {
-            task.run_check().await.map_err(|e| {
+            let upload_poll_interval =
+                Duration::from_secs(config::value().general.upload_poll_interval);
+            let due = {
+                let mut last_upload_poll = LAST_UPLOAD_POLL.lock().unwrap();
+                poll_due(
+                    last_upload_poll.entry(task.hash.clone()).or_default(),
+                    upload_poll_interval,
+                )
+            };
+            if !due {
+                return Ok(());
+            }
+            let res = task.clone().run_check().await;
+            if task.state().status != Status::OnTask {
+                LAST_UPLOAD_POLL.lock().unwrap().remove(&task.hash);
+            }
+            res.map_err(|e| {
                 RuntimeTaskError::from_kind(RuntimeTaskErrorKind::RuntimeUpload, Some(e))
             })?;
             Ok(())
@@ -162,76 +227,130 @@ fn classify_torrent_state(state: String) -> TorrentState {
     }
 }
 
-/// update task status, task map is not empty
+/// update task status of every instance, task map is not empty
 /// # Error
 /// - may return [`TaskError::Qb`] is get torrents status failed
 async fn update_task() -> Result<(), TaskError> {
-    let torrent_infos = qb::get_torrent_info()
-        .await
-        .add_context("Failed to get torrent infos")?;
-
-    let mut task_map = task_map_mut();
-    let mut new_task_map = TaskMap::new();
-
-    for info in torrent_infos {
-        let task = task_map.remove(&info.hash);
-
-        if let Some(task) = task {
-            let mut state = task.state_mut();
-            let current_status = state.status;
-            let current_seeding = state.is_seeding;
+    for instance in qb::instances() {
+        update_instance_task(&instance).await?;
+    }
+    Ok(())
+}
 
-            // check if downloading has completed
-            if let Status::Downloading = current_status {
-                state.progress = info.progress;
-                let torrent_staus = classify_torrent_state(info.state);
+/// update status of the tasks on the instance, skipped if the instance hasn't synced
+async fn update_instance_task(instance: &str) -> Result<(), TaskError> {
+    if !qb::sync::is_synced(instance) {
+        return Ok(());
+    }
+    let mut torrent_infos: HashMap<String, TorrentInfo> = qb::get_torrent_info(instance)
+        .add_context("Failed to get torrent infos")?
+        .into_iter()
+        .map(|info| (info.hash.clone(), info))
+        .collect();
 
-                use TorrentState::*;
-                match torrent_staus {
-                    Seeding => {
-                        state.status = Status::Downloaded;
-                        state.is_seeding = true;
-                    }
-                    FinishedSeeding => {
-                        state.status = Status::Downloaded;
-                        state.is_seeding = false;
-                    }
-                    Error => {
-                        state.status = Status::Error;
-                        task.set_error_info(RuntimeTaskError::from_kind(
-                            RuntimeTaskErrorKind::Download,
-                            None,
-                        ));
-                    }
-                    Downloading => {}
+    let tasks: Vec<_> = task_map()
+        .values()
+        .filter(|task| task.instance == instance)
+        .cloned()
+        .collect();
+    for task in tasks {
+        let info = torrent_infos.remove(&task.hash).or_else(|| {
+            // a hybrid torrent may be listed by its v1 hash
+            let id = qb::sync::torrent(instance, &task.hash)?.hash;
+            torrent_infos.remove(&id)
+        });
+        if let Some(info) = info {
+            update_state(&task, info);
+            MISSING.lock().unwrap().remove(&task.hash);
+            continue;
+        }
+        let status = task.state().status;
+        if status == Status::Done || status == Status::Error {
+            continue;
+        }
+        match qb::sync::torrent(instance, &task.hash) {
+            // the torrent may be left in another category, e.g. the category is changed
+            Some(info) => {
+                MISSING.lock().unwrap().remove(&task.hash);
+                if info.category != qb::category()
+                    && let Err(e) = qb::set_category(instance, &task.hash, &qb::category()).await
+                {
+                    warn!(
+                        "Failed to set category of task: {}\n{}",
+                        &task.name,
+                        format_error_chain(e)
+                    );
                 }
             }
-            // check if seeding has finished
-            else if current_seeding && FINISHED_SEEDING.contains(&info.state.as_str()) {
-                state.is_seeding = false;
-            }
-            drop(state);
-            new_task_map.insert(info.hash, task);
+            None => check_missing(instance, &task),
         }
     }
+    MISSING
+        .lock()
+        .unwrap()
+        .retain(|hash, _| task_map().contains_key(hash));
+    Ok(())
+}
 
-    // remaining tasks in old map are probably deleted in qbittorrent
-    std::mem::take(&mut *task_map)
-        .into_iter()
-        .for_each(|(hash, task)| {
-            let status = task.state().status;
-            if status != Status::Done && status != Status::Error {
-                error!("Task: {} not found in qbittorrent", &task.name);
-                task.state_mut().status = Status::Error;
-                task.set_error_info(RuntimeTaskError::from_kind(
-                    RuntimeTaskErrorKind::TorrentNotFound,
-                    None,
-                ));
+/// Mark the task missing in the synced torrent list as error,
+/// once it's still missing after the next full update.
+/// The synced torrent list may lag behind a newly added torrent.
+fn check_missing(instance: &str, task: &TaskValue) {
+    let full_updates = qb::sync::full_updates(instance);
+    let mut missing = MISSING.lock().unwrap();
+    match missing.get(&task.hash) {
+        Some(&since) if full_updates > since => {
+            missing.remove(&task.hash);
+            error!("Task: {} not found in qbittorrent", &task.name);
+            set_error(task, RuntimeTaskErrorKind::TorrentNotFound);
+        }
+        Some(_) => {}
+        None => {
+            missing.insert(task.hash.clone(), full_updates);
+            qb::sync::request_full_update(instance);
+        }
+    }
+}
+
+/// update task state from torrent info, only take the write lock when state changes
+fn update_state(task: &TaskValue, info: TorrentInfo) {
+    let (current_status, current_seeding) = {
+        let state = task.state();
+        (state.status, state.is_seeding)
+    };
+
+    // check if downloading has completed
+    if let Status::Downloading = current_status {
+        task.set_progress(info.progress);
+
+        use TorrentState::*;
+        let is_seeding = match classify_torrent_state(info.state) {
+            Seeding => true,
+            FinishedSeeding => false,
+            Error => {
+                set_error(task, RuntimeTaskErrorKind::Download);
+                return;
             }
-            new_task_map.insert(hash, task);
-        });
-    *task_map = new_task_map;
-    Ok(())
+            Downloading => return,
+        };
+        let mut state = task.state_mut();
+        state.status = Status::Downloaded;
+        state.is_seeding = is_seeding;
+        task::record_part(&task.hash, state.current_part_num, PartEvent::Downloaded);
+    }
+    // check if seeding has finished
+    else if current_seeding && FINISHED_SEEDING.contains(&info.state.as_str()) {
+        task.state_mut().is_seeding = false;
+    }
+}
+
+/// mark task as error with the given kind
+fn set_error(task: &TaskValue, kind: RuntimeTaskErrorKind) {
+    let mut state = task.state_mut();
+    state.status = Status::Error;
+    let e = RuntimeTaskError::from_kind(kind, None);
+    task::record_error(&task.hash, state.current_part_num, &e);
+    task.set_error_info(e);
 }
 
 /// Add the next part of the task, may get task state write lock
@@ -239,23 +358,70 @@ async fn update_task() -> Result<(), TaskError> {
 /// may return [`RuntimeTaskError::AddNextPart`]
 pub(super) async fn add_next_part(task: Arc<TaskValue>) -> Result<(), TaskError> {
     let hash = &task.hash;
-    let (current_part_num, total_parts) = { (task.state().current_part_num, task.total_part_num) };
-    qb::delete(hash, true)
+    let next_part = {
+        let state = task.state();
+        match &state.rerun_parts {
+            Some(parts) => parts.first().copied(),
+            None => Some(state.current_part_num + 1).filter(|&part| part < task.total_part_num),
+        }
+    };
+    // the files of the later parts of an adopted torrent may be downloaded already
+    qb::delete(&task.instance, hash, !task.adopted)
         .await
         .add_context("Failed to delete old part")?;
+    if task.adopted {
+        remove_part_files(&task).await;
+    }
 
-    if current_part_num == total_parts - 1 {
-        task.state_mut().status = Status::Done;
+    let Some(next_part) = next_part else {
+        {
+            let mut state = task.state_mut();
+            state.status = Status::Done;
+            state.done_at = Some(humantime::format_rfc3339_seconds(SystemTime::now()).to_string());
+            state.rerun_parts = None;
+        }
         info!("Task: {} completed", &task.name);
+        tokio::spawn(completion::run(task));
         return Ok(());
+    };
+    add_part(next_part, task.clone()).await?;
+    if let Some(parts) = &mut task.state_mut().rerun_parts {
+        parts.retain(|&part| part != next_part);
+    }
+    Ok(())
+}
+
+/// remove the downloaded files of the current part, errors are logged only
+async fn remove_part_files(task: &TaskValue) {
+    let paths = match bencode::get_file_paths(&task.torrent_path).await {
+        Ok(paths) => paths,
+        Err(e) => {
+            error!(
+                "Failed to read files of task: {}\n{}",
+                &task.name,
+                format_error_chain(e)
+            );
+            return;
+        }
+    };
+    let part = task.state().current_part_num;
+    let save_path = Path::new(&task.save_path);
+    for path in task.task_order[part]
+        .iter()
+        .filter_map(|&index| paths.get(index))
+    {
+        match fs::remove_file(save_path.join(path)).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => error!("Failed to remove file {}: {e}", path.display()),
+        }
     }
-    let new_part_num = current_part_num + 1;
-    add_part(new_part_num, task).await
 }
 
 /// Add torrent from cached, launch given index part
 pub(super) async fn add_part(index: usize, task: Arc<TaskValue>) -> Result<(), TaskError> {
     qb::add_by_file(
+        &task.instance,
         &task.torrent_path,
         &task.save_path,
         task.seeding_time_limit,
@@ -263,7 +429,11 @@ pub(super) async fn add_part(index: usize, task: Arc<TaskValue>) -> Result<(), T
     )
     .await
     .add_context("Failed to add to qbittorrent")?;
-    sleep(Duration::from_millis(500)).await;
+    // wait for qBittorrent to load the torrent before setting file priorities
+    sleep(Duration::from_millis(
+        config::value().general.add_part_delay,
+    ))
+    .await;
     launch(index, &task.hash, task.clone()).await?;
     info!("Added part {} for task: {}", index + 1, &task.name);
     Ok(())
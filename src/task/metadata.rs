@@ -2,16 +2,19 @@ use std::{
     collections::HashMap,
     path::Path,
     sync::{Arc, LazyLock, OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard, mpsc::Sender},
+    time::{Duration, Instant},
 };
 
 use thiserror::Error;
 use tokio::task::JoinHandle;
 
 use crate::{
-    errors::{ContextedResult, TaskError},
+    errors::{ContextedResult, IntoContextedError, QbError, TaskError},
     qb,
 };
 const FETCHED_STATE: [&str; 4] = ["stoppedUP", "pausedUP", "stoppedDL", "pausedDL"];
+/// give up fetching metadata after this long, e.g. a magnet link without peers
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60 * 60);
 type FetchingMap = HashMap<String, FetchingTaskItem>;
 type FetchHandle = JoinHandle<Result<(), TaskError>>;
 
@@ -97,11 +100,17 @@ pub(super) fn cancel(hash: &str) -> Result<(), FetchingError> {
     }
 }
 
-/// Export torrent file after fetching metadata
-/// Preconditions
+/// Export torrent file from the instance after fetching metadata
+/// # Error
+/// [`TaskError::MetadataTimeout`] if the metadata is not fetched within [`FETCH_TIMEOUT`]
+/// # Preconditions
 /// - call only once per fetching task
 /// - the fetching task has been added to [`METADATA_FETCHING_MAP`]
-pub(super) async fn export(hash: impl AsRef<str>, path: impl AsRef<Path>) -> Result<(), TaskError> {
+pub(super) async fn export(
+    instance: String,
+    hash: impl AsRef<str>,
+    path: impl AsRef<Path>,
+) -> Result<(), TaskError> {
     let (hash, path) = (hash.as_ref(), path.as_ref());
     let (tx, rx) = std::sync::mpsc::channel::<()>();
 
@@ -111,23 +120,41 @@ pub(super) async fn export(hash: impl AsRef<str>, path: impl AsRef<Path>) -> Res
         .write()
         .set_cancel_sender(tx);
 
-    // wait for the torrent to fetch meta data
-    loop {
+    // wait for the torrent to fetch meta data, checking on every sync
+    let deadline = Instant::now() + FETCH_TIMEOUT;
+    let mut updated = qb::sync::subscribe();
+    // whether the torrent has appeared in the synced torrent list
+    let mut seen = false;
+    // the id in qBittorrent, which may change to the v1 hash for a hybrid torrent
+    let id = loop {
         // recv cancel signal
         if rx.try_recv().is_ok() {
             return Err(TaskError::Abort);
         }
 
-        let state = qb::get_state(hash)
-            .await
-            .add_context("Failed to get qBittorrent state")?;
-        if FETCHED_STATE.contains(&state.as_str()) {
-            drop(rx);
-            break;
+        match qb::sync::torrent(&instance, hash) {
+            Some(info) => {
+                if FETCHED_STATE.contains(&info.state.as_str()) {
+                    drop(rx);
+                    break info.hash;
+                }
+                seen = true;
+            }
+            // the torrent is removed while fetching meta data, probably cancelled by user
+            None if seen => {
+                return Err(TaskError::Qb(
+                    QbError::Cancelled
+                        .into_contexted_error("Torrent removed while fetching metadata"),
+                ));
+            }
+            None => {}
         }
-        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-    }
-    qb::export(hash, path)
+        if Instant::now() >= deadline {
+            return Err(TaskError::MetadataTimeout);
+        }
+        qb::sync::wait_update(&mut updated, qb::sync::sync_interval() * 2).await;
+    };
+    qb::export(&instance, &id, path)
         .await
         .add_context("Failed to export torrent file")?;
     Ok(())
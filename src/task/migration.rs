@@ -0,0 +1,615 @@
+//! task file format versioning
+//!
+//! The task file is stored as `{ "version": N, "tasks": { hash: TaskValue } }`.
+//! Older files are upgraded on load by running the migration chain
+//! from their version up to [`TASK_FILE_VERSION`].
+use serde::{Serialize, de::Error as _};
+use serde_json::{Map, Value};
+
+use super::TaskMap;
+use crate::qb::DEFAULT_INSTANCE;
+
+/// current version of the task file format
+pub(super) const TASK_FILE_VERSION: u32 = 9;
+
+/// `MIGRATIONS[i]` upgrades a single serialized task from version `i` to `i + 1`
+const MIGRATIONS: [fn(&mut Map<String, Value>); TASK_FILE_VERSION as usize] = [
+    v0_to_v1, v1_to_v2, v2_to_v3, v3_to_v4, v4_to_v5, v5_to_v6, v6_to_v7, v7_to_v8, v8_to_v9,
+];
+
+/// the versioned task file, used for serializing
+#[derive(Serialize)]
+pub(super) struct TaskFile<'a> {
+    pub version: u32,
+    pub tasks: &'a TaskMap,
+}
+
+impl<'a> TaskFile<'a> {
+    pub fn new(tasks: &'a TaskMap) -> Self {
+        Self {
+            version: TASK_FILE_VERSION,
+            tasks,
+        }
+    }
+}
+
+/// Parse the task file content of any known version into [`TaskMap`]
+/// # Error
+/// if the content is not a known shape, or the version is newer than [`TASK_FILE_VERSION`]
+pub(super) fn parse(value: Value) -> Result<TaskMap, serde_json::Error> {
+    let (version, mut tasks) = split_version(value)?;
+    check_version(version)?;
+    if let Value::Object(tasks) = &mut tasks {
+        for task in tasks.values_mut() {
+            upgrade_task(task, version)?;
+        }
+    }
+    serde_json::from_value(tasks)
+}
+
+/// Parse the version stored apart from the tasks, e.g. in the task database
+/// # Error
+/// if it's not a number, or newer than [`TASK_FILE_VERSION`]
+#[cfg(feature = "sqlite")]
+pub(super) fn parse_version(version: &str) -> Result<u32, serde_json::Error> {
+    let version = version
+        .parse()
+        .map_err(|_| serde_json::Error::custom(format!("invalid task file version {version}")))?;
+    check_version(version)?;
+    Ok(version)
+}
+
+/// reject a version written by a newer build
+fn check_version(version: u32) -> Result<(), serde_json::Error> {
+    if version > TASK_FILE_VERSION {
+        return Err(serde_json::Error::custom(format!(
+            "unsupported task file version {version}, the latest supported is {TASK_FILE_VERSION}"
+        )));
+    }
+    Ok(())
+}
+
+/// Upgrade a single serialized task from `version` to [`TASK_FILE_VERSION`]
+pub(super) fn upgrade_task(task: &mut Value, version: u32) -> Result<(), serde_json::Error> {
+    check_version(version)?;
+    let Value::Object(task) = task else {
+        return Err(serde_json::Error::custom("task is not an object"));
+    };
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(task);
+    }
+    Ok(())
+}
+
+/// split the file content into version and task map,
+/// version 0 is the bare task map without envelope
+fn split_version(value: Value) -> Result<(u32, Value), serde_json::Error> {
+    match value {
+        Value::Object(mut obj) if obj.contains_key("version") => {
+            let version = obj
+                .get("version")
+                .and_then(Value::as_u64)
+                .ok_or_else(|| serde_json::Error::custom("invalid task file version"))?;
+            let tasks = obj
+                .remove("tasks")
+                .ok_or_else(|| serde_json::Error::custom("missing tasks in task file"))?;
+            Ok((version as u32, tasks))
+        }
+        Value::Object(obj) => Ok((0, Value::Object(obj))),
+        _ => Err(serde_json::Error::custom("unknown task file shape")),
+    }
+}
+
+/// v1 only introduces the envelope, task shape is unchanged
+fn v0_to_v1(_: &mut Map<String, Value>) {}
+
+/// v2 adds the qBittorrent instance, tasks before are on the default one
+fn v1_to_v2(task: &mut Map<String, Value>) {
+    task.entry("instance")
+        .or_insert_with(|| Value::String(DEFAULT_INSTANCE.to_string()));
+}
+
+/// v3 adds the v2 info hash, tasks before are all v1 torrents as v2 ones failed to parse
+fn v2_to_v3(task: &mut Map<String, Value>) {
+    task.entry("hash_v2").or_insert(Value::Null);
+}
+
+/// v4 adds the task category, tasks before have none
+fn v3_to_v4(task: &mut Map<String, Value>) {
+    task.entry("category")
+        .or_insert_with(|| Value::String(String::new()));
+}
+
+/// v5 adds the time the task is done, unknown for tasks before
+fn v4_to_v5(task: &mut Map<String, Value>) {
+    if let Some(Value::Object(state)) = task.get_mut("state") {
+        state.entry("done_at").or_insert(Value::Null);
+    }
+}
+
+/// v6 adds the time the task is added, unknown for tasks before
+fn v5_to_v6(task: &mut Map<String, Value>) {
+    task.entry("added_at").or_insert(Value::Null);
+}
+
+/// v7 adds the parts left to rerun, none for tasks before
+fn v6_to_v7(task: &mut Map<String, Value>) {
+    if let Some(Value::Object(state)) = task.get_mut("state") {
+        state.entry("rerun_parts").or_insert(Value::Null);
+    }
+}
+
+/// v8 adds the status before paused, paused tasks before were downloading
+fn v7_to_v8(task: &mut Map<String, Value>) {
+    if let Some(Value::Object(state)) = task.get_mut("state") {
+        state.entry("paused_status").or_insert(Value::Null);
+    }
+}
+
+/// v9 marks the tasks imported from qBittorrent, which are unknown for tasks before
+fn v8_to_v9(task: &mut Map<String, Value>) {
+    task.entry("adopted").or_insert(Value::Bool(false));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::{Status, TaskValue};
+
+    use std::sync::Arc;
+
+    /// v0: bare task map, as written by v2.3.0 and before
+    const V0: &str = r#"{
+        "0123456789abcdef0123456789abcdef01234567": {
+            "hash": "0123456789abcdef0123456789abcdef01234567",
+            "name": "Show S01",
+            "save_path": "/downloads",
+            "root_dir": "Show S01",
+            "upload_path": "remote:/anime",
+            "total_part_num": 2,
+            "task_order": [[0, 1], [2]],
+            "file_num": 3,
+            "torrent_path": "/data/torrents/0123456789abcdef0123456789abcdef01234567.torrent",
+            "max_size": 53687091200,
+            "seeding_time_limit": -2,
+            "ratio_limit": -2.0,
+            "error_info": null,
+            "uploader": { "type": "Rclone", "job": 12 },
+            "state": {
+                "current_part_num": 1,
+                "status": "OnTask",
+                "is_seeding": false,
+                "progress": 1.0
+            }
+        }
+    }"#;
+
+    fn v1() -> String {
+        format!(r#"{{ "version": 1, "tasks": {V0} }}"#)
+    }
+
+    /// v2: adds the instance
+    const V2: &str = r#"{
+        "version": 2,
+        "tasks": {
+            "0123456789abcdef0123456789abcdef01234567": {
+                "hash": "0123456789abcdef0123456789abcdef01234567",
+                "name": "Show S01",
+                "instance": "disk2",
+                "save_path": "/downloads",
+                "root_dir": "Show S01",
+                "upload_path": "remote:/anime",
+                "total_part_num": 2,
+                "task_order": [[0, 1], [2]],
+                "file_num": 3,
+                "torrent_path": "/data/torrents/0123456789abcdef0123456789abcdef01234567.torrent",
+                "max_size": 53687091200,
+                "seeding_time_limit": -2,
+                "ratio_limit": -2.0,
+                "error_info": null,
+                "uploader": { "type": "Rclone", "job": 12 },
+                "state": {
+                    "current_part_num": 1,
+                    "status": "OnTask",
+                    "is_seeding": false,
+                    "progress": 1.0
+                }
+            }
+        }
+    }"#;
+
+    /// v3: adds the v2 info hash
+    const V3: &str = r#"{
+        "version": 3,
+        "tasks": {
+            "0123456789abcdef0123456789abcdef01234567": {
+                "hash": "0123456789abcdef0123456789abcdef01234567",
+                "name": "Show S01",
+                "instance": "disk2",
+                "hash_v2": "d8dd32ac93357c368556af3ac1d95c9d76bd0dff6fa9833ecdac3d53134efabb",
+                "save_path": "/downloads",
+                "root_dir": "Show S01",
+                "upload_path": "remote:/anime",
+                "total_part_num": 2,
+                "task_order": [[0, 1], [2]],
+                "file_num": 3,
+                "torrent_path": "/data/torrents/0123456789abcdef0123456789abcdef01234567.torrent",
+                "max_size": 53687091200,
+                "seeding_time_limit": -2,
+                "ratio_limit": -2.0,
+                "error_info": null,
+                "uploader": { "type": "Rclone", "job": 12 },
+                "state": {
+                    "current_part_num": 1,
+                    "status": "OnTask",
+                    "is_seeding": false,
+                    "progress": 1.0
+                }
+            }
+        }
+    }"#;
+
+    /// v4: adds the category
+    const V4: &str = r#"{
+        "version": 4,
+        "tasks": {
+            "0123456789abcdef0123456789abcdef01234567": {
+                "hash": "0123456789abcdef0123456789abcdef01234567",
+                "name": "Show S01",
+                "instance": "disk2",
+                "hash_v2": "d8dd32ac93357c368556af3ac1d95c9d76bd0dff6fa9833ecdac3d53134efabb",
+                "category": "anime",
+                "save_path": "/downloads",
+                "root_dir": "Show S01",
+                "upload_path": "remote:/anime",
+                "total_part_num": 2,
+                "task_order": [[0, 1], [2]],
+                "file_num": 3,
+                "torrent_path": "/data/torrents/0123456789abcdef0123456789abcdef01234567.torrent",
+                "max_size": 53687091200,
+                "seeding_time_limit": -2,
+                "ratio_limit": -2.0,
+                "error_info": null,
+                "uploader": { "type": "Rclone", "job": 12 },
+                "state": {
+                    "current_part_num": 1,
+                    "status": "Done",
+                    "is_seeding": false,
+                    "progress": 1.0
+                }
+            }
+        }
+    }"#;
+
+    /// v5: adds the time the task is done
+    const V5: &str = r#"{
+        "version": 5,
+        "tasks": {
+            "0123456789abcdef0123456789abcdef01234567": {
+                "hash": "0123456789abcdef0123456789abcdef01234567",
+                "name": "Show S01",
+                "instance": "disk2",
+                "hash_v2": "d8dd32ac93357c368556af3ac1d95c9d76bd0dff6fa9833ecdac3d53134efabb",
+                "category": "anime",
+                "save_path": "/downloads",
+                "root_dir": "Show S01",
+                "upload_path": "remote:/anime",
+                "total_part_num": 2,
+                "task_order": [[0, 1], [2]],
+                "file_num": 3,
+                "torrent_path": "/data/torrents/0123456789abcdef0123456789abcdef01234567.torrent",
+                "max_size": 53687091200,
+                "seeding_time_limit": -2,
+                "ratio_limit": -2.0,
+                "error_info": null,
+                "uploader": { "type": "Rclone", "job": 12 },
+                "state": {
+                    "current_part_num": 1,
+                    "status": "Done",
+                    "is_seeding": false,
+                    "progress": 1.0,
+                    "done_at": "2024-05-02T08:00:00Z"
+                }
+            }
+        }
+    }"#;
+
+    /// v6: adds the time the task is added
+    const V6: &str = r#"{
+        "version": 6,
+        "tasks": {
+            "0123456789abcdef0123456789abcdef01234567": {
+                "hash": "0123456789abcdef0123456789abcdef01234567",
+                "name": "Show S01",
+                "instance": "disk2",
+                "hash_v2": "d8dd32ac93357c368556af3ac1d95c9d76bd0dff6fa9833ecdac3d53134efabb",
+                "category": "anime",
+                "added_at": "2024-05-01T08:00:00Z",
+                "save_path": "/downloads",
+                "root_dir": "Show S01",
+                "upload_path": "remote:/anime",
+                "total_part_num": 2,
+                "task_order": [[0, 1], [2]],
+                "file_num": 3,
+                "torrent_path": "/data/torrents/0123456789abcdef0123456789abcdef01234567.torrent",
+                "max_size": 53687091200,
+                "seeding_time_limit": -2,
+                "ratio_limit": -2.0,
+                "error_info": null,
+                "uploader": { "type": "Rclone", "job": 12 },
+                "state": {
+                    "current_part_num": 1,
+                    "status": "Done",
+                    "is_seeding": false,
+                    "progress": 1.0,
+                    "done_at": "2024-05-02T08:00:00Z"
+                }
+            }
+        }
+    }"#;
+
+    /// v7: adds the parts left to rerun
+    const V7: &str = r#"{
+        "version": 7,
+        "tasks": {
+            "0123456789abcdef0123456789abcdef01234567": {
+                "hash": "0123456789abcdef0123456789abcdef01234567",
+                "name": "Show S01",
+                "instance": "disk2",
+                "hash_v2": "d8dd32ac93357c368556af3ac1d95c9d76bd0dff6fa9833ecdac3d53134efabb",
+                "category": "anime",
+                "added_at": "2024-05-01T08:00:00Z",
+                "save_path": "/downloads",
+                "root_dir": "Show S01",
+                "upload_path": "remote:/anime",
+                "total_part_num": 2,
+                "task_order": [[0, 1], [2]],
+                "file_num": 3,
+                "torrent_path": "/data/torrents/0123456789abcdef0123456789abcdef01234567.torrent",
+                "max_size": 53687091200,
+                "seeding_time_limit": -2,
+                "ratio_limit": -2.0,
+                "error_info": null,
+                "uploader": { "type": "Rclone", "job": 12 },
+                "state": {
+                    "current_part_num": 1,
+                    "status": "Downloading",
+                    "is_seeding": false,
+                    "progress": 1.0,
+                    "done_at": null,
+                    "rerun_parts": [1]
+                }
+            }
+        }
+    }"#;
+
+    /// v8: adds the status before paused
+    const V8: &str = r#"{
+        "version": 8,
+        "tasks": {
+            "0123456789abcdef0123456789abcdef01234567": {
+                "hash": "0123456789abcdef0123456789abcdef01234567",
+                "name": "Show S01",
+                "instance": "disk2",
+                "hash_v2": "d8dd32ac93357c368556af3ac1d95c9d76bd0dff6fa9833ecdac3d53134efabb",
+                "category": "anime",
+                "added_at": "2024-05-01T08:00:00Z",
+                "save_path": "/downloads",
+                "root_dir": "Show S01",
+                "upload_path": "remote:/anime",
+                "total_part_num": 2,
+                "task_order": [[0, 1], [2]],
+                "file_num": 3,
+                "torrent_path": "/data/torrents/0123456789abcdef0123456789abcdef01234567.torrent",
+                "max_size": 53687091200,
+                "seeding_time_limit": -2,
+                "ratio_limit": -2.0,
+                "error_info": null,
+                "uploader": { "type": "Rclone", "job": 12 },
+                "state": {
+                    "current_part_num": 1,
+                    "status": "Paused",
+                    "is_seeding": false,
+                    "progress": 1.0,
+                    "done_at": null,
+                    "rerun_parts": [1],
+                    "paused_status": "Finished"
+                }
+            }
+        }
+    }"#;
+
+    /// v9: marks the imported tasks, the current version
+    const V9: &str = r#"{
+        "version": 9,
+        "tasks": {
+            "0123456789abcdef0123456789abcdef01234567": {
+                "hash": "0123456789abcdef0123456789abcdef01234567",
+                "name": "Show S01",
+                "instance": "disk2",
+                "hash_v2": "d8dd32ac93357c368556af3ac1d95c9d76bd0dff6fa9833ecdac3d53134efabb",
+                "category": "anime",
+                "added_at": "2024-05-01T08:00:00Z",
+                "adopted": true,
+                "save_path": "/downloads",
+                "root_dir": "Show S01",
+                "upload_path": "remote:/anime",
+                "total_part_num": 2,
+                "task_order": [[0, 1], [2]],
+                "file_num": 3,
+                "torrent_path": "/data/torrents/0123456789abcdef0123456789abcdef01234567.torrent",
+                "max_size": 53687091200,
+                "seeding_time_limit": -2,
+                "ratio_limit": -2.0,
+                "error_info": null,
+                "uploader": { "type": "Rclone", "job": 12 },
+                "state": {
+                    "current_part_num": 1,
+                    "status": "Paused",
+                    "is_seeding": false,
+                    "progress": 1.0,
+                    "done_at": null,
+                    "rerun_parts": [1],
+                    "paused_status": "Finished"
+                }
+            }
+        }
+    }"#;
+
+    const HASH: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    fn assert_current(tasks: &TaskMap) {
+        let task = tasks.get(HASH).expect("task missing");
+        assert_eq!(task.name, "Show S01");
+        assert_eq!(task.instance, DEFAULT_INSTANCE);
+        assert_eq!(task.hash_v2, None);
+        assert_eq!(task.category, "");
+        assert_eq!(task.added_at, None);
+        assert!(!task.adopted);
+        assert_eq!(task.task_order, vec![vec![0, 1], vec![2]]);
+        assert_eq!(task.total_part_num, 2);
+        let state = task.state();
+        assert_eq!(state.current_part_num, 1);
+        assert_eq!(state.status, Status::OnTask);
+        assert_eq!(state.done_at, None);
+        assert_eq!(state.rerun_parts, None);
+        assert_eq!(state.paused_status, None);
+    }
+
+    fn parse_str(s: &str) -> Result<TaskMap, serde_json::Error> {
+        parse(serde_json::from_str(s).unwrap())
+    }
+
+    #[test]
+    fn parse_v0() {
+        assert_current(&parse_str(V0).unwrap());
+    }
+
+    #[test]
+    fn parse_v1() {
+        assert_current(&parse_str(&v1()).unwrap());
+    }
+
+    const HASH_V2: &str = "d8dd32ac93357c368556af3ac1d95c9d76bd0dff6fa9833ecdac3d53134efabb";
+
+    fn task(content: &str) -> Arc<TaskValue> {
+        parse_str(content)
+            .unwrap()
+            .remove(HASH)
+            .expect("task missing")
+    }
+
+    #[test]
+    fn parse_v2() {
+        let task = task(V2);
+        assert_eq!(task.instance, "disk2");
+        assert_eq!(task.hash_v2, None);
+        assert_eq!(task.category, "");
+        assert_eq!(task.state().status, Status::OnTask);
+    }
+
+    #[test]
+    fn parse_v3() {
+        let task = task(V3);
+        assert_eq!(task.instance, "disk2");
+        assert_eq!(task.hash_v2.as_deref(), Some(HASH_V2));
+        assert_eq!(task.category, "");
+    }
+
+    #[test]
+    fn parse_v4() {
+        let task = task(V4);
+        assert_eq!(task.hash_v2.as_deref(), Some(HASH_V2));
+        assert_eq!(task.category, "anime");
+        assert_eq!(task.added_at, None);
+        let state = task.state();
+        assert_eq!(state.status, Status::Done);
+        assert_eq!(state.done_at, None);
+    }
+
+    #[test]
+    fn parse_v5() {
+        let task = task(V5);
+        assert_eq!(task.category, "anime");
+        assert_eq!(task.added_at, None);
+        assert_eq!(
+            task.state().done_at.as_deref(),
+            Some("2024-05-02T08:00:00Z")
+        );
+    }
+
+    #[test]
+    fn parse_v6() {
+        let task = task(V6);
+        assert_eq!(task.added_at.as_deref(), Some("2024-05-01T08:00:00Z"));
+        let state = task.state();
+        assert_eq!(state.done_at.as_deref(), Some("2024-05-02T08:00:00Z"));
+        assert_eq!(state.rerun_parts, None);
+    }
+
+    #[test]
+    fn parse_v7() {
+        let task = task(V7);
+        let state = task.state();
+        assert_eq!(state.status, Status::Downloading);
+        assert_eq!(state.rerun_parts, Some(vec![1]));
+        assert_eq!(state.paused_status, None);
+    }
+
+    #[test]
+    fn parse_v8() {
+        let task = task(V8);
+        assert_eq!(task.instance, "disk2");
+        assert_eq!(task.hash_v2.as_deref(), Some(HASH_V2));
+        assert_eq!(task.category, "anime");
+        assert_eq!(task.added_at.as_deref(), Some("2024-05-01T08:00:00Z"));
+        let state = task.state();
+        assert_eq!(state.status, Status::Paused);
+        assert_eq!(state.rerun_parts, Some(vec![1]));
+        assert_eq!(state.paused_status, Some(Status::Finished));
+        assert!(!task.adopted);
+    }
+
+    #[test]
+    fn parse_v9() {
+        let task = task(V9);
+        assert!(task.adopted);
+        assert_eq!(task.category, "anime");
+        assert_eq!(task.state().paused_status, Some(Status::Finished));
+    }
+
+    #[test]
+    fn parse_empty() {
+        assert!(parse_str("{}").unwrap().is_empty());
+        assert!(
+            parse_str(r#"{ "version": 1, "tasks": {} }"#)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let tasks = parse_str(V0).unwrap();
+        let content = serde_json::to_string(&TaskFile::new(&tasks)).unwrap();
+        let value: Value = serde_json::from_str(&content).unwrap();
+        assert_eq!(value["version"], TASK_FILE_VERSION);
+        assert_current(&parse(value).unwrap());
+    }
+
+    #[test]
+    fn reject_future_version() {
+        let content = format!(
+            r#"{{ "version": {}, "tasks": {{}} }}"#,
+            TASK_FILE_VERSION + 1
+        );
+        assert!(parse_str(&content).is_err());
+    }
+
+    #[test]
+    fn reject_unknown_shape() {
+        assert!(parse_str("[]").is_err());
+        assert!(parse_str(r#"{ "version": "1", "tasks": {} }"#).is_err());
+        assert!(parse_str(r#"{ "version": 1 }"#).is_err());
+    }
+}
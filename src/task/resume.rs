@@ -35,7 +35,7 @@ pub(super) async fn resume_from_error(
         // user needs to fix qbittorrent download issue, probably network, storage, etc.
         // TODO: require test
         Download => {
-            let _ = qb::delete(&task.hash, false)
+            let _ = qb::delete(&task.instance, &task.hash, false)
                 .await;
             let current_part_num = task.state().current_part_num;
             add_part(current_part_num, task).await
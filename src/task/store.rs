@@ -0,0 +1,1029 @@
+//! task storage backends
+//!
+//! [`JsonStore`] is the default, storing the task list in `tasks.json`
+//! and the archive in `archive.jsonl`.
+//! [`SqliteStore`] is available with the `sqlite` feature,
+//! which additionally records part history and error records.
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use directories_next::BaseDirs;
+use log::{error, warn};
+use tokio::task::spawn_blocking;
+
+use super::{
+    TaskMap,
+    archive::ArchivedTask,
+    migration::{self, TaskFile},
+};
+use crate::{
+    errors::{CommonError, TargetContextedResult, format_error_chain},
+    persist,
+};
+
+const TASK_FILE_NAME: &str = "tasks.json";
+const ARCHIVE_FILE_NAME: &str = "archive.jsonl";
+/// number of rotating backups of the task file
+const TASK_BACKUP_NUM: usize = 3;
+/// the task file is backed up on the first save of the process, then at most once per interval
+const TASK_BACKUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// storage backend kind, chosen by `--task-store`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StoreKind {
+    #[default]
+    Json,
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+}
+
+impl std::str::FromStr for StoreKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(StoreKind::Json),
+            #[cfg(feature = "sqlite")]
+            "sqlite" => Ok(StoreKind::Sqlite),
+            #[cfg(not(feature = "sqlite"))]
+            "sqlite" => Err("qb-downloader is built without the sqlite feature".into()),
+            _ => Err(format!("unknown task store: {s}")),
+        }
+    }
+}
+
+/// part event recorded in history
+#[derive(Debug, Clone, Copy)]
+pub enum PartEvent {
+    Downloaded,
+    Uploaded,
+}
+
+impl PartEvent {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            PartEvent::Downloaded => "downloaded",
+            PartEvent::Uploaded => "uploaded",
+        }
+    }
+}
+
+pub trait TaskStore {
+    /// load the task list, empty if nothing has been stored yet
+    fn load(&self) -> Result<TaskMap, CommonError>;
+
+    /// store the whole task list
+    fn save(&self, tasks: &TaskMap) -> impl Future<Output = Result<(), CommonError>>;
+
+    /// record a part event of a task
+    fn record_part(&self, hash: &str, part: usize, event: PartEvent);
+
+    /// record a runtime error of a task
+    fn record_error(&self, hash: &str, part: usize, kind: &str, message: &str);
+
+    /// append a done task to the archive
+    fn archive(&self, task: &ArchivedTask) -> impl Future<Output = Result<(), CommonError>>;
+
+    /// archived tasks matching `search`, newest first, with the total count of matches
+    fn archived(
+        &self,
+        search: &str,
+        offset: usize,
+        limit: usize,
+    ) -> impl Future<Output = Result<(usize, Vec<ArchivedTask>), CommonError>>;
+
+    /// the latest archived task of the hash
+    fn find_archived(
+        &self,
+        hash: &str,
+    ) -> impl Future<Output = Result<Option<ArchivedTask>, CommonError>>;
+}
+
+#[derive(Debug)]
+pub enum Store {
+    Json(JsonStore),
+    #[cfg(feature = "sqlite")]
+    Sqlite(SqliteStore),
+}
+
+impl Store {
+    /// open the store of `kind`, located at `path` or the default data directory
+    pub fn open(kind: StoreKind, path: Option<PathBuf>) -> Result<Self, CommonError> {
+        let default_path = |file_name: &str| {
+            BaseDirs::new()
+                .expect("Failed to get data dir")
+                .data_dir()
+                .join("qb-downloader")
+                .join(file_name)
+        };
+        match kind {
+            StoreKind::Json => {
+                let filepath = path.unwrap_or_else(|| default_path(TASK_FILE_NAME));
+                Ok(Store::Json(JsonStore::new(filepath)))
+            }
+            #[cfg(feature = "sqlite")]
+            StoreKind::Sqlite => Ok(Store::Sqlite(SqliteStore::open(
+                path.unwrap_or_else(|| default_path(sqlite::TASK_DB_NAME)),
+            )?)),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        match self {
+            Store::Json(s) => &s.filepath,
+            #[cfg(feature = "sqlite")]
+            Store::Sqlite(s) => &s.filepath,
+        }
+    }
+}
+
+impl TaskStore for Store {
+    fn load(&self) -> Result<TaskMap, CommonError> {
+        match self {
+            Store::Json(s) => s.load(),
+            #[cfg(feature = "sqlite")]
+            Store::Sqlite(s) => s.load(),
+        }
+    }
+
+    async fn save(&self, tasks: &TaskMap) -> Result<(), CommonError> {
+        match self {
+            Store::Json(s) => s.save(tasks).await,
+            #[cfg(feature = "sqlite")]
+            Store::Sqlite(s) => s.save(tasks).await,
+        }
+    }
+
+    fn record_part(&self, hash: &str, part: usize, event: PartEvent) {
+        match self {
+            Store::Json(s) => s.record_part(hash, part, event),
+            #[cfg(feature = "sqlite")]
+            Store::Sqlite(s) => s.record_part(hash, part, event),
+        }
+    }
+
+    fn record_error(&self, hash: &str, part: usize, kind: &str, message: &str) {
+        match self {
+            Store::Json(s) => s.record_error(hash, part, kind, message),
+            #[cfg(feature = "sqlite")]
+            Store::Sqlite(s) => s.record_error(hash, part, kind, message),
+        }
+    }
+
+    async fn archive(&self, task: &ArchivedTask) -> Result<(), CommonError> {
+        match self {
+            Store::Json(s) => s.archive(task).await,
+            #[cfg(feature = "sqlite")]
+            Store::Sqlite(s) => s.archive(task).await,
+        }
+    }
+
+    async fn archived(
+        &self,
+        search: &str,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(usize, Vec<ArchivedTask>), CommonError> {
+        match self {
+            Store::Json(s) => s.archived(search, offset, limit).await,
+            #[cfg(feature = "sqlite")]
+            Store::Sqlite(s) => s.archived(search, offset, limit).await,
+        }
+    }
+
+    async fn find_archived(&self, hash: &str) -> Result<Option<ArchivedTask>, CommonError> {
+        match self {
+            Store::Json(s) => s.find_archived(hash).await,
+            #[cfg(feature = "sqlite")]
+            Store::Sqlite(s) => s.find_archived(hash).await,
+        }
+    }
+}
+
+/// store the task list as a versioned json file, see [`migration`],
+/// and the archive as a json line per task.
+/// The archive is only appended to, so [`TaskStore::archived`] and [`TaskStore::find_archived`]
+/// read the whole file on each call.
+#[derive(Debug)]
+pub struct JsonStore {
+    filepath: PathBuf,
+    archive_path: PathBuf,
+    /// when the task file was last backed up, None before the first save
+    last_backup: Mutex<Option<Instant>>,
+    /// the task file failed to parse on load, so it must not be rotated into the backups
+    corrupt: AtomicBool,
+}
+
+impl JsonStore {
+    fn new(filepath: PathBuf) -> Self {
+        Self {
+            archive_path: filepath.with_file_name(ARCHIVE_FILE_NAME),
+            filepath,
+            last_backup: Mutex::new(None),
+            corrupt: AtomicBool::new(false),
+        }
+    }
+
+    /// whether the task file should be backed up before this save
+    fn backup_due(&self) -> bool {
+        if self.corrupt.load(Ordering::Relaxed) {
+            return false;
+        }
+        let mut last_backup = self.last_backup.lock().unwrap();
+        if last_backup.is_some_and(|last| last.elapsed() < TASK_BACKUP_INTERVAL) {
+            return false;
+        }
+        *last_backup = Some(Instant::now());
+        true
+    }
+
+    fn read(path: &Path) -> Result<TaskMap, CommonError> {
+        let task_file = std::fs::File::open(path)
+            .convert_then_add_context(format!("Failed to open task file: {}", path.display()))?;
+        let reader = std::io::BufReader::new(task_file);
+        let task_map = serde_json::from_reader(reader)
+            .and_then(migration::parse)
+            .convert_then_add_context("Failed to parse task file")?;
+        Ok(task_map)
+    }
+
+    /// all archived tasks, oldest first, invalid lines are skipped
+    async fn read_archive(&self) -> Result<Vec<ArchivedTask>, CommonError> {
+        let contents = match tokio::fs::read_to_string(&self.archive_path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).convert_then_add_context("Failed to read archive file"),
+        };
+        Ok(contents
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| match serde_json::from_str(line) {
+                Ok(task) => Some(task),
+                Err(e) => {
+                    warn!("Skip invalid archived task: {e}");
+                    None
+                }
+            })
+            .collect())
+    }
+}
+
+impl TaskStore for JsonStore {
+    /// load task list from the task file, fall back to the newest valid backup if failed
+    fn load(&self) -> Result<TaskMap, CommonError> {
+        let path = &self.filepath;
+        if !path.exists() {
+            return Ok(TaskMap::new());
+        }
+        match Self::read(path) {
+            Ok(task_map) => Ok(task_map),
+            Err(e) => {
+                error!("Failed to load task file\n{}", format_error_chain(&e));
+                self.corrupt.store(true, Ordering::Relaxed);
+                persist::backup_paths(path, TASK_BACKUP_NUM)
+                    .iter()
+                    .filter(|backup| backup.exists())
+                    .find_map(|backup| {
+                        let task_map = Self::read(backup).ok()?;
+                        warn!("Task list recovered from backup: {}", backup.display());
+                        Some(task_map)
+                    })
+                    .ok_or(e)
+            }
+        }
+    }
+
+    async fn save(&self, tasks: &TaskMap) -> Result<(), CommonError> {
+        let contents = serde_json::to_vec(&TaskFile::new(tasks))
+            .convert_then_add_context("Failed to serialize task list")?;
+        // saves are serialized by `task::save`, so backups are never rotated concurrently
+        if self.backup_due() {
+            persist::rotate_backups(&self.filepath, TASK_BACKUP_NUM).await?;
+        }
+        persist::write(&self.filepath, contents).await?;
+        self.corrupt.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// history is not kept in json store
+    fn record_part(&self, _: &str, _: usize, _: PartEvent) {}
+
+    fn record_error(&self, _: &str, _: usize, _: &str, _: &str) {}
+
+    async fn archive(&self, task: &ArchivedTask) -> Result<(), CommonError> {
+        let mut line = serde_json::to_vec(task)
+            .convert_then_add_context("Failed to serialize archived task")?;
+        line.push(b'\n');
+        let path = self.archive_path.clone();
+        spawn_blocking(move || {
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?;
+            file.write_all(&line)?;
+            file.sync_data()
+        })
+        .await
+        .expect("archive writer panicked")
+        .convert_then_add_context("Failed to write archive file")
+    }
+
+    async fn archived(
+        &self,
+        search: &str,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(usize, Vec<ArchivedTask>), CommonError> {
+        let matched: Vec<ArchivedTask> = self
+            .read_archive()
+            .await?
+            .into_iter()
+            .rev()
+            .filter(|task| task.matches(search))
+            .collect();
+        let total = matched.len();
+        Ok((
+            total,
+            matched.into_iter().skip(offset).take(limit).collect(),
+        ))
+    }
+
+    async fn find_archived(&self, hash: &str) -> Result<Option<ArchivedTask>, CommonError> {
+        Ok(self
+            .read_archive()
+            .await?
+            .into_iter()
+            .rev()
+            .find(|task| task.hash == hash))
+    }
+}
+
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStore;
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use std::{
+        collections::HashMap,
+        path::PathBuf,
+        sync::{Arc, Mutex},
+    };
+
+    use log::{error, info};
+    use rusqlite::{Connection, OptionalExtension, params};
+    use serde_json::Value;
+    use tokio::task::{JoinHandle, spawn_blocking};
+
+    use super::{JsonStore, PartEvent, TASK_FILE_NAME, TaskStore};
+    use crate::{
+        errors::{CommonError, TargetContextedResult, format_error_chain},
+        task::{
+            TaskMap,
+            archive::ArchivedTask,
+            migration::{self, TASK_FILE_VERSION, upgrade_task},
+        },
+    };
+
+    pub(super) const TASK_DB_NAME: &str = "tasks.db";
+
+    const SCHEMA: &str = "
+        CREATE TABLE IF NOT EXISTS meta (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS tasks (
+            hash TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS part_history (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            hash TEXT NOT NULL,
+            part INTEGER NOT NULL,
+            event TEXT NOT NULL,
+            timestamp TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS error_records (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            hash TEXT NOT NULL,
+            part INTEGER NOT NULL,
+            kind TEXT NOT NULL,
+            message TEXT NOT NULL,
+            timestamp TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS archive (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            hash TEXT NOT NULL,
+            name TEXT NOT NULL,
+            value TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS part_history_hash ON part_history (hash);
+        CREATE INDEX IF NOT EXISTS error_records_hash ON error_records (hash);
+        CREATE INDEX IF NOT EXISTS archive_hash ON archive (hash);
+    ";
+
+    /// store the task list, part history and error records in a sqlite database,
+    /// each task is stored as a versioned json value
+    ///
+    /// The task list of the json store next to the database is imported on first open.
+    #[derive(Debug)]
+    pub struct SqliteStore {
+        pub(super) filepath: PathBuf,
+        conn: Arc<Mutex<Connection>>,
+        /// tasks as last loaded or saved, only the changed rows are written on save,
+        /// locked after `conn`
+        saved: Arc<Mutex<HashMap<String, String>>>,
+    }
+
+    impl SqliteStore {
+        pub(super) fn open(filepath: PathBuf) -> Result<Self, CommonError> {
+            if let Some(parent) = filepath.parent() {
+                std::fs::create_dir_all(parent)
+                    .convert_then_add_context("Failed to create task database directory")?;
+            }
+            let conn = Connection::open(&filepath).convert_then_with_context(|| {
+                format!("Failed to open task database: {}", filepath.display()).into()
+            })?;
+            Self::with_connection(filepath, conn)
+        }
+
+        fn with_connection(filepath: PathBuf, conn: Connection) -> Result<Self, CommonError> {
+            conn.execute_batch(SCHEMA)
+                .convert_then_add_context("Failed to create task database schema")?;
+            Ok(Self {
+                filepath,
+                conn: Arc::new(Mutex::new(conn)),
+                saved: Arc::default(),
+            })
+        }
+
+        /// the task list of the json store next to the database, for a database never saved
+        fn import_json(&self) -> Result<TaskMap, CommonError> {
+            let json_path = self.filepath.with_file_name(TASK_FILE_NAME);
+            let task_map = JsonStore::new(json_path.clone()).load()?;
+            if !task_map.is_empty() {
+                info!(
+                    "Imported {} tasks from {}",
+                    task_map.len(),
+                    json_path.display()
+                );
+            }
+            Ok(task_map)
+        }
+
+        /// run a write in background, errors are logged only
+        fn execute_background(
+            &self,
+            sql: &'static str,
+            params: Vec<rusqlite::types::Value>,
+        ) -> JoinHandle<()> {
+            let conn = self.conn.clone();
+            spawn_blocking(move || {
+                let conn = conn.lock().unwrap();
+                if let Err(e) = conn.execute(sql, rusqlite::params_from_iter(params)) {
+                    error!("Failed to write task history\n{}", format_error_chain(e));
+                }
+            })
+        }
+
+        /// [`TaskStore::record_part`], returning the handle of the background write
+        fn write_part_record(&self, hash: &str, part: usize, event: PartEvent) -> JoinHandle<()> {
+            self.execute_background(
+                "INSERT INTO part_history (hash, part, event, timestamp) VALUES (?1, ?2, ?3, ?4)",
+                vec![
+                    hash.to_string().into(),
+                    (part as i64).into(),
+                    event.as_str().to_string().into(),
+                    now().into(),
+                ],
+            )
+        }
+
+        /// [`TaskStore::record_error`], returning the handle of the background write
+        fn write_error_record(
+            &self,
+            hash: &str,
+            part: usize,
+            kind: &str,
+            message: &str,
+        ) -> JoinHandle<()> {
+            self.execute_background(
+                "INSERT INTO error_records (hash, part, kind, message, timestamp) VALUES (?1, ?2, ?3, ?4, ?5)",
+                vec![
+                    hash.to_string().into(),
+                    (part as i64).into(),
+                    kind.to_string().into(),
+                    message.to_string().into(),
+                    now().into(),
+                ],
+            )
+        }
+    }
+
+    /// LIKE pattern of the name containing `search`
+    fn like_pattern(search: &str) -> String {
+        let escaped = search
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+        format!("%{escaped}%")
+    }
+
+    fn parse_archived(value: &str) -> rusqlite::Result<ArchivedTask> {
+        serde_json::from_str(value).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, e.into())
+        })
+    }
+
+    fn now() -> String {
+        humantime::format_rfc3339(std::time::SystemTime::now()).to_string()
+    }
+
+    impl TaskStore for SqliteStore {
+        fn load(&self) -> Result<TaskMap, CommonError> {
+            let conn = self.conn.lock().unwrap();
+            let Some(version) = conn
+                .query_row("SELECT value FROM meta WHERE key = 'version'", [], |row| {
+                    row.get::<_, String>(0)
+                })
+                .optional()
+                .convert_then_add_context("Failed to read task database version")?
+            else {
+                drop(conn);
+                return self.import_json();
+            };
+            let version = migration::parse_version(&version)
+                .convert_then_add_context("Failed to read task database version")?;
+
+            let mut stmt = conn
+                .prepare("SELECT hash, value FROM tasks")
+                .convert_then_add_context("Failed to read tasks")?;
+            let rows = stmt
+                .query_map([], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+                })
+                .convert_then_add_context("Failed to read tasks")?;
+
+            let mut task_map = TaskMap::new();
+            let mut saved = HashMap::new();
+            for row in rows {
+                let (hash, value) = row.convert_then_add_context("Failed to read task row")?;
+                let task = serde_json::from_str::<Value>(&value)
+                    .and_then(|mut task| {
+                        upgrade_task(&mut task, version)?;
+                        serde_json::from_value(task)
+                    })
+                    .convert_then_with_context(|| format!("Failed to parse task: {hash}").into())?;
+                task_map.insert(hash.clone(), task);
+                saved.insert(hash, value);
+            }
+            *self.saved.lock().unwrap() = saved;
+            Ok(task_map)
+        }
+
+        /// upsert the changed tasks and delete the removed ones in a transaction
+        async fn save(&self, tasks: &TaskMap) -> Result<(), CommonError> {
+            let rows = tasks
+                .iter()
+                .map(|(hash, task)| Ok((hash.clone(), serde_json::to_string(task)?)))
+                .collect::<Result<HashMap<_, _>, serde_json::Error>>()
+                .convert_then_add_context("Failed to serialize task list")?;
+            let conn = self.conn.clone();
+            let saved = self.saved.clone();
+            spawn_blocking(move || {
+                let mut conn = conn.lock().unwrap();
+                let mut saved = saved.lock().unwrap();
+                let tx = conn.transaction()?;
+                {
+                    let mut upsert = tx.prepare(
+                        "INSERT INTO tasks (hash, value) VALUES (?1, ?2)
+                         ON CONFLICT (hash) DO UPDATE SET value = excluded.value",
+                    )?;
+                    for (hash, value) in &rows {
+                        if saved.get(hash) != Some(value) {
+                            upsert.execute(params![hash, value])?;
+                        }
+                    }
+                    let mut delete = tx.prepare("DELETE FROM tasks WHERE hash = ?1")?;
+                    for hash in saved.keys().filter(|hash| !rows.contains_key(*hash)) {
+                        delete.execute(params![hash])?;
+                    }
+                }
+                tx.execute(
+                    "INSERT OR REPLACE INTO meta (key, value) VALUES ('version', ?1)",
+                    params![TASK_FILE_VERSION.to_string()],
+                )?;
+                tx.commit()?;
+                *saved = rows;
+                Ok::<_, rusqlite::Error>(())
+            })
+            .await
+            .expect("task database writer panicked")
+            .convert_then_add_context("Failed to write task database")
+        }
+
+        fn record_part(&self, hash: &str, part: usize, event: PartEvent) {
+            self.write_part_record(hash, part, event);
+        }
+
+        fn record_error(&self, hash: &str, part: usize, kind: &str, message: &str) {
+            self.write_error_record(hash, part, kind, message);
+        }
+
+        async fn archive(&self, task: &ArchivedTask) -> Result<(), CommonError> {
+            let value = serde_json::to_string(task)
+                .convert_then_add_context("Failed to serialize archived task")?;
+            let (hash, name) = (task.hash.clone(), task.name.clone());
+            let conn = self.conn.clone();
+            spawn_blocking(move || {
+                conn.lock().unwrap().execute(
+                    "INSERT INTO archive (hash, name, value) VALUES (?1, ?2, ?3)",
+                    params![hash, name, value],
+                )
+            })
+            .await
+            .expect("task database writer panicked")
+            .convert_then_add_context("Failed to write archived task")?;
+            Ok(())
+        }
+
+        async fn archived(
+            &self,
+            search: &str,
+            offset: usize,
+            limit: usize,
+        ) -> Result<(usize, Vec<ArchivedTask>), CommonError> {
+            const FILTER: &str = "?1 = '' OR name LIKE ?2 ESCAPE '\\' OR hash = lower(?1)";
+            let search = search.to_string();
+            let conn = self.conn.clone();
+            spawn_blocking(move || {
+                let conn = conn.lock().unwrap();
+                let pattern = like_pattern(&search);
+                let total: i64 = conn.query_row(
+                    &format!("SELECT count(*) FROM archive WHERE {FILTER}"),
+                    params![search, pattern],
+                    |row| row.get(0),
+                )?;
+                let mut stmt = conn.prepare(&format!(
+                    "SELECT value FROM archive WHERE {FILTER} ORDER BY id DESC LIMIT ?3 OFFSET ?4"
+                ))?;
+                let tasks = stmt
+                    .query_map(
+                        params![search, pattern, limit as i64, offset as i64],
+                        |row| parse_archived(&row.get::<_, String>(0)?),
+                    )?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                Ok::<_, rusqlite::Error>((total as usize, tasks))
+            })
+            .await
+            .expect("task database reader panicked")
+            .convert_then_add_context("Failed to read archive")
+        }
+
+        async fn find_archived(&self, hash: &str) -> Result<Option<ArchivedTask>, CommonError> {
+            let hash = hash.to_string();
+            let conn = self.conn.clone();
+            spawn_blocking(move || {
+                conn.lock()
+                    .unwrap()
+                    .query_row(
+                        "SELECT value FROM archive WHERE hash = ?1 ORDER BY id DESC LIMIT 1",
+                        params![hash],
+                        |row| parse_archived(&row.get::<_, String>(0)?),
+                    )
+                    .optional()
+            })
+            .await
+            .expect("task database reader panicked")
+            .convert_then_add_context("Failed to read archive")
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::{
+            task::{Status, migration},
+            upload::UploadType,
+        };
+
+        const HASH_A: &str = "0123456789abcdef0123456789abcdef01234567";
+        const HASH_B: &str = "89abcdef0123456789abcdef0123456789abcdef";
+
+        fn task_json(hash: &str) -> String {
+            format!(
+                r#"{{
+                    "hash": "{hash}",
+                    "name": "Show {hash}",
+                    "save_path": "/downloads",
+                    "root_dir": "Show",
+                    "upload_path": "remote:/anime",
+                    "total_part_num": 2,
+                    "task_order": [[0, 1], [2]],
+                    "file_num": 3,
+                    "torrent_path": "/data/torrents/{hash}.torrent",
+                    "max_size": 53687091200,
+                    "seeding_time_limit": -2,
+                    "ratio_limit": -2.0,
+                    "error_info": null,
+                    "uploader": {{ "type": "Rclone", "job": null }},
+                    "state": {{
+                        "current_part_num": 0,
+                        "status": "Paused",
+                        "is_seeding": false,
+                        "progress": 0.0
+                    }}
+                }}"#
+            )
+        }
+
+        fn task_map(hashes: &[&str]) -> TaskMap {
+            let tasks = hashes
+                .iter()
+                .map(|hash| format!(r#""{hash}": {}"#, task_json(hash)))
+                .collect::<Vec<_>>()
+                .join(",");
+            migration::parse(serde_json::from_str(&format!("{{{tasks}}}")).unwrap()).unwrap()
+        }
+
+        fn archived(hash: &str, name: &str) -> ArchivedTask {
+            ArchivedTask {
+                hash: hash.to_string(),
+                instance: "default".to_string(),
+                name: name.to_string(),
+                category: String::new(),
+                save_path: "/downloads".to_string(),
+                upload_path: "remote:/anime".to_string(),
+                destination: format!("remote:/anime/{name}"),
+                upload_type: UploadType::Rclone,
+                total_size: Some(1024),
+                total_part_num: 1,
+                task_order: vec![vec![0]],
+                max_size: 1024,
+                seeding_time_limit: -2,
+                ratio_limit: -2.0,
+                added_at: None,
+                done_at: None,
+                duration: None,
+            }
+        }
+
+        /// a store on an in-memory database, with no task file next to it
+        fn memory_store() -> SqliteStore {
+            let filepath = std::env::temp_dir()
+                .join(format!("qb-downloader-sqlite-{}", std::process::id()))
+                .join(TASK_DB_NAME);
+            SqliteStore::with_connection(filepath, Connection::open_in_memory().unwrap()).unwrap()
+        }
+
+        fn runtime() -> tokio::runtime::Runtime {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+        }
+
+        fn count(store: &SqliteStore, table: &str) -> i64 {
+            store
+                .conn
+                .lock()
+                .unwrap()
+                .query_row(&format!("SELECT count(*) FROM {table}"), [], |row| {
+                    row.get(0)
+                })
+                .unwrap()
+        }
+
+        #[test]
+        fn save_load_round_trip() {
+            let store = memory_store();
+            assert!(store.load().unwrap().is_empty());
+
+            let tasks = task_map(&[HASH_A, HASH_B]);
+            tasks[HASH_A].state.write().unwrap().current_part_num = 1;
+            runtime().block_on(store.save(&tasks)).unwrap();
+            let loaded = store.load().unwrap();
+            assert_eq!(loaded.len(), 2);
+            assert_eq!(loaded[HASH_A].state().current_part_num, 1);
+            assert_eq!(loaded[HASH_B].state().status, Status::Paused);
+
+            runtime()
+                .block_on(store.save(&task_map(&[HASH_B])))
+                .unwrap();
+            let loaded = store.load().unwrap();
+            assert_eq!(loaded.keys().collect::<Vec<_>>(), vec![HASH_B]);
+            assert_eq!(loaded[HASH_B].state().current_part_num, 0);
+        }
+
+        #[test]
+        fn reject_unknown_version() {
+            let next = (TASK_FILE_VERSION + 1).to_string();
+            for version in [next.as_str(), "abc"] {
+                let store = memory_store();
+                runtime()
+                    .block_on(store.save(&task_map(&[HASH_A])))
+                    .unwrap();
+                store
+                    .conn
+                    .lock()
+                    .unwrap()
+                    .execute(
+                        "UPDATE meta SET value = ?1 WHERE key = 'version'",
+                        params![version],
+                    )
+                    .unwrap();
+                assert!(store.load().is_err(), "version {version}");
+            }
+        }
+
+        #[test]
+        fn records() {
+            let store = memory_store();
+            runtime().block_on(async {
+                store
+                    .write_part_record(HASH_A, 0, PartEvent::Downloaded)
+                    .await
+                    .unwrap();
+                store
+                    .write_part_record(HASH_A, 0, PartEvent::Uploaded)
+                    .await
+                    .unwrap();
+                store
+                    .write_error_record(HASH_A, 1, "upload", "rclone failed")
+                    .await
+                    .unwrap();
+            });
+            assert_eq!(count(&store, "part_history"), 2);
+            let (kind, message): (String, String) = store
+                .conn
+                .lock()
+                .unwrap()
+                .query_row(
+                    "SELECT kind, message FROM error_records WHERE hash = ?1 AND part = 1",
+                    params![HASH_A],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .unwrap();
+            assert_eq!(
+                (kind.as_str(), message.as_str()),
+                ("upload", "rclone failed")
+            );
+        }
+
+        #[test]
+        fn archive() {
+            let store = memory_store();
+            let rt = runtime();
+            rt.block_on(async {
+                store.archive(&archived(HASH_A, "Show 100%")).await.unwrap();
+                store.archive(&archived(HASH_B, "Movie")).await.unwrap();
+                store
+                    .archive(&archived(HASH_A, "Show 100% v2"))
+                    .await
+                    .unwrap();
+
+                let (total, tasks) = store.archived("", 0, 10).await.unwrap();
+                assert_eq!(total, 3);
+                assert_eq!(tasks[0].name, "Show 100% v2");
+
+                let (total, tasks) = store.archived("", 1, 1).await.unwrap();
+                assert_eq!((total, tasks[0].name.as_str()), (3, "Movie"));
+
+                let (total, _) = store.archived("show", 0, 10).await.unwrap();
+                assert_eq!(total, 2);
+                let (total, _) = store.archived("0%", 0, 10).await.unwrap();
+                assert_eq!(total, 2);
+                let (total, _) = store.archived("_", 0, 10).await.unwrap();
+                assert_eq!(total, 0);
+                let (total, tasks) = store.archived(&HASH_B.to_uppercase(), 0, 10).await.unwrap();
+                assert_eq!((total, tasks[0].name.as_str()), (1, "Movie"));
+
+                let found = store.find_archived(HASH_A).await.unwrap().unwrap();
+                assert_eq!(found.name, "Show 100% v2");
+                assert!(store.find_archived("missing").await.unwrap().is_none());
+            });
+            assert_eq!(count(&store, "archive"), 3);
+        }
+
+        #[test]
+        fn import_json_on_first_open() {
+            let dir =
+                std::env::temp_dir().join(format!("qb-downloader-import-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let tasks = format!(
+                r#"{{ "version": 1, "tasks": {{ "{HASH_A}": {} }} }}"#,
+                task_json(HASH_A)
+            );
+            std::fs::write(dir.join(TASK_FILE_NAME), tasks).unwrap();
+            let store = SqliteStore::with_connection(
+                dir.join(TASK_DB_NAME),
+                Connection::open_in_memory().unwrap(),
+            )
+            .unwrap();
+
+            let loaded = store.load().unwrap();
+            assert!(loaded.contains_key(HASH_A));
+            runtime().block_on(store.save(&loaded)).unwrap();
+            std::fs::remove_dir_all(&dir).unwrap();
+            // never imported again once saved
+            assert_eq!(store.load().unwrap().len(), 1);
+            runtime().block_on(store.save(&TaskMap::new())).unwrap();
+            assert!(store.load().unwrap().is_empty());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EMPTY_TASK_FILE: &str = r#"{ "version": 1, "tasks": {} }"#;
+    const CORRUPT_TASK_FILE: &str = "{ \"version\": 1, \"tas";
+
+    /// a json store in an empty directory, removed when dropped
+    struct TestStore {
+        dir: PathBuf,
+        store: JsonStore,
+    }
+
+    impl TestStore {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir()
+                .join(format!("qb-downloader-store-{}-{name}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            let store = JsonStore::new(dir.join(TASK_FILE_NAME));
+            Self { dir, store }
+        }
+
+        fn backup(&self, i: usize) -> PathBuf {
+            persist::backup_paths(&self.store.filepath, TASK_BACKUP_NUM)[i].clone()
+        }
+    }
+
+    impl Drop for TestStore {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn block_on<F: Future>(f: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(f)
+    }
+
+    #[test]
+    fn load_missing_file() {
+        let t = TestStore::new("missing");
+        assert!(t.store.load().unwrap().is_empty());
+    }
+
+    #[test]
+    fn load_falls_back_to_backup() {
+        let t = TestStore::new("fallback");
+        std::fs::write(&t.store.filepath, CORRUPT_TASK_FILE).unwrap();
+        assert!(t.store.load().is_err());
+
+        std::fs::write(t.backup(1), CORRUPT_TASK_FILE).unwrap();
+        std::fs::write(t.backup(2), EMPTY_TASK_FILE).unwrap();
+        assert!(t.store.load().unwrap().is_empty());
+    }
+
+    #[test]
+    fn save_backs_up_once_per_interval() {
+        let t = TestStore::new("interval");
+        std::fs::write(&t.store.filepath, EMPTY_TASK_FILE).unwrap();
+        t.store.load().unwrap();
+        block_on(t.store.save(&TaskMap::new())).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(t.backup(0)).unwrap(),
+            EMPTY_TASK_FILE
+        );
+        block_on(t.store.save(&TaskMap::new())).unwrap();
+        assert!(!t.backup(1).exists());
+    }
+
+    #[test]
+    fn save_does_not_back_up_corrupt_file() {
+        let t = TestStore::new("corrupt");
+        std::fs::write(&t.store.filepath, CORRUPT_TASK_FILE).unwrap();
+        std::fs::write(t.backup(0), EMPTY_TASK_FILE).unwrap();
+        assert!(t.store.load().unwrap().is_empty());
+
+        block_on(t.store.save(&TaskMap::new())).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(t.backup(0)).unwrap(),
+            EMPTY_TASK_FILE
+        );
+        assert!(!t.backup(1).exists());
+        assert!(JsonStore::read(&t.store.filepath).unwrap().is_empty());
+    }
+}
@@ -1,4 +1,5 @@
 //! deal with upload
+pub mod template;
 
 use std::{borrow::Cow, sync::Arc};
 
@@ -22,13 +23,43 @@ pub enum Uploader {
     Rclone(ArcSwap<Option<i32>>),
 }
 
+/// kind of [`Uploader`] without its state, used in task presets
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum UploadType {
+    Rclone,
+}
+
+impl UploadType {
+    /// a fresh uploader of this type
+    pub fn uploader(self) -> Uploader {
+        match self {
+            UploadType::Rclone => Uploader::Rclone(ArcSwap::from_pointee(None)),
+        }
+    }
+}
+
 pub trait UploaderTrait {
     fn upload(task: Arc<TaskValue>) -> impl Future<Output = Result<(), TaskError>>;
     fn check(task: Arc<TaskValue>) -> impl Future<Output = Result<bool, TaskError>>;
+    fn stop(task: Arc<TaskValue>) -> impl Future<Output = Result<(), TaskError>>;
     fn test(host: &str, username: &str, password: &str) -> impl Future<Output = bool>;
 }
 
+impl Clone for Uploader {
+    fn clone(&self) -> Self {
+        match self {
+            Uploader::Rclone(job) => Uploader::Rclone(ArcSwap::new(job.load_full())),
+        }
+    }
+}
+
 impl Uploader {
+    pub fn upload_type(&self) -> UploadType {
+        match self {
+            Uploader::Rclone(_) => UploadType::Rclone,
+        }
+    }
+
     /// Check if upload is completed
     pub async fn check(&self, task: Arc<TaskValue>) -> Result<bool, TaskError> {
         match self {
@@ -42,6 +73,13 @@ impl Uploader {
             Uploader::Rclone(_) => Rclone::upload(task.clone()).await,
         }
     }
+
+    /// Stop the running upload, which is submitted again on resume
+    pub async fn stop(&self, task: Arc<TaskValue>) -> Result<(), TaskError> {
+        match self {
+            Uploader::Rclone(_) => Rclone::stop(task.clone()).await,
+        }
+    }
 }
 
 pub struct Rclone;
@@ -53,9 +91,10 @@ impl UploaderTrait for Rclone {
         let host = &rclone_cfg.rclone_host;
         let username = &rclone_cfg.rclone_username;
         let password = &rclone_cfg.rclone_password;
+        let upload_path = template::upload_path(&task).map_err(|e| TaskError::Upload(Some(e)))?;
         let (src, dst) = (
             format!("{}/{}", task.save_path, task.root_dir),
-            format!("{}/{}", task.upload_path, task.root_dir),
+            format!("{}/{}", upload_path, task.root_dir),
         );
         let body = json!({
             "srcFs": src,
@@ -117,6 +156,18 @@ impl UploaderTrait for Rclone {
             .await
     }
 
+    /// stop the job if any, the copied files are skipped when uploaded again
+    async fn stop(task: Arc<TaskValue>) -> Result<(), TaskError> {
+        let job_id = {
+            let Uploader::Rclone(job_id_opt) = &task.uploader;
+            *job_id_opt.load_full()
+        };
+        let Some(job_id) = job_id else {
+            return Ok(());
+        };
+        Self::command("job/stop", json!({ "jobid": job_id })).await
+    }
+
     async fn test(host: &str, username: &str, password: &str) -> bool {
         let res = request::post(format!("{host}/core/version"))
             .basic_auth(username, password)
@@ -136,6 +187,25 @@ impl UploaderTrait for Rclone {
 }
 
 impl Rclone {
+    /// call the rc `command` with `params`
+    pub async fn command(command: &str, params: Value) -> Result<(), TaskError> {
+        let rclone_cfg = &config::value().rclone;
+        let host = &rclone_cfg.rclone_host;
+        let username = &rclone_cfg.rclone_username;
+        let password = &rclone_cfg.rclone_password;
+        request::post(format!("{host}/{}", command.trim_matches('/')))
+            .basic_auth(username, password)
+            .json(params)
+            .send_and_then(async |res| {
+                let value: Value = res.json().await.map_err(RequestError::from)?;
+                match Self::get_error_msg(&value) {
+                    Some(error_msg) => Err(TaskError::Upload(Some(error_msg))),
+                    None => Ok(()),
+                }
+            })
+            .await
+    }
+
     fn get_error_msg(value: &Value) -> Option<Cow<'static, str>> {
         value
             .get("error")
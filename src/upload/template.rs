@@ -0,0 +1,288 @@
+//! upload path templating
+//!
+//! `upload_path` may contain variables in braces, expanded when a part is uploaded:
+//! `{name}`, `{root_dir}`, `{hash}`, `{part}` (1-based), `{category}`,
+//! `{date}` or `{date:FORMAT}` in UTC, where FORMAT supports `%Y %m %d %H %M %S %%`,
+//! and the named groups of the first `general.upload_path_patterns` matching the torrent name.
+//! `{{` and `}}` are literal braces, so are the braces not around a variable name, e.g. `{ a }`.
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    sync::{LazyLock, Mutex},
+    time::SystemTime,
+};
+
+use regex::Regex;
+
+use crate::{config, task::TaskValue};
+
+type TemplateResult<T> = Result<T, Cow<'static, str>>;
+
+/// variables of every task, besides `date` and the named groups of the patterns
+const TASK_VARS: [&str; 5] = ["name", "root_dir", "hash", "part", "category"];
+
+/// compiled `general.upload_path_patterns`, by pattern
+static PATTERNS: LazyLock<Mutex<HashMap<String, Regex>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// expand the upload path of the task for its current part
+pub fn upload_path(task: &TaskValue) -> TemplateResult<String> {
+    render(task, &task.upload_path, &[])
+}
+
+/// expand `template` with the variables of the task, and the `extra` ones
+pub fn render(task: &TaskValue, template: &str, extra: &[(&str, &str)]) -> TemplateResult<String> {
+    if !template.contains(['{', '}']) {
+        return Ok(template.to_string());
+    }
+    let part = (task.state().current_part_num + 1).to_string();
+    let mut vars: HashMap<&str, Cow<str>> = TASK_VARS
+        .into_iter()
+        .zip([
+            Cow::Borrowed(task.name.as_str()),
+            Cow::Borrowed(task.root_dir.as_str()),
+            Cow::Borrowed(task.hash.as_str()),
+            Cow::Owned(part),
+            Cow::Borrowed(task.category.as_str()),
+        ])
+        .collect();
+    let patterns = config::value().general.upload_path_patterns.clone();
+    let groups = name_groups(&patterns, &task.name)?;
+    for (key, value) in &groups {
+        vars.entry(key).or_insert(Cow::Borrowed(value));
+    }
+    for &(key, value) in extra {
+        vars.insert(key, Cow::Borrowed(value));
+    }
+    expand(template, &vars, SystemTime::now())
+}
+
+/// Check `template` before it's used by a task, rendering it with every variable a task may have,
+/// including the named groups of any of `patterns`
+/// # Error
+/// the template is invalid, or a pattern is
+pub fn check(template: &str, patterns: &[String]) -> TemplateResult<()> {
+    let mut vars: HashMap<&str, Cow<str>> = TASK_VARS
+        .iter()
+        .map(|&key| (key, Cow::Borrowed("")))
+        .collect();
+    let compiled = patterns
+        .iter()
+        .map(|pattern| compile(pattern))
+        .collect::<TemplateResult<Vec<Regex>>>()?;
+    for re in &compiled {
+        for group in re.capture_names().flatten() {
+            vars.insert(group, Cow::Borrowed(""));
+        }
+    }
+    expand(template, &vars, SystemTime::now()).map(drop)
+}
+
+/// the compiled pattern, compiled once and cached
+fn compile(pattern: &str) -> TemplateResult<Regex> {
+    let mut cache = PATTERNS.lock().unwrap();
+    if let Some(re) = cache.get(pattern) {
+        return Ok(re.clone());
+    }
+    let re = Regex::new(pattern)
+        .map_err(|e| Cow::Owned(format!("Invalid upload path pattern {pattern}: {e}")))?;
+    cache.insert(pattern.to_string(), re.clone());
+    Ok(re)
+}
+
+/// named groups of the first pattern matching `name`, unmatched groups are empty
+fn name_groups(patterns: &[String], name: &str) -> TemplateResult<Vec<(String, String)>> {
+    for pattern in patterns {
+        let re = compile(pattern)?;
+        if let Some(caps) = re.captures(name) {
+            return Ok(re
+                .capture_names()
+                .flatten()
+                .map(|group| {
+                    let value = caps.name(group).map_or("", |m| m.as_str().trim());
+                    (group.to_string(), value.to_string())
+                })
+                .collect());
+        }
+    }
+    Ok(Vec::new())
+}
+
+fn expand(
+    template: &str,
+    vars: &HashMap<&str, Cow<str>>,
+    now: SystemTime,
+) -> TemplateResult<String> {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(i) = rest.find(['{', '}']) {
+        result.push_str(&rest[..i]);
+        let brace = rest.as_bytes()[i];
+        rest = &rest[i + 1..];
+        // escaped brace
+        if rest.as_bytes().first() == Some(&brace) {
+            result.push(brace as char);
+            rest = &rest[1..];
+            continue;
+        }
+        // a brace not around a variable name is literal
+        let variable = (brace == b'{')
+            .then(|| rest.find('}'))
+            .flatten()
+            .map(|end| {
+                let (key, format) = match rest[..end].split_once(':') {
+                    Some((key, format)) => (key, Some(format)),
+                    None => (&rest[..end], None),
+                };
+                (end, key, format)
+            })
+            .filter(|(_, key, _)| is_variable_name(key));
+        let Some((end, key, format)) = variable else {
+            result.push(brace as char);
+            continue;
+        };
+        match (key, format) {
+            ("date", format) => result.push_str(&format_date(now, format.unwrap_or("%Y-%m-%d"))),
+            (key, None) if vars.contains_key(key) => result.push_str(&vars[key]),
+            _ => {
+                return Err(Cow::Owned(format!(
+                    "Unknown variable {{{}}} in upload path",
+                    &rest[..end]
+                )));
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+fn is_variable_name(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'_')
+}
+
+/// format the UTC time with a subset of strftime
+fn format_date(time: SystemTime, format: &str) -> String {
+    // e.g. "2025-01-31T08:05:09Z"
+    let rfc3339 = humantime::format_rfc3339_seconds(time).to_string();
+    let mut result = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => result.push_str(&rfc3339[0..4]),
+            Some('m') => result.push_str(&rfc3339[5..7]),
+            Some('d') => result.push_str(&rfc3339[8..10]),
+            Some('H') => result.push_str(&rfc3339[11..13]),
+            Some('M') => result.push_str(&rfc3339[14..16]),
+            Some('S') => result.push_str(&rfc3339[17..19]),
+            Some('%') => result.push('%'),
+            Some(other) => {
+                result.push('%');
+                result.push(other);
+            }
+            None => result.push('%'),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// 2025-01-31T08:05:09Z
+    fn now() -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_738_310_709)
+    }
+
+    fn vars() -> HashMap<&'static str, Cow<'static, str>> {
+        HashMap::from([
+            ("name", Cow::Borrowed("[Group] Show S02 [1080p]")),
+            ("part", Cow::Borrowed("3")),
+            ("title", Cow::Borrowed("Show")),
+            ("season", Cow::Borrowed("02")),
+        ])
+    }
+
+    #[test]
+    fn expand_variables() {
+        let cases = [
+            ("gdrive:/anime", "gdrive:/anime"),
+            ("gdrive:/{title}/Season {season}", "gdrive:/Show/Season 02"),
+            (
+                "gdrive:/{name}/part{part}",
+                "gdrive:/[Group] Show S02 [1080p]/part3",
+            ),
+            ("gdrive:/{date}", "gdrive:/2025-01-31"),
+            (
+                "gdrive:/{date:%Y-%m}/{date:%H%M%S}",
+                "gdrive:/2025-01/080509",
+            ),
+            ("gdrive:/{date:%%Y}", "gdrive:/%Y"),
+            ("gdrive:/{{literal}}", "gdrive:/{literal}"),
+            // braces not around a variable name are kept
+            ("gdrive:/{title", "gdrive:/{title"),
+            ("gdrive:/title}", "gdrive:/title}"),
+            ("gdrive:/{ a }/{}", "gdrive:/{ a }/{}"),
+            ("gdrive:/{a-b}/{title}", "gdrive:/{a-b}/Show"),
+        ];
+        for (template, expected) in cases {
+            assert_eq!(
+                expand(template, &vars(), now()).unwrap(),
+                expected,
+                "{template}"
+            );
+        }
+    }
+
+    #[test]
+    fn expand_invalid() {
+        for template in ["gdrive:/{unknown}", "gdrive:/{titel}", "{name:x}"] {
+            assert!(expand(template, &vars(), now()).is_err(), "{template}");
+        }
+    }
+
+    #[test]
+    fn check_templates() {
+        let patterns = [r"^(?P<title>.+?) S(?P<season>\d+)".to_string()];
+        for template in [
+            "gdrive:/anime",
+            "gdrive:/{title}/Season {season}/{name}",
+            "gdrive:/{date:%Y}/{part}",
+            "gdrive:/{ a }",
+        ] {
+            assert!(check(template, &patterns).is_ok(), "{template}");
+        }
+        assert!(check("gdrive:/{episode}", &patterns).is_err());
+        assert!(check("gdrive:/{title}", &[]).is_err());
+        assert!(check("gdrive:/anime", &["(".to_string()]).is_err());
+    }
+
+    #[test]
+    fn name_groups_first_match() {
+        let patterns = [
+            r"^\[.+?\] (?P<title>.+?) S(?P<season>\d+)".to_string(),
+            r"^(?P<title>.+)$".to_string(),
+        ];
+        let groups = name_groups(&patterns, "[Group] Show S02 [1080p]").unwrap();
+        assert_eq!(
+            groups,
+            [
+                ("title".to_string(), "Show".to_string()),
+                ("season".to_string(), "02".to_string()),
+            ]
+        );
+        let groups = name_groups(&patterns, "Movie").unwrap();
+        assert_eq!(groups, [("title".to_string(), "Movie".to_string())]);
+        assert!(name_groups(&["(".to_string()], "Movie").is_err());
+    }
+}